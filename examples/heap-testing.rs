@@ -1,3 +1,5 @@
+#![allow(clippy::useless_vec)]
+
 // This is a very simple example of how to do heap usage testing of a program.
 // To use this code for a test, you need to make some changes:
 // - Move it into an integration test file within `tests/`.
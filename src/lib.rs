@@ -1,6 +1,10 @@
 #![deny(missing_docs)]
 #![deny(rustdoc::missing_doc_code_examples)]
 #![deny(missing_debug_implementations)]
+#![cfg_attr(feature = "allocator-api", feature(allocator_api))]
+// The heap usage testing example in the crate docs shows a `#[test]`
+// attribute, deliberately.
+#![allow(clippy::test_attr_in_doctest)]
 
 //! **Warning:** *This crate is experimental. It relies on implementation
 //! techniques that are hard to keep working for 100% of configurations. It may
@@ -136,7 +140,9 @@
 //!
 //! The meaning of the integer argument to `ad_hoc_event` will depend on
 //! exactly what you are measuring. If there is no meaningful weight to give to
-//! an event, you can just use `1`.
+//! an event, you can just use `1`. If the weight is fractional, use
+//! [`ad_hoc_event_f64`] instead. To distinguish categories of events recorded
+//! from the same code, use [`ad_hoc_event_labeled`].
 //!
 //! # Running
 //!
@@ -147,12 +153,14 @@
 //! on Windows than on other platforms.)
 //!
 //! When the [`Profiler`] is dropped at the end of `main`, some basic
-//! information will be printed to `stderr`. For heap profiling it will look
-//! like the following.
+//! information will be printed to `stderr` (or elsewhere, as chosen with
+//! [`ProfilerBuilder::summary_output`]). For heap profiling it will look like
+//! the following.
 //! ```text
 //! dhat: Total:     1,256 bytes in 6 blocks
 //! dhat: At t-gmax: 1,256 bytes in 6 blocks
 //! dhat: At t-end:  1,256 bytes in 6 blocks
+//! dhat: Largest:   1,024 bytes
 //! dhat: The data has been saved to dhat-heap.json, and is viewable with dhat/dh_view.html
 //! ```
 //! ("Blocks" is a synonym for "allocations".)
@@ -165,6 +173,11 @@
 //! A file called `dhat-heap.json` (for heap profiling) or `dhat-ad-hoc.json`
 //! (for ad hoc profiling) will be written. It can be viewed in DHAT's viewer.
 //!
+//! Writing the file requires looking up debug info for every backtrace, which
+//! can take a while for programs with many distinct allocation points. The
+//! `parallel-resolve` feature of this crate spreads these lookups across
+//! multiple threads.
+//!
 //! If you don't see this output, it may be because your program called
 //! [`std::process::exit`], which exits a program without running any
 //! destructors. To work around this, explicitly call `drop` on the
@@ -172,7 +185,8 @@
 //!
 //! When doing heap profiling, if you unexpectedly see zero allocations in the
 //! output it may be because you forgot to set [`dhat::Alloc`](Alloc) as the
-//! global allocator.
+//! global allocator. A warning is printed when the profiler finishes in that
+//! case.
 //!
 //! When doing heap profiling it is recommended that the lifetime of the
 //! [`Profiler`] value cover all of `main`. But it is still possible for
@@ -181,7 +195,7 @@
 //! - Allocated before, untouched within: ignored.
 //! - Allocated before, freed within: ignored.
 //! - Allocated before, reallocated within: treated like a new allocation
-//!   within.
+//!   within. The summary says how many times this happened.
 //! - Allocated after: ignored.
 //!
 //! These cases are not ideal, but it is impossible to do better. `dhat`
@@ -379,15 +393,19 @@ use lazy_static::lazy_static;
 // making the mutex implementation on a lower level than the allocator,
 // allowing the allocator to depend on it.
 use mintex::Mutex;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use serde::Serialize;
 use std::alloc::{GlobalAlloc, Layout, System};
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
 use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::BufWriter;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
 use std::ops::AddAssign;
+use std::panic::Location;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::time::{Duration, Instant};
 use thousands::Separable;
 
@@ -395,6 +413,56 @@ lazy_static! {
     static ref TRI_GLOBALS: Mutex<Phase<Globals>> = Mutex::new(Phase::Ready);
 }
 
+// Is `TRI_GLOBALS` in the `Running` phase for heap profiling? Only changed
+// while `TRI_GLOBALS` is locked. This lets `Alloc`'s methods skip locking
+// `TRI_GLOBALS` when there is nothing to record, e.g. before a `Profiler` is
+// built or when doing ad hoc profiling. A `false` value can only be stale for
+// allocations that aren't recorded anyway, so the check can't cause a block
+// to be missed.
+static HEAP_PROFILING: AtomicBool = AtomicBool::new(false);
+
+// The `THREAD_NUM` of the thread whose allocations are recorded, if requested
+// with `ProfilerBuilder::current_thread_only`, or zero if allocations on all
+// threads are recorded. Set when a `Profiler` is built.
+static PROFILED_THREAD: AtomicU64 = AtomicU64::new(0);
+
+// Frames to trim at the top and bottom of backtraces. Computed once the first
+// backtrace is obtained during profiling; that backtrace is then compared to
+// `Globals::start_bt`. This is done once per process, rather than once per
+// `Profiler`, so that successive `Profiler`s trim backtraces identically.
+//
+// Each key is the address of a frame, and thus actually a `*mut c_void`, but
+// we store it as a `usize` because (a) we never dereference it, and (b) using
+// `*mut c_void` leads to compile errors because raw pointers don't implement
+// `Send`.
+static FRAMES_TO_TRIM: OnceLock<FxHashMap<usize, TB>> = OnceLock::new();
+
+// A unique non-zero number for each thread. Unlike `ThreadId`, it can be
+// compared with an atomic value, and getting it never allocates.
+thread_local!(static THREAD_NUM: u64 = {
+    static NEXT_THREAD_NUM: AtomicU64 = AtomicU64::new(1);
+    NEXT_THREAD_NUM.fetch_add(1, Ordering::Relaxed)
+});
+
+// The current thread's `THREAD_NUM`, or 0 if it can't be got because the
+// thread is being torn down. Unlike `std::thread::current`, this never
+// panics, which matters because it is called from within the global
+// allocator.
+fn current_thread_num() -> u64 {
+    THREAD_NUM.try_with(|&n| n).unwrap_or(0)
+}
+
+// Should allocations on this thread be recorded, according to
+// `ProfilerBuilder::current_thread_only`? This can be called during thread
+// teardown, after `THREAD_NUM` has been destroyed, in which case it returns
+// false if only one thread's allocations are recorded.
+fn is_profiled_thread() -> bool {
+    match PROFILED_THREAD.load(Ordering::Relaxed) {
+        0 => true,
+        profiled => THREAD_NUM.try_with(|&n| n == profiled).unwrap_or(false),
+    }
+}
+
 // State transition diagram:
 //
 // +---------------> Ready
@@ -402,12 +470,13 @@ lazy_static! {
 // | Profiler::        | ProfilerBuilder::
 // | drop_inner()      | build()
 // |                   v
-// +---------------- Running
-// |                   |
-// |                   | check_assert_condition()
-// |                   | [if the check fails]
-// |                   v
-// +---------------- PostAssert
+// +---------------- Running <-----------------+
+// |                   |                       |
+// |                   | check_assert_         | Profiler::
+// |                   | condition()           | recover_from_assert()
+// |                   | [if the check fails]  |
+// |                   v                       |
+// +---------------- PostAssert ---------------+
 //
 // Note: the use of `std::process::exit` or `std::mem::forget` (on the
 // `Profiler`) can result in termination while the profiler is still running,
@@ -421,8 +490,9 @@ enum Phase<T> {
     Running(T),
 
     // The current `Profiler` has stopped due to as assertion failure, but
-    // hasn't been dropped yet.
-    PostAssert,
+    // hasn't been dropped yet. The data is kept in case profiling is resumed
+    // with `Profiler::recover_from_assert`.
+    PostAssert(T),
 }
 
 // Type used in frame trimming.
@@ -438,27 +508,138 @@ struct Globals {
     // The file name for the saved data.
     file_name: PathBuf,
 
+    // The already-open file for the saved data, if one was given with
+    // `ProfilerBuilder::file`. Takes precedence over `file_name`.
+    file: Option<File>,
+
     // Are we in testing mode?
     testing: bool,
 
+    // Should a failed assertion leave profiling running? And has one failed
+    // yet, saving the profile?
+    soft_asserts: bool,
+    soft_assert_failed: bool,
+
+    // Should dropping a testing mode `Profiler` with live blocks panic?
+    fail_on_leak: bool,
+
     // How should we trim backtraces?
     trim_backtraces: Option<usize>,
 
+    // How long may getting a single backtrace take, if limited? Backtraces
+    // that take longer are truncated.
+    backtrace_budget: Option<Duration>,
+
+    // Should frames be identified by file and line alone in the saved
+    // profile, merging PPs that differ only by IPs and columns?
+    merge_by_line: bool,
+
+    // Should `ftbl` in the saved profile hold objects instead of strings?
+    structured_frames: bool,
+
+    // Should we skip getting backtraces, and attribute everything to a
+    // single PP with an empty backtrace?
+    no_backtraces: bool,
+
+    // Extra symbol name prefixes for allocator frames to trim from the top of
+    // heap backtraces, as well as the built-in ones.
+    trim_top_matching: Vec<String>,
+
+    // How many trailing components of file paths should we keep?
+    path_components: usize,
+
+    // Should we resolve symbols when saving? If not, frames are shown as raw
+    // addresses.
+    resolve_symbols: bool,
+
     // Print the JSON to stderr when saving it?
     eprint_json: bool,
 
-    // The backtrace at startup. Used for backtrace trimmming.
-    start_bt: Backtrace,
+    // Where the summary lines are printed when saving.
+    summary_output: SummaryOutput,
 
-    // Frames to trim at the top and bottom of backtraces. Computed once the
-    // first backtrace is obtained during profiling; that backtrace is then
-    // compared to `start_bt`.
-    //
-    // Each element is the address of a frame, and thus actually a `*mut
-    // c_void`, but we store it as a `usize` because (a) we never dereference
-    // it, and (b) using `*mut c_void` leads to compile errors because raw
-    // pointers don't implement `Send`.
-    frames_to_trim: Option<FxHashMap<usize, TB>>,
+    // Where and how to also save the profile as folded stacks, if at all.
+    folded_output: Option<(PathBuf, FoldedWeight)>,
+
+    // Where to also save the blocks that were live at the global peak, if at
+    // all.
+    save_at_peak: Option<PathBuf>,
+
+    // Where to also save the summary stats, if at all.
+    stats_file: Option<PathBuf>,
+
+    // Where to save a Chrome trace of the live bytes, if at all, and the
+    // maximum number of events to keep.
+    chrome_trace: Option<(PathBuf, usize)>,
+
+    // What to do when a block is allocated at the address of a live block.
+    on_double_free: DoubleFreePolicy,
+
+    // The unit for times in the saved profile.
+    time_unit: TimeUnit,
+
+    // Which count determines the global peak (t-gmax).
+    peak_metric: PeakMetric,
+
+    // The command recorded in the saved profile.
+    cmd: String,
+
+    // How long to wait at the end of heap profiling before taking the final
+    // counts, if at all.
+    end_settle: Option<Duration>,
+
+    // Should we get backtraces for deallocations?
+    record_dealloc_backtraces: bool,
+
+    // Heap blocks smaller than this are not recorded.
+    min_size: usize,
+
+    // Should we count allocations by alignment?
+    track_alignment: bool,
+
+    // Should we record a histogram of block lifetimes for each PP?
+    lifetime_histogram: bool,
+
+    // If set, PPs with more alloc/free cycles than this are reported as
+    // thrashing.
+    detect_thrashing: Option<u64>,
+
+    // Should the final profile only count blocks still live at the end?
+    net_only: bool,
+
+    // Should heap PPs be split by the size class of their blocks?
+    split_by_size_class: bool,
+
+    // Should we save the profile with `DhatFormatter`?
+    compact_output: bool,
+
+    // Should `finish` write each PP to file as it is built, rather than
+    // building the whole JSON in memory first?
+    stream_output: bool,
+
+    // Should we flush the saved profile to disk before reporting success?
+    sync_on_write: bool,
+
+    // Should a child process discard the data inherited at `fork` time?
+    #[cfg(unix)]
+    reset_on_fork: bool,
+
+    // The interval requested with `ProfilerBuilder::sample_curve`, if any.
+    // Only used for the `config` object in the saved profile; the sampler
+    // thread has its own copy.
+    sample_curve: Option<Duration>,
+
+    // The environment variables to include in the saved profile, captured
+    // when profiling started. `None` if none were requested.
+    env: Option<BTreeMap<String, String>>,
+
+    // Where to also save the profile in pprof format, if at all.
+    #[cfg(feature = "pprof")]
+    pprof_output: Option<PathBuf>,
+
+    // The backtrace at startup. Used to compute `FRAMES_TO_TRIM`, and so
+    // only obtained if that hasn't been done yet in this process.
+    start_bt: Option<Backtrace>,
 
     // When `Globals` is created, which is when the `Profiler` is created.
     start_instant: Instant,
@@ -472,16 +653,136 @@ struct Globals {
     // than `Rc`s or raw pointers or whatever.
     pp_infos: Vec<PpInfo>,
 
+    // The unresolved frame for each IP that appears in `backtraces`, or in
+    // `HeapGlobals::dealloc_backtraces`. Frames repeat heavily across
+    // backtraces, so storing each one once here, and only the IPs in each
+    // `Backtrace`, saves a lot of memory.
+    frames: FrameTable,
+
     // Each `Backtrace` is associated with a `PpInfo`. The `usize` is an index
     // into `pp_infos`. Entries are not deleted during execution.
     backtraces: FxHashMap<Backtrace, usize>,
 
+    // The total size of the frames in `backtraces`, kept up to date so that
+    // `metadata_bytes` is cheap enough to call after every allocation.
+    backtrace_bytes: usize,
+
+    // If `metadata_bytes` exceeds this, profiling stops early.
+    metadata_limit: Option<usize>,
+
+    // Set when profiling has stopped early because of `metadata_limit`. The
+    // data has been saved, and nothing more is recorded. Also set from the
+    // start if profiling is disabled with `DHAT_DISABLE`, in which case nothing
+    // is recorded or saved at all.
+    stopped_early: bool,
+
     // Counts for the entire run.
     total_blocks: u64, // For ad hoc profiling it's actually `total_events`.
     total_bytes: u64,  // For ad hoc profiling it's actually `total_units`.
 
+    // Units from `ad_hoc_event_f64`, kept separately from `total_bytes` so
+    // that the integer counts stay exact.
+    total_units_f64: f64,
+
+    // Per-thread allocation counts, keyed by the allocating thread. Only
+    // updated when heap profiling.
+    thread_totals: FxHashMap<u64, ThreadTotals>,
+
     // Extra things kept when heap profiling.
     heap: Option<HeapGlobals>,
+
+    // Set when this `Globals` is dropped, to tell the thread started by
+    // `ProfilerBuilder::dump_on_signal`, if there is one, to stop.
+    stop_dump_thread: Option<Arc<AtomicBool>>,
+
+    // Likewise for the thread started by `ProfilerBuilder::sample_curve`.
+    stop_sampler_thread: Option<Arc<AtomicBool>>,
+
+    // Likewise for the thread started by `ProfilerBuilder::rotate`.
+    stop_rotate_thread: Option<Arc<AtomicBool>>,
+
+    // Called for each recorded allocation, if requested with
+    // `ProfilerBuilder::on_alloc`.
+    on_alloc: Option<AllocCallback>,
+
+    // The source of the current time, if not `Instant::now`.
+    clock: Option<Clock>,
+
+    // The data discarded by `reset_for_fork`, which can't be freed in the
+    // child's fork handler.
+    #[cfg(unix)]
+    fork_discarded: Option<Box<ForkReset>>,
+}
+
+// A wrapper for the `ProfilerBuilder::on_alloc` callback, so that
+// `ProfilerBuilder` can still derive `Debug`.
+struct AllocCallback(Box<dyn Fn(AllocEvent) + Send>);
+
+impl std::fmt::Debug for AllocCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AllocCallback")
+    }
+}
+
+// A wrapper for the `ProfilerBuilder::clock` function, so that
+// `ProfilerBuilder` can still derive `Debug`.
+struct Clock(Box<dyn Fn() -> Instant + Send>);
+
+impl Clock {
+    // The current time, from `clock` if there is one.
+    fn now(clock: &Option<Clock>) -> Instant {
+        match clock {
+            Some(clock) => clock.0(),
+            None => Instant::now(),
+        }
+    }
+}
+
+impl std::fmt::Debug for Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+impl Drop for Globals {
+    fn drop(&mut self) {
+        if let Some(stop) = &self.stop_dump_thread {
+            stop.store(true, Ordering::Relaxed);
+        }
+        if let Some(stop) = &self.stop_sampler_thread {
+            stop.store(true, Ordering::Relaxed);
+        }
+        if let Some(stop) = &self.stop_rotate_thread {
+            stop.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+// Allocation counts for a single alignment. `padding_bytes` is the sum of
+// `align_padding` for each block.
+#[derive(Default)]
+struct AlignCounts {
+    blocks: u64,
+    bytes: u64,
+    padding_bytes: u64,
+}
+
+// Estimate the number of bytes wasted by the system allocator to satisfy
+// `align` for a block of `size` bytes: rounding the size up to a multiple of
+// the alignment, plus, for alignments above what `malloc` guarantees
+// (typically two words), the worst-case offset needed to align the block.
+// This is deliberately rough, and is more of an upper bound.
+fn align_padding(size: usize, align: usize) -> usize {
+    let malloc_align = 2 * std::mem::size_of::<usize>();
+    (size.next_multiple_of(align) - size) + align.saturating_sub(malloc_align)
+}
+
+// The index of the `ProfilerBuilder::lifetime_histogram` bucket for a block
+// that lived for `d`. Bucket `i` covers lifetimes from 2^i up to (but not
+// including) 2^(i+1) nanoseconds, except that bucket 0 also covers zero.
+fn lifetime_bucket(d: Duration) -> usize {
+    let nanos = u64::try_from(d.as_nanos()).unwrap_or(u64::MAX);
+    (u64::BITS - 1 - (nanos | 1).leading_zeros()) as usize
 }
 
 struct HeapGlobals {
@@ -498,105 +799,466 @@ struct HeapGlobals {
     curr_blocks: usize,
     curr_bytes: usize,
 
+    // Counts of blocks freed, including the old blocks of reallocations, so
+    // that `total_bytes - freed_bytes == curr_bytes`.
+    freed_blocks: u64,
+    freed_bytes: u64,
+
     // Counts at the global max, i.e. when `curr_bytes` peaks.
     max_blocks: usize,
     max_bytes: usize,
 
     // Time of the global max.
     tgmax_instant: Instant,
+
+    // Number of allocations (including reallocations) for which the system
+    // allocator returned null.
+    failed_blocks: u64,
+
+    // The addresses of blocks allocated while paused by `pause`, or smaller
+    // than `Globals::min_size`. These are not recorded, even if reallocated
+    // after `resume` or to a larger size.
+    unrecorded_blocks: FxHashSet<usize>,
+
+    // Number of reallocations of live blocks, and the sum of their new
+    // sizes.
+    realloc_blocks: u64,
+    realloc_bytes_moved: u64,
+
+    // Of those reallocations, the number that kept the block at the same
+    // address, and the number that moved it.
+    realloc_in_place: u64,
+    realloc_moved: u64,
+
+    // Number of zero-sized allocations. These are passed through without
+    // being recorded, because the allocator may return the same sentinel
+    // address for all of them.
+    zst_allocations: u64,
+
+    // Number of reallocations of blocks allocated before profiling started,
+    // which are treated as new allocations.
+    preexisting_realloc_blocks: u64,
+
+    // The size of the largest block allocated (including by reallocation),
+    // and the index of its PP. The earliest such block wins ties.
+    largest_block_bytes: usize,
+    largest_block_pp: Option<usize>,
+
+    // The backtraces of deallocations, if requested with
+    // `ProfilerBuilder::record_dealloc_backtraces`. Each one is mapped to an
+    // index, like `Globals::backtraces`, but these are not PPs.
+    dealloc_backtraces: FxHashMap<Backtrace, usize>,
+
+    // The number of deallocations for each pair of allocation PP index and
+    // deallocation backtrace index.
+    dealloc_counts: FxHashMap<(usize, usize), u64>,
+
+    // The time and current bytes after each allocation and deallocation, if
+    // requested with `ProfilerBuilder::chrome_trace`. This is a ring buffer:
+    // when it's full the oldest event is dropped.
+    trace_events: VecDeque<(Instant, usize)>,
+    trace_events_dropped: u64,
+
+    // The time and name of each marker recorded with `marker`.
+    markers: Vec<(Instant, String)>,
+
+    // The time, current bytes, and current blocks at each sample taken by
+    // the thread started by `ProfilerBuilder::sample_curve`.
+    curve: Vec<(Instant, usize, usize)>,
+
+    // Counts for each requested alignment, if requested with
+    // `ProfilerBuilder::track_alignment`.
+    align_counts: BTreeMap<usize, AlignCounts>,
+
+    // The current counts (bytes, blocks) at the end of profiling, before
+    // waiting for `ProfilerBuilder::end_settle`.
+    t_end_before_settle: Option<(usize, usize)>,
+
+    // Have the counts underflowed? If so, a warning is printed when
+    // profiling finishes, rather than when it happens, because printing
+    // with `TRI_GLOBALS` locked can deadlock.
+    underflowed: bool,
 }
 
 impl Globals {
-    fn new(
-        testing: bool,
-        file_name: PathBuf,
-        trim_backtraces: Option<usize>,
-        eprint_json: bool,
-        heap: Option<HeapGlobals>,
-    ) -> Self {
+    fn new(b: &ProfilerBuilder, file_name: PathBuf, heap: Option<HeapGlobals>) -> Self {
         Self {
-            testing,
+            testing: b.testing,
+            soft_asserts: b.soft_asserts,
+            fail_on_leak: b.fail_on_leak,
+            soft_assert_failed: false,
             file_name,
-            trim_backtraces,
-            eprint_json,
+            trim_backtraces: b.trim_backtraces,
+            backtrace_budget: b.backtrace_budget,
+            merge_by_line: b.merge_by_line,
+            structured_frames: b.structured_frames,
+            no_backtraces: b.no_backtraces,
+            trim_top_matching: b.trim_top_matching.clone(),
+            path_components: if b.full_paths {
+                usize::MAX
+            } else {
+                b.path_components
+            },
+            resolve_symbols: b.resolve_symbols,
+            eprint_json: b.eprint_json,
+            summary_output: b.summary_output,
+            folded_output: b.folded_output.clone(),
+            save_at_peak: b.save_at_peak.clone(),
+            stats_file: b.stats_file.clone(),
+            chrome_trace: b.chrome_trace.clone(),
+            on_double_free: b.on_double_free,
+            time_unit: b.time_unit,
+            peak_metric: b.peak_metric,
+            end_settle: b.end_settle,
+            record_dealloc_backtraces: b.record_dealloc_backtraces,
+            min_size: b.min_size,
+            track_alignment: b.track_alignment,
+            lifetime_histogram: b.lifetime_histogram,
+            detect_thrashing: b.detect_thrashing,
+            net_only: b.net_only,
+            split_by_size_class: b.split_by_size_class,
+            compact_output: b.compact_output,
+            stream_output: b.stream_output,
+            sync_on_write: b.sync_on_write,
+            #[cfg(unix)]
+            reset_on_fork: b.reset_on_fork,
+            sample_curve: b.sample_curve,
+            cmd: match &b.cmd {
+                Some(cmd) => cmd.clone(),
+                None if b.redact_args => std::env::args_os()
+                    .next()
+                    .map(|arg0| {
+                        let path = Path::new(&arg0);
+                        path.file_name()
+                            .unwrap_or(path.as_os_str())
+                            .to_string_lossy()
+                            .into_owned()
+                    })
+                    .unwrap_or_default(),
+                None => std::env::args().collect::<Vec<_>>().join(" "),
+            },
+            env: if b.record_env.is_empty() {
+                None
+            } else {
+                Some(
+                    b.record_env
+                        .iter()
+                        .filter_map(|key| {
+                            let value = std::env::var_os(key)?;
+                            Some((key.clone(), value.to_string_lossy().into_owned()))
+                        })
+                        .collect(),
+                )
+            },
+            #[cfg(feature = "pprof")]
+            pprof_output: b.pprof_output.clone(),
             // `None` here because we don't want any frame trimming for this
             // backtrace.
-            start_bt: new_backtrace_inner(None, &FxHashMap::default()),
-            frames_to_trim: None,
+            start_bt: if FRAMES_TO_TRIM.get().is_none() {
+                Some(new_backtrace_inner(
+                    None,
+                    None,
+                    &FxHashMap::default(),
+                    &mut FrameTable::default(),
+                ))
+            } else {
+                None
+            },
             start_instant: Instant::now(),
-            pp_infos: Vec::default(),
-            backtraces: FxHashMap::default(),
+            pp_infos: Vec::with_capacity(b.reserve_backtraces),
+            backtraces: FxHashMap::with_capacity_and_hasher(
+                b.reserve_backtraces,
+                Default::default(),
+            ),
+            frames: FrameTable::default(),
+            backtrace_bytes: 0,
+            metadata_limit: b.metadata_limit,
+            stopped_early: false,
             total_blocks: 0,
             total_bytes: 0,
+            total_units_f64: 0.0,
+            thread_totals: FxHashMap::default(),
             heap,
+            stop_dump_thread: None,
+            stop_sampler_thread: None,
+            stop_rotate_thread: None,
+            // These are moved from the builder by `try_build`, because they
+            // can't be cloned.
+            file: None,
+            on_alloc: None,
+            clock: None,
+            #[cfg(unix)]
+            fork_discarded: None,
         }
     }
 
     // Get the PpInfo for this backtrace, creating it if necessary.
     fn get_pp_info<F: FnOnce() -> PpInfo>(&mut self, bt: Backtrace, new: F) -> usize {
         let pp_infos = &mut self.pp_infos;
-        *self.backtraces.entry(bt).or_insert_with(|| {
+        let backtrace_bytes = &mut self.backtrace_bytes;
+        *self.backtraces.entry(bt).or_insert_with_key(|bt| {
+            *backtrace_bytes += std::mem::size_of_val(&*bt.ips);
             let pp_info_idx = pp_infos.len();
             pp_infos.push(new());
             pp_info_idx
         })
     }
 
-    fn record_block(&mut self, ptr: *mut u8, pp_info_idx: usize, now: Instant) {
+    // If `metadata_limit` has been exceeded, stop profiling, saving the data
+    // collected so far. Returns the output to be printed once `TRI_GLOBALS`
+    // has been unlocked. Called after each allocation or ad hoc event is
+    // recorded; `metadata_bytes` is cheap enough for that.
+    fn check_metadata_limit(&mut self) -> Option<Output> {
+        let limit = self.metadata_limit?;
+        let metadata_bytes = self.metadata_bytes();
+        if metadata_bytes <= limit {
+            return None;
+        }
+
+        let mut out = if self.testing {
+            Output::new(self.summary_output)
+        } else {
+            self.snapshot()
+        };
+        out.warn(format_args!(
+            "dhat: warning: dhat's own memory use ({} bytes) exceeded the limit of {} bytes, so \
+            profiling has stopped early",
+            metadata_bytes.separate_with_commas(),
+            limit.separate_with_commas(),
+        ));
+        out.warn(format_args!(
+            "dhat: warning: to reduce dhat's memory use, reduce the `trim_backtraces` depth, or \
+            use `min_size` to skip small allocations"
+        ));
+        self.stopped_early = true;
+        HEAP_PROFILING.store(false, Ordering::Release);
+        Some(out)
+    }
+
+    // Record a new live block. If the address was already live, this follows
+    // `on_double_free`, and any warning is returned, to be printed once
+    // `TRI_GLOBALS` has been unlocked.
+    fn record_block(
+        &mut self,
+        ptr: *mut u8,
+        pp_info_idx: usize,
+        size: usize,
+        now: Instant,
+    ) -> Option<Output> {
         let h = self.heap.as_mut().unwrap();
         let old = h.live_blocks.insert(
             ptr as usize,
             LiveBlock {
                 pp_info_idx,
+                size,
                 allocation_instant: now,
+                thread: current_thread_num(),
             },
         );
-        std::assert!(matches!(old, None));
+        let old = old?;
+
+        // The address was already live, so a deallocation must have been
+        // missed (or the program freed the block twice in a way that the
+        // system allocator tolerated).
+        let out = match self.on_double_free {
+            DoubleFreePolicy::Panic => std::panic!(
+                "dhat: block at {:#x} allocated again without being freed",
+                ptr as usize
+            ),
+            DoubleFreePolicy::Warn => {
+                let mut out = Output::new(self.summary_output);
+                out.warn(format_args!(
+                    "dhat: warning: block at {:#x} allocated again without being freed",
+                    ptr as usize
+                ));
+                for (what, idx) in [("Previously", old.pp_info_idx), ("Now", pp_info_idx)] {
+                    out.warn(format_args!("dhat: {} allocated at {{", what));
+                    for frame in self.frame_strings(self.pp_backtrace(idx)) {
+                        out.warn(format_args!("dhat:   {}", frame));
+                    }
+                    out.warn(format_args!("dhat: }}"));
+                }
+                Some(out)
+            }
+            DoubleFreePolicy::Ignore => None,
+        };
+
+        // Treat the old block as freed, so that the counts stay consistent.
+        self.check_for_global_peak();
+        let alloc_duration = now.saturating_duration_since(old.allocation_instant);
+        self.update_counts_for_dealloc(old.pp_info_idx, old.size, alloc_duration, old.thread);
+        out
+    }
+
+    // Get the backtrace for a PP. This is a linear search, so it should only
+    // be used in unusual situations.
+    fn pp_backtrace(&self, pp_info_idx: usize) -> &Backtrace {
+        self.backtraces
+            .iter()
+            .find(|&(_, &idx)| idx == pp_info_idx)
+            .map(|(bt, _)| bt)
+            .unwrap()
     }
 
     fn update_counts_for_alloc(
         &mut self,
         pp_info_idx: usize,
         size: usize,
+        align: usize,
         delta: Option<Delta>,
         now: Instant,
     ) {
         self.total_blocks += 1;
         self.total_bytes += size as u64;
 
+        // Getting the current thread's number is cheap. Getting its name
+        // requires allocation, but we are ignoring allocations here, and we
+        // only do it the first time we see each thread.
+        let thread = current_thread_num();
+        let t = self
+            .thread_totals
+            .entry(thread)
+            .or_insert_with(|| ThreadTotals {
+                name: if thread == 0 {
+                    "<unknown thread>".to_string()
+                } else {
+                    let current = std::thread::current();
+                    match current.name() {
+                        Some(name) => name.to_string(),
+                        None => format!("{:?}", current.id()),
+                    }
+                },
+                total_blocks: 0,
+                total_bytes: 0,
+                curr_bytes: 0,
+                max_bytes: 0,
+            });
+        t.total_blocks += 1;
+        t.total_bytes += size as u64;
+
+        // For a reallocation, the old size has already been removed from the
+        // allocating thread's count by `update_thread_for_realloc`, so the
+        // new size is added in full.
+        t.curr_bytes += size;
+        t.max_bytes = std::cmp::max(t.max_bytes, t.curr_bytes);
+
         let h = self.heap.as_mut().unwrap();
         if let Some(delta) = delta {
-            // realloc
+            // realloc: the old block counts as freed.
             h.curr_blocks += 0; // unchanged
             h.curr_bytes += delta;
+            h.freed_blocks += 1;
+            h.freed_bytes += if delta.shrinking {
+                size + delta.size
+            } else {
+                size - delta.size
+            } as u64;
         } else {
             // alloc
             h.curr_blocks += 1;
             h.curr_bytes += size;
         }
 
+        if size > h.largest_block_bytes {
+            h.largest_block_bytes = size;
+            h.largest_block_pp = Some(pp_info_idx);
+        }
+
         // The use of `>=` not `>` means that if there are multiple equal peaks
         // we record the latest one, like `check_for_global_peak` does.
-        if h.curr_bytes >= h.max_bytes {
+        let is_peak = match self.peak_metric {
+            PeakMetric::Bytes => h.curr_bytes >= h.max_bytes,
+            PeakMetric::Blocks => h.curr_blocks >= h.max_blocks,
+        };
+        if is_peak {
             h.max_blocks = h.curr_blocks;
             h.max_bytes = h.curr_bytes;
             h.tgmax_instant = now;
         }
 
-        self.pp_infos[pp_info_idx].update_counts_for_alloc(size, delta);
+        if self.track_alignment {
+            let a = h.align_counts.entry(align).or_default();
+            a.blocks += 1;
+            a.bytes += size as u64;
+            a.padding_bytes += align_padding(size, align) as u64;
+        }
+
+        self.pp_infos[pp_info_idx].update_counts_for_alloc(size, delta, now);
+        self.record_trace_event();
+
+        if let Some(on_alloc) = &self.on_alloc {
+            on_alloc.0(AllocEvent {
+                size,
+                is_realloc: delta.is_some(),
+                pp_index: pp_info_idx,
+            });
+        }
     }
 
+    // Remove the old size of a reallocated block from the count of the thread
+    // that allocated it. The new size is then added to the current thread's
+    // count by `update_counts_for_alloc`.
+    fn update_thread_for_realloc(&mut self, thread: u64, old_size: usize) {
+        if let Some(t) = self.thread_totals.get_mut(&thread) {
+            t.curr_bytes = t.curr_bytes.saturating_sub(old_size);
+        }
+    }
+
+    // `thread` is the thread that allocated the block, which may not be the
+    // current thread.
     fn update_counts_for_dealloc(
         &mut self,
         pp_info_idx: usize,
         size: usize,
         alloc_duration: Duration,
+        thread: u64,
     ) {
+        if let Some(t) = self.thread_totals.get_mut(&thread) {
+            t.curr_bytes = t.curr_bytes.saturating_sub(size);
+        }
+
+        // The subtractions shouldn't underflow, but they can if the accounting
+        // gets confused by the edge cases involving blocks allocated before
+        // the `Profiler` started. Saturate rather than panicking or wrapping.
         let h = self.heap.as_mut().unwrap();
-        h.curr_blocks -= 1;
-        h.curr_bytes -= size;
+        let mut underflow = h.curr_blocks < 1 || h.curr_bytes < size;
+        h.curr_blocks = h.curr_blocks.saturating_sub(1);
+        h.curr_bytes = h.curr_bytes.saturating_sub(size);
+        h.freed_blocks += 1;
+        h.freed_bytes += size as u64;
+
+        let pp_info = &mut self.pp_infos[pp_info_idx];
+        underflow |= pp_info.update_counts_for_dealloc(size, alloc_duration);
+        if self.lifetime_histogram {
+            let hist = &mut pp_info.heap.as_mut().unwrap().lifetime_histogram;
+            let bucket = lifetime_bucket(alloc_duration);
+            if hist.len() <= bucket {
+                hist.resize(bucket + 1, 0);
+            }
+            hist[bucket] += 1;
+        }
+        if self.detect_thrashing.is_some() {
+            pp_info.heap.as_mut().unwrap().update_thrash_cycles(size);
+        }
+        self.record_trace_event();
 
-        self.pp_infos[pp_info_idx].update_counts_for_dealloc(size, alloc_duration);
+        self.heap.as_mut().unwrap().underflowed |= underflow;
+    }
+
+    // Record the current bytes for `ProfilerBuilder::chrome_trace`.
+    fn record_trace_event(&mut self) {
+        if let Some((_, capacity)) = self.chrome_trace {
+            if capacity == 0 {
+                return;
+            }
+            let h = self.heap.as_mut().unwrap();
+            if h.trace_events.len() == capacity {
+                h.trace_events.pop_front();
+                h.trace_events_dropped += 1;
+            }
+            h.trace_events
+                .push_back((Clock::now(&self.clock), h.curr_bytes));
+        }
     }
 
     fn update_counts_for_ad_hoc_event(&mut self, pp_info_idx: usize, weight: usize) {
@@ -607,6 +1269,14 @@ impl Globals {
         self.pp_infos[pp_info_idx].update_counts_for_ad_hoc_event(weight);
     }
 
+    fn update_counts_for_ad_hoc_event_f64(&mut self, pp_info_idx: usize, weight: f64) {
+        std::assert!(self.heap.is_none());
+        self.total_blocks += 1;
+        self.total_units_f64 += weight;
+
+        self.pp_infos[pp_info_idx].update_counts_for_ad_hoc_event_f64(weight);
+    }
+
     // If we are at peak memory, update `at_tgmax_{blocks,bytes}` in all
     // `PpInfo`s. This is somewhat expensive so we avoid calling it on every
     // allocation; instead we call it upon a deallocation (when we might be
@@ -614,7 +1284,11 @@ impl Globals {
     // a global peak).
     fn check_for_global_peak(&mut self) {
         let h = self.heap.as_mut().unwrap();
-        if h.curr_bytes == h.max_bytes {
+        let is_peak = match self.peak_metric {
+            PeakMetric::Bytes => h.curr_bytes == h.max_bytes,
+            PeakMetric::Blocks => h.curr_blocks == h.max_blocks,
+        };
+        if is_peak {
             // It's a peak. (If there are multiple equal peaks we record the
             // latest one.) Record it in every PpInfo.
             for pp_info in self.pp_infos.iter_mut() {
@@ -626,762 +1300,5462 @@ impl Globals {
     }
 
     fn get_heap_stats(&self) -> HeapStats {
-        match &self.heap {
-            Some(heap) => HeapStats {
-                total_blocks: self.total_blocks,
-                total_bytes: self.total_bytes,
-                curr_blocks: heap.curr_blocks,
-                curr_bytes: heap.curr_bytes,
-                max_blocks: heap.max_blocks,
-                max_bytes: heap.max_bytes,
-            },
+        match self.try_get_heap_stats() {
+            Some(stats) => stats,
             None => panic!("dhat: getting heap stats while doing ad hoc profiling"),
         }
     }
 
-    fn get_ad_hoc_stats(&self) -> AdHocStats {
-        match self.heap {
-            None => AdHocStats {
-                total_events: self.total_blocks,
-                total_units: self.total_bytes,
-            },
-            Some(_) => panic!("dhat: getting ad hoc stats while doing heap profiling"),
-        }
+    fn try_get_heap_stats(&self) -> Option<HeapStats> {
+        self.heap.as_ref().map(|heap| HeapStats {
+            total_blocks: self.total_blocks,
+            total_bytes: self.total_bytes,
+            curr_blocks: heap.curr_blocks,
+            curr_bytes: heap.curr_bytes,
+            freed_blocks: heap.freed_blocks,
+            freed_bytes: heap.freed_bytes,
+            max_blocks: heap.max_blocks,
+            max_bytes: heap.max_bytes,
+            pp_count: self.pp_infos.len(),
+            failed_blocks: heap.failed_blocks,
+            realloc_blocks: heap.realloc_blocks,
+            realloc_bytes_moved: heap.realloc_bytes_moved,
+            realloc_in_place: heap.realloc_in_place,
+            realloc_moved: heap.realloc_moved,
+            zst_allocations: heap.zst_allocations,
+            largest_block: heap.largest_block_bytes,
+        })
     }
 
-    // Finish tracking allocations and deallocations, print a summary message
-    // to `stderr` and save the profile to file/memory if requested.
-    fn finish(mut self, memory_output: Option<&mut String>) {
-        let now = Instant::now();
-
-        if self.heap.is_some() {
-            // Total bytes is at a possible peak.
-            self.check_for_global_peak();
+    // Estimate the memory used by the profiler's own data structures. Hash
+    // tables are assumed to need one control byte per entry beyond the
+    // entries themselves, as `hashbrown` does.
+    fn metadata_bytes(&self) -> usize {
+        use std::mem::size_of;
 
-            let h = self.heap.as_ref().unwrap();
+        fn map_bytes<K, V>(capacity: usize) -> usize {
+            capacity * (size_of::<K>() + size_of::<V>() + 1)
+        }
 
-            // Account for the lifetimes of all remaining live blocks.
-            for &LiveBlock {
-                pp_info_idx,
-                allocation_instant,
-            } in h.live_blocks.values()
-            {
-                self.pp_infos[pp_info_idx]
-                    .heap
-                    .as_mut()
-                    .unwrap()
-                    .total_lifetimes_duration += now.duration_since(allocation_instant);
-            }
+        let mut bytes = self.pp_infos.capacity() * size_of::<PpInfo>();
+        bytes += map_bytes::<Backtrace, usize>(self.backtraces.capacity());
+        bytes += self.backtrace_bytes;
+        bytes += map_bytes::<usize, backtrace::BacktraceFrame>(self.frames.capacity());
+        bytes += map_bytes::<u64, ThreadTotals>(self.thread_totals.capacity());
+        if let Some(h) = &self.heap {
+            bytes += map_bytes::<usize, LiveBlock>(h.live_blocks.capacity());
+            bytes += map_bytes::<usize, ()>(h.unrecorded_blocks.capacity());
+            bytes += h.trace_events.capacity() * size_of::<(Instant, usize)>();
+            bytes += h.markers.capacity() * size_of::<(Instant, String)>();
+            bytes += h
+                .markers
+                .iter()
+                .map(|(_, name)| name.capacity())
+                .sum::<usize>();
+            bytes += h.curve.capacity() * size_of::<(Instant, usize, usize)>();
         }
+        bytes
+    }
 
-        // We give each unique frame an index into `ftbl`, starting with 0
-        // for the special frame "[root]".
-        let mut ftbl_indices: FxHashMap<String, usize> = FxHashMap::default();
-        ftbl_indices.insert("[root]".to_string(), 0);
-        let mut next_ftbl_idx = 1;
+    // The current time, for profiling purposes.
+    fn now(&self) -> Instant {
+        Clock::now(&self.clock)
+    }
 
-        // Because `self` is being consumed, we can consume `self.backtraces`
-        // and replace it with an empty `FxHashMap`. (This is necessary because
-        // we modify the *keys* here with `resolve`, which isn't allowed with a
-        // non-consuming iterator.)
-        let pps: Vec<_> = std::mem::take(&mut self.backtraces)
-            .into_iter()
-            .map(|(mut bt, pp_info_idx)| {
-                // Do the potentially expensive debug info lookups to get
-                // symbol names, line numbers, etc.
-                bt.0.resolve();
-
-                // Trim boring frames at the top and bottom of the backtrace.
-                let first_symbol_to_show = if self.trim_backtraces.is_some() {
-                    if self.heap.is_some() {
-                        bt.first_heap_symbol_to_show()
-                    } else {
-                        bt.first_ad_hoc_symbol_to_show()
-                    }
-                } else {
-                    0
-                };
+    // Blocks allocated per second since profiling started.
+    fn alloc_rate(&self) -> f64 {
+        let secs = self
+            .now()
+            .saturating_duration_since(self.start_instant)
+            .as_secs_f64();
+        if secs == 0.0 {
+            0.0
+        } else {
+            self.total_blocks as f64 / secs
+        }
+    }
 
-                // Determine the frame indices for this backtrace. This
-                // involves getting the string for each frame and adding a
-                // new entry to `ftbl_indices` if it hasn't been seen
-                // before.
-                let mut fs = vec![];
-                let mut i = 0;
-                for frame in bt.0.frames().iter() {
-                    for symbol in frame.symbols().iter() {
-                        i += 1;
-                        if (i - 1) < first_symbol_to_show {
-                            continue;
-                        }
-                        let s = Backtrace::frame_to_string(frame, symbol);
-                        let &mut ftbl_idx = ftbl_indices.entry(s).or_insert_with(|| {
-                            next_ftbl_idx += 1;
-                            next_ftbl_idx - 1
-                        });
-                        fs.push(ftbl_idx);
-                    }
-                }
+    fn get_per_thread_totals(&self) -> Vec<(String, u64, u64)> {
+        let mut totals: Vec<_> = self
+            .thread_totals
+            .values()
+            .map(|t| (t.name.clone(), t.total_bytes, t.total_blocks))
+            .collect();
+        // Biggest allocators first.
+        totals.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        totals
+    }
 
-                PpInfoJson::new(&self.pp_infos[pp_info_idx], fs)
-            })
+    fn get_per_thread_peaks(&self) -> Vec<(String, usize)> {
+        let mut peaks: Vec<_> = self
+            .thread_totals
+            .values()
+            .map(|t| (t.name.clone(), t.max_bytes))
             .collect();
+        // Biggest peaks first.
+        peaks.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        peaks
+    }
 
-        // We pre-allocate `ftbl` with empty strings, and then fill it in.
-        let mut ftbl = vec![String::new(); ftbl_indices.len()];
-        for (frame, ftbl_idx) in ftbl_indices.into_iter() {
-            ftbl[ftbl_idx] = frame;
-        }
+    fn record_dealloc(&mut self, pp_info_idx: usize, bt: Backtrace) {
+        let h = self.heap.as_mut().unwrap();
+        let n = h.dealloc_backtraces.len();
+        let dealloc_idx = *h.dealloc_backtraces.entry(bt).or_insert(n);
+        *h.dealloc_counts
+            .entry((pp_info_idx, dealloc_idx))
+            .or_insert(0) += 1;
+    }
 
-        let h = self.heap.as_ref();
-        let is_heap = h.is_some();
-        let json = DhatJson {
-            dhatFileVersion: 2,
-            mode: if is_heap { "rust-heap" } else { "rust-ad-hoc" },
-            verb: "Allocated",
-            bklt: is_heap,
-            bkacc: false,
-            bu: if is_heap { None } else { Some("unit") },
-            bsu: if is_heap { None } else { Some("units") },
-            bksu: if is_heap { None } else { Some("events") },
-            tu: "µs",
-            Mtu: "s",
-            tuth: if is_heap { Some(10) } else { None },
-            cmd: std::env::args().collect::<Vec<_>>().join(" "),
-            pid: std::process::id(),
-            tg: h.map(|h| {
-                h.tgmax_instant
-                    .saturating_duration_since(self.start_instant)
-                    .as_micros()
-            }),
-            te: now.duration_since(self.start_instant).as_micros(),
-            pps,
-            ftbl,
+    fn get_alloc_dealloc_matrix(&self) -> Vec<(String, String, u64)> {
+        let h = match &self.heap {
+            Some(h) => h,
+            None => return vec![],
         };
 
-        eprintln!(
-            "dhat: Total:     {} {} in {} {}",
-            self.total_bytes.separate_with_commas(),
-            json.bsu.unwrap_or("bytes"),
-            self.total_blocks.separate_with_commas(),
-            json.bksu.unwrap_or("blocks"),
-        );
-        if let Some(h) = &self.heap {
-            eprintln!(
-                "dhat: At t-gmax: {} bytes in {} blocks",
-                h.max_bytes.separate_with_commas(),
-                h.max_blocks.separate_with_commas(),
-            );
-            eprintln!(
-                "dhat: At t-end:  {} bytes in {} blocks",
-                h.curr_bytes.separate_with_commas(),
-                h.curr_blocks.separate_with_commas(),
-            );
+        // Resolution is expensive, so each backtrace is resolved at most
+        // once.
+        let mut alloc_frames: FxHashMap<usize, String> = FxHashMap::default();
+        let mut dealloc_frames = vec![String::new(); h.dealloc_backtraces.len()];
+        for (bt, &dealloc_idx) in h.dealloc_backtraces.iter() {
+            dealloc_frames[dealloc_idx] = self.top_frame_string(bt);
         }
 
-        if let Some(memory_output) = memory_output {
-            // Default pretty printing is fine here, it's only used for small
-            // tests.
-            *memory_output = serde_json::to_string_pretty(&json).unwrap();
-            eprintln!("dhat: The data has been saved to the memory buffer");
-        } else {
-            let write = || -> std::io::Result<()> {
-                let buffered_file = BufWriter::new(File::create(&self.file_name)?);
-                // `to_writer` produces JSON that is compact.
-                // `to_writer_pretty` produces JSON that is readable. This code
-                // gives us JSON that is fairly compact and fairly readable.
-                // Ideally it would be more like what DHAT produces, e.g. one
-                // space indents, no spaces after `:` and `,`, and `fs` arrays
-                // on a single line, but this is as good as we can easily
-                // achieve.
-                let formatter = serde_json::ser::PrettyFormatter::with_indent(b"");
-                let mut ser = serde_json::Serializer::with_formatter(buffered_file, formatter);
-                json.serialize(&mut ser)?;
-                Ok(())
-            };
-            match write() {
-                Ok(()) => eprintln!(
-                    "dhat: The data has been saved to {}, and is viewable with dhat/dh_view.html",
-                    self.file_name.to_string_lossy()
-                ),
-                Err(e) => eprintln!(
-                    "dhat: error: Writing to {} failed: {}",
-                    self.file_name.to_string_lossy(),
-                    e
-                ),
-            }
-        }
-        if self.eprint_json {
-            eprintln!(
-                "dhat: json = `{}`",
-                serde_json::to_string_pretty(&json).unwrap()
-            );
-        }
+        let mut matrix: Vec<_> = h
+            .dealloc_counts
+            .iter()
+            .map(|(&(pp_info_idx, dealloc_idx), &count)| {
+                let alloc_frame = alloc_frames
+                    .entry(pp_info_idx)
+                    .or_insert_with(|| self.top_frame_string(self.pp_backtrace(pp_info_idx)))
+                    .clone();
+                (alloc_frame, dealloc_frames[dealloc_idx].clone(), count)
+            })
+            .collect();
+        // Most common pairs first.
+        matrix.sort_unstable_by(|a, b| {
+            b.2.cmp(&a.2)
+                .then_with(|| a.0.cmp(&b.0))
+                .then_with(|| a.1.cmp(&b.1))
+        });
+        matrix
     }
-}
 
-impl HeapGlobals {
-    fn new() -> Self {
-        Self {
-            live_blocks: FxHashMap::default(),
+    fn get_heap_stats_matching(&mut self, needle: &str) -> HeapStats {
+        if self.heap.is_none() {
+            panic!("dhat: getting heap stats while doing ad hoc profiling");
+        }
+
+        // Make sure the `at_tgmax_*` counts are up to date.
+        self.check_for_global_peak();
+
+        let resolved = Backtrace::resolve_all(&self.frames, self.backtraces.keys());
+        let mut stats = HeapStats {
+            total_blocks: 0,
+            total_bytes: 0,
             curr_blocks: 0,
             curr_bytes: 0,
+            freed_blocks: 0,
+            freed_bytes: 0,
             max_blocks: 0,
             max_bytes: 0,
-            tgmax_instant: Instant::now(),
+            pp_count: 0,
+            failed_blocks: 0,
+            realloc_blocks: 0,
+            realloc_bytes_moved: 0,
+            realloc_in_place: 0,
+            realloc_moved: 0,
+            zst_allocations: 0,
+            largest_block: 0,
+        };
+        for (bt, &pp_info_idx) in self.backtraces.iter() {
+            let matches = bt.region.is_some_and(|label| label.contains(needle))
+                || bt.tag_string().is_some_and(|s| s.contains(needle))
+                || bt
+                    .location_string(self.path_components)
+                    .is_some_and(|s| s.contains(needle))
+                || bt.symbols(&resolved).any(|(frame, symbol)| {
+                    Backtrace::frame_to_string(frame, symbol, self.path_components).contains(needle)
+                });
+            if matches {
+                let pp_info = &self.pp_infos[pp_info_idx];
+                let h = pp_info.heap.as_ref().unwrap();
+                stats.total_blocks += pp_info.total_blocks;
+                stats.total_bytes += pp_info.total_bytes;
+                stats.curr_blocks += h.curr_blocks;
+                stats.curr_bytes += h.curr_bytes;
+                // Per PP, everything not live has been freed.
+                stats.freed_blocks += pp_info.total_blocks.saturating_sub(h.curr_blocks as u64);
+                stats.freed_bytes += pp_info.total_bytes.saturating_sub(h.curr_bytes as u64);
+                stats.max_blocks += h.at_tgmax_blocks;
+                stats.max_bytes += h.at_tgmax_bytes;
+                stats.pp_count += 1;
+            }
         }
+        stats
     }
-}
 
-struct PpInfo {
-    // The total number of blocks and bytes allocated by this PP.
-    total_blocks: u64,
-    total_bytes: u64,
+    fn get_peak_site(&mut self) -> Option<(String, usize)> {
+        self.heap.as_ref()?;
 
-    heap: Option<HeapPpInfo>,
-}
+        // Make sure the `at_tgmax_*` counts are up to date.
+        self.check_for_global_peak();
 
-#[derive(Default)]
-struct HeapPpInfo {
+        let (pp_info_idx, bytes) = self
+            .pp_infos
+            .iter()
+            .enumerate()
+            .map(|(idx, pp_info)| (idx, pp_info.heap.as_ref().unwrap().at_tgmax_bytes))
+            .max_by_key(|&(_, bytes)| bytes)?;
+        if bytes == 0 {
+            return None;
+        }
+        Some((self.top_frame_string(self.pp_backtrace(pp_info_idx)), bytes))
+    }
+
+    // For `ProfilerBuilder::fail_on_leak`: get the first user frame of the PP
+    // with the most bytes still allocated, and the total bytes and blocks still
+    // allocated. `None` if nothing is still allocated.
+    fn get_leak(&self) -> Option<(String, usize, usize)> {
+        let h = self.heap.as_ref()?;
+        if h.curr_blocks == 0 {
+            return None;
+        }
+        let pp_info_idx = self
+            .pp_infos
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, pp_info)| pp_info.heap.as_ref().unwrap().curr_bytes)
+            .map(|(idx, _)| idx)?;
+        let site = self
+            .frame_format()
+            .user_frame_string(&self.frames, self.pp_backtrace(pp_info_idx));
+        Some((site, h.curr_bytes, h.curr_blocks))
+    }
+
+    fn get_largest_block_site(&self) -> Option<(String, usize)> {
+        let h = self.heap.as_ref()?;
+        let pp_info_idx = h.largest_block_pp?;
+        Some((
+            self.top_frame_string(self.pp_backtrace(pp_info_idx)),
+            h.largest_block_bytes,
+        ))
+    }
+
+    fn get_bytes_by_file(&self) -> Vec<(String, u64)> {
+        // Resolve all the backtraces together, so each unique IP is resolved
+        // only once.
+        let frame_format = self.frame_format();
+        let resolved = Backtrace::resolve_all(&self.frames, self.backtraces.keys());
+
+        let mut bytes_by_file: FxHashMap<String, u64> = FxHashMap::default();
+        for (bt, &pp_info_idx) in self.backtraces.iter() {
+            let file = frame_format.top_file_string(&resolved, bt);
+            *bytes_by_file.entry(file).or_default() += self.pp_infos[pp_info_idx].total_bytes;
+        }
+        let mut bytes_by_file: Vec<_> = bytes_by_file.into_iter().collect();
+        bytes_by_file.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        bytes_by_file
+    }
+
+    fn get_live_blocks(&self) -> Vec<LiveBlockInfo> {
+        let h = match &self.heap {
+            Some(h) => h,
+            None => return vec![],
+        };
+        let now = self.now();
+
+        // Map each PP to its backtrace, then get the top frame of each PP
+        // with a live block. Resolution is expensive, so each backtrace is
+        // resolved at most once.
+        let mut pp_backtraces = vec![None; self.pp_infos.len()];
+        for (bt, &pp_info_idx) in self.backtraces.iter() {
+            pp_backtraces[pp_info_idx] = Some(bt);
+        }
+        let mut top_frames: FxHashMap<usize, String> = FxHashMap::default();
+
+        let mut blocks: Vec<_> = h
+            .live_blocks
+            .values()
+            .map(|live_block| {
+                let top_frame = top_frames
+                    .entry(live_block.pp_info_idx)
+                    .or_insert_with(|| {
+                        let bt = pp_backtraces[live_block.pp_info_idx].unwrap();
+                        self.top_frame_string(bt)
+                    })
+                    .clone();
+                LiveBlockInfo {
+                    size: live_block.size,
+                    age: now.duration_since(live_block.allocation_instant),
+                    top_frame,
+                }
+            })
+            .collect();
+        // Biggest blocks first.
+        blocks.sort_unstable_by(|a, b| b.size.cmp(&a.size).then_with(|| b.age.cmp(&a.age)));
+        blocks
+    }
+
+    // Get the string for the first interesting frame of a heap backtrace, as
+    // it would appear in the saved profile.
+    fn top_frame_string(&self, bt: &Backtrace) -> String {
+        self.frame_strings(bt)
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| "???".to_string())
+    }
+
+    // Get the strings for the interesting frames of a heap backtrace, as they
+    // would appear in the saved profile.
+    fn frame_strings(&self, bt: &Backtrace) -> Vec<String> {
+        self.frame_format().frame_strings(&self.frames, bt)
+    }
+
+    fn frame_format(&self) -> FrameFormat {
+        FrameFormat {
+            resolve_symbols: self.resolve_symbols,
+            trim_heap_frames: self.heap.is_some() && self.trim_backtraces.is_some(),
+            trim_top_matching: self.trim_top_matching.clone(),
+            path_components: self.path_components,
+        }
+    }
+
+    // Also returns the frames for the returned backtraces, so that they can
+    // be turned into frame strings without the `Globals`.
+    #[allow(clippy::type_complexity)]
+    fn get_pp_reports(&mut self) -> (FrameFormat, FrameTable, Vec<(Backtrace, PpReport)>) {
+        if self.heap.is_some() {
+            // Make sure the `at_tgmax_*` counts are up to date.
+            self.check_for_global_peak();
+        }
+
+        let mut pps: Vec<_> = self
+            .backtraces
+            .iter()
+            .map(|(bt, &pp_info_idx)| {
+                let pp_info = &self.pp_infos[pp_info_idx];
+                let h = pp_info.heap.as_ref();
+                let report = PpReport {
+                    total_bytes: pp_info.total_bytes,
+                    total_blocks: pp_info.total_blocks,
+                    max_bytes: h.map_or(0, |h| h.max_bytes),
+                    at_tgmax_bytes: h.map_or(0, |h| h.at_tgmax_bytes),
+                    curr_bytes: h.map_or(0, |h| h.curr_bytes),
+                    lifetime_histogram: h.map_or(vec![], |h| h.lifetime_histogram.clone()),
+                    thrash_cycles: h.map_or(0, |h| h.thrash_cycles),
+                    frames: vec![],
+                };
+                (pp_info_idx, bt.clone(), report)
+            })
+            .collect();
+        pps.sort_unstable_by_key(|&(pp_info_idx, ..)| pp_info_idx);
+        let pps: Vec<_> = pps
+            .into_iter()
+            .map(|(_, bt, report)| (bt, report))
+            .collect();
+        let frames = pps
+            .iter()
+            .flat_map(|(bt, _)| bt.ips.iter())
+            .map(|ip| (*ip, self.frames[ip].clone()))
+            .collect();
+        (self.frame_format(), frames, pps)
+    }
+
+    fn get_ad_hoc_stats(&self) -> AdHocStats {
+        match self.try_get_ad_hoc_stats() {
+            Some(stats) => stats,
+            None => panic!("dhat: getting ad hoc stats while doing heap profiling"),
+        }
+    }
+
+    fn try_get_ad_hoc_stats(&self) -> Option<AdHocStats> {
+        if self.heap.is_some() {
+            return None;
+        }
+        Some(AdHocStats {
+            total_events: self.total_blocks,
+            total_units: self.total_bytes,
+            total_units_f64: self.total_units_f64,
+            pp_count: self.pp_infos.len(),
+        })
+    }
+
+    // Finish tracking allocations and deallocations, and save the profile to
+    // file/memory if requested. Returns the summary message, to be printed
+    // (to `stderr` by default) once `TRI_GLOBALS` is unlocked.
+    fn finish(mut self, memory_output: Option<&mut String>) -> Output {
+        let now = self.now();
+        let mut out = Output::new(self.summary_output);
+
+        if self.heap.is_some() {
+            // Total bytes is at a possible peak.
+            self.check_for_global_peak();
+
+            // The most likely cause of this is a missing `#[global_allocator]`.
+            if self.total_blocks == 0 {
+                out.warn(format_args!(
+                    "dhat: warning: recorded zero allocations; did you set \
+                     `#[global_allocator] static ALLOC: dhat::Alloc = dhat::Alloc;`?"
+                ));
+            }
+
+            if cfg!(debug_assertions) && self.heap.as_ref().unwrap().underflowed {
+                out.warn(format_args!(
+                    "dhat: warning: heap counts underflowed on deallocation; counts may be \
+                     inaccurate"
+                ));
+            }
+        }
+
+        // This must be done before `keep_only_net`, which discards PPs that
+        // may have had live blocks at the peak.
+        let peak_json = self
+            .save_at_peak
+            .as_ref()
+            .filter(|_| self.heap.is_some())
+            .map(|path| (path.clone(), self.peak_json(now)));
+
+        // Likewise, `keep_only_net` changes the totals.
+        let stats_json = self.stats_file.is_some().then(|| self.stats_json(now));
+
+        if self.heap.is_some() && self.net_only {
+            self.keep_only_net();
+        }
+
+        if self.all_backtraces_empty() {
+            out.warn(format_args!(
+                "dhat: warning: all backtraces are empty; check that unwinding works on this \
+                 target, e.g. that it isn't built with `panic = \"abort\"` and stripped of unwind \
+                 tables"
+            ));
+        }
+
+        // These must be done before `self.backtraces` is taken below.
+        let truncated = self.truncated_backtraces();
+        let metadata_bytes = self.metadata_bytes();
+
+        // Because `self` is being consumed, we can consume `self.backtraces`
+        // and replace it with an empty `FxHashMap`. (This is necessary because
+        // we modify the *keys* with `resolve`, which isn't allowed with a
+        // non-consuming iterator.)
+        let backtraces = std::mem::take(&mut self.backtraces);
+        if self.stream_output && memory_output.is_none() && !self.needs_json_in_memory() {
+            let mut out = self.finish_streamed(backtraces, truncated, metadata_bytes, now, out);
+            if let Some((path, peak_json)) = peak_json {
+                self.save(&peak_json, &path, &mut out);
+            }
+            if let (Some(path), Some(stats_json)) = (&self.stats_file, stats_json) {
+                self.save_stats(&stats_json, path, &mut out);
+            }
+            return out;
+        }
+        let json = self.to_json(backtraces, now);
+
+        self.print_summary(&json, truncated, metadata_bytes, &mut out);
+        if json.missing_line_info {
+            out.warn(format_args!(
+                "dhat: warning: no line info found; add `debug = 1` to your release profile"
+            ));
+        }
+
+        if let Some(memory_output) = memory_output {
+            // Default pretty printing is fine here, it's only used for small
+            // tests.
+            *memory_output = serde_json::to_string_pretty(&json).unwrap();
+            out.println(format_args!(
+                "dhat: The data has been saved to the memory buffer"
+            ));
+        } else {
+            self.save_profile(&json, &mut out);
+        }
+        if let Some((path, weight)) = &self.folded_output {
+            self.save_folded(&json, path, *weight, &mut out);
+        }
+        if let Some((path, _)) = &self.chrome_trace {
+            self.save_chrome_trace(path, &mut out);
+        }
+        #[cfg(feature = "pprof")]
+        if let Some(path) = &self.pprof_output {
+            self.save_pprof(&json, path, &mut out);
+        }
+        if let Some((path, peak_json)) = peak_json {
+            self.save(&peak_json, &path, &mut out);
+        }
+        if let (Some(path), Some(stats_json)) = (&self.stats_file, stats_json) {
+            self.save_stats(&stats_json, path, &mut out);
+        }
+        if self.eprint_json {
+            out.json = Some(format!(
+                "dhat: json = `{}`\n",
+                serde_json::to_string_pretty(&json).unwrap()
+            ));
+        }
+        out
+    }
+
+    // Get the summary stats, for `ProfilerBuilder::stats_file`.
+    fn stats_json(&self, now: Instant) -> StatsJson {
+        let h = self.try_get_heap_stats();
+        StatsJson {
+            mode: if h.is_some() { "heap" } else { "ad-hoc" },
+            total_blocks: self.total_blocks,
+            total_bytes: self.total_bytes,
+            curr_blocks: h.as_ref().map(|h| h.curr_blocks),
+            curr_bytes: h.as_ref().map(|h| h.curr_bytes),
+            max_blocks: h.as_ref().map(|h| h.max_blocks),
+            max_bytes: h.as_ref().map(|h| h.max_bytes),
+            elapsed_secs: now.duration_since(self.start_instant).as_secs_f64(),
+        }
+    }
+
+    // Build the JSON representation of the blocks that were live at the
+    // global peak, for `ProfilerBuilder::save_at_peak`. It is like a profile
+    // that ended at the peak: each PP's counts are its counts at the peak,
+    // and PPs with no live blocks at the peak are omitted. The `at_tgmax`
+    // counts are kept up to date for every PP anyway, so nothing extra needs
+    // to be recorded while profiling.
+    fn peak_json(&self, now: Instant) -> DhatJson {
+        let backtraces: Vec<_> = self
+            .backtraces
+            .iter()
+            .filter(|(_, &pp_info_idx)| {
+                self.pp_infos[pp_info_idx]
+                    .heap
+                    .as_ref()
+                    .unwrap()
+                    .at_tgmax_blocks
+                    > 0
+            })
+            .map(|(bt, &pp_info_idx)| (bt.clone(), pp_info_idx))
+            .collect();
+        let mut json = self.to_json(backtraces, now);
+        for pp in &mut json.pps {
+            let (gb, gbk) = (pp.gb.unwrap(), pp.gbk.unwrap());
+            pp.tb = Units::Int(gb as u64);
+            pp.tbk = gbk as u64;
+            // Lifetimes aren't known until blocks are freed.
+            pp.tl = Some(0);
+            pp.mb = Some(gb);
+            pp.mbk = Some(gbk);
+            pp.eb = Some(gb);
+            pp.ebk = Some(gbk);
+            pp.mt = None;
+            pp.lh = None;
+        }
+        json.te = json.tg.unwrap();
+        json
+    }
+
+    // Discard all the data recorded so far and rename the output files, for
+    // `ProfilerBuilder::reset_on_fork`. Called in a child process after a
+    // `fork`, where nothing may be allocated or freed, so the fresh state is
+    // swapped in from `reset`, which was built by `fork_prepare`, and the
+    // parent's data is kept in `fork_discarded` until this `Globals` is
+    // dropped. The configuration is kept.
+    #[cfg(unix)]
+    fn reset_for_fork(&mut self, mut reset: Box<ForkReset>, pid: u32) {
+        use std::mem::swap;
+
+        reset.file_name.swap(&mut self.file_name, pid);
+        if let (Some((path, _)), Some(name)) = (&mut self.folded_output, &mut reset.folded_output) {
+            name.swap(path, pid);
+        }
+        if let (Some((path, _)), Some(name)) = (&mut self.chrome_trace, &mut reset.chrome_trace) {
+            name.swap(path, pid);
+        }
+        if let (Some(path), Some(name)) = (&mut self.save_at_peak, &mut reset.save_at_peak) {
+            name.swap(path, pid);
+        }
+        if let (Some(path), Some(name)) = (&mut self.stats_file, &mut reset.stats_file) {
+            name.swap(path, pid);
+        }
+        #[cfg(feature = "pprof")]
+        if let (Some(path), Some(name)) = (&mut self.pprof_output, &mut reset.pprof_output) {
+            name.swap(path, pid);
+        }
+        // The file is shared with the parent.
+        swap(&mut self.file, &mut reset.file);
+
+        self.soft_assert_failed = false;
+        self.start_instant = reset.start_instant;
+        swap(&mut self.pp_infos, &mut reset.pp_infos);
+        swap(&mut self.frames, &mut reset.frames);
+        swap(&mut self.backtraces, &mut reset.backtraces);
+        self.backtrace_bytes = 0;
+        self.total_blocks = 0;
+        self.total_bytes = 0;
+        self.total_units_f64 = 0.0;
+        swap(&mut self.thread_totals, &mut reset.thread_totals);
+        swap(&mut self.heap, &mut reset.heap);
+
+        // The helper threads weren't copied into the child.
+        swap(&mut self.stop_dump_thread, &mut reset.stop_dump_thread);
+        swap(
+            &mut self.stop_sampler_thread,
+            &mut reset.stop_sampler_thread,
+        );
+        swap(&mut self.stop_rotate_thread, &mut reset.stop_rotate_thread);
+
+        // Keep the data discarded by any earlier reset, e.g. in the parent if
+        // it is itself a child, without freeing anything.
+        swap(&mut self.fork_discarded, &mut reset.prev);
+        self.fork_discarded = Some(reset);
+    }
+
+    // Recompute the totals from the live blocks, for
+    // `ProfilerBuilder::net_only`, and drop the PPs that have no live blocks.
+    fn keep_only_net(&mut self) {
+        let h = self.heap.as_ref().unwrap();
+        for pp_info in &mut self.pp_infos {
+            pp_info.total_blocks = 0;
+            pp_info.total_bytes = 0;
+        }
+        self.total_blocks = 0;
+        self.total_bytes = 0;
+        for live_block in h.live_blocks.values() {
+            let pp_info = &mut self.pp_infos[live_block.pp_info_idx];
+            pp_info.total_blocks += 1;
+            pp_info.total_bytes += live_block.size as u64;
+            self.total_blocks += 1;
+            self.total_bytes += live_block.size as u64;
+        }
+        let pp_infos = &self.pp_infos;
+        self.backtraces
+            .retain(|_, pp_info_idx| pp_infos[*pp_info_idx].total_blocks > 0);
+    }
+
+    // Were backtraces requested and obtained, but all of them empty? This
+    // happens on targets where unwinding doesn't work, and makes all the PPs
+    // indistinguishable. (Backtraces from `ad_hoc_event_here` have a location
+    // instead of frames, and backtraces cut short by the backtrace budget have
+    // no frames by design, so neither count.)
+    fn all_backtraces_empty(&self) -> bool {
+        let mut bts = self.backtraces.keys().filter(|bt| !bt.truncated).peekable();
+        !self.no_backtraces
+            && bts.peek().is_some()
+            && bts.all(|bt| bt.ips.is_empty() && bt.location.is_none())
+    }
+
+    // The number of PPs whose backtraces were cut short by the backtrace
+    // budget, for the summary.
+    fn truncated_backtraces(&self) -> usize {
+        self.backtraces.keys().filter(|bt| bt.truncated).count()
+    }
+
+    // Do any of the requested outputs need the entire JSON in memory?
+    fn needs_json_in_memory(&self) -> bool {
+        #[cfg(feature = "pprof")]
+        if self.pprof_output.is_some() {
+            return true;
+        }
+        // PPs can't be merged as they are streamed.
+        self.folded_output.is_some() || self.eprint_json || self.merge_by_line
+    }
+
+    // Like the end of `finish`, but writes each PP to file as it is built,
+    // instead of building a `DhatJson` with all the PPs in memory first.
+    fn finish_streamed(
+        &self,
+        backtraces: FxHashMap<Backtrace, usize>,
+        truncated: usize,
+        metadata_bytes: usize,
+        now: Instant,
+        mut out: Output,
+    ) -> Output {
+        let backtraces: Vec<_> = backtraces.into_iter().collect();
+        let builder = RefCell::new(PpJsonBuilder::new(self, &backtraces, now));
+        let missing_line_info = builder.borrow().missing_line_info();
+
+        // `ftbl` is serialized after `pps`, so it is complete by then.
+        let pps = StreamedSeq::new(
+            backtraces
+                .iter()
+                .map(|(bt, pp_info_idx)| builder.borrow_mut().pp_json(bt, *pp_info_idx)),
+        );
+        let ftbl = StreamedFtbl(&builder);
+        let json = self.dhat_json(now, pps, ftbl, missing_line_info);
+
+        self.print_summary(&json, truncated, metadata_bytes, &mut out);
+        if json.missing_line_info {
+            out.warn(format_args!(
+                "dhat: warning: no line info found; add `debug = 1` to your release profile"
+            ));
+        }
+        self.save_profile(&json, &mut out);
+        if let Some((path, _)) = &self.chrome_trace {
+            self.save_chrome_trace(path, &mut out);
+        }
+        out
+    }
+
+    // Save the profile to file, without stopping profiling. Returns the
+    // summary message, like `finish`.
+    fn snapshot(&mut self) -> Output {
+        let mut out = Output::new(self.summary_output);
+        let json = self.snapshot_json();
+
+        self.print_summary(
+            &json,
+            self.truncated_backtraces(),
+            self.metadata_bytes(),
+            &mut out,
+        );
+        self.save_profile(&json, &mut out);
+        if let Some((path, weight)) = &self.folded_output {
+            self.save_folded(&json, path, *weight, &mut out);
+        }
+        if let Some((path, _)) = &self.chrome_trace {
+            self.save_chrome_trace(path, &mut out);
+        }
+        #[cfg(feature = "pprof")]
+        if let Some(path) = &self.pprof_output {
+            self.save_pprof(&json, path, &mut out);
+        }
+        if self.eprint_json {
+            out.json = Some(format!(
+                "dhat: json = `{}`\n",
+                serde_json::to_string_pretty(&json).unwrap()
+            ));
+        }
+        out
+    }
+
+    // Serialize the profile into memory, without stopping profiling.
+    fn write_to_vec(&mut self) -> Vec<u8> {
+        let json = self.snapshot_json();
+        self.write_json(&json, vec![]).unwrap()
+    }
+
+    // Build the JSON representation of the profile, without stopping
+    // profiling.
+    fn snapshot_json(&mut self) -> DhatJson {
+        let now = self.now();
+
+        if self.heap.is_some() {
+            // Total bytes is at a possible peak.
+            self.check_for_global_peak();
+        }
+
+        // We can't consume `self.backtraces` because profiling continues, so
+        // we resolve copies of the backtraces instead.
+        let backtraces: Vec<_> = self
+            .backtraces
+            .iter()
+            .map(|(bt, &pp_info_idx)| (bt.clone(), pp_info_idx))
+            .collect();
+        self.to_json(backtraces, now)
+    }
+
+    // Build the JSON representation of the profile.
+    fn to_json<I>(&self, backtraces: I, now: Instant) -> DhatJson
+    where
+        I: IntoIterator<Item = (Backtrace, usize)>,
+    {
+        let backtraces: Vec<_> = backtraces.into_iter().collect();
+        let mut builder = PpJsonBuilder::new(self, &backtraces, now);
+        let pps = backtraces
+            .iter()
+            .map(|(bt, pp_info_idx)| builder.pp_json(bt, *pp_info_idx));
+        let pps = if self.merge_by_line {
+            PpInfoJson::merge_identical(pps)
+        } else {
+            pps.collect()
+        };
+        let missing_line_info = builder.missing_line_info();
+        let ftbl = builder.into_ftbl();
+        self.dhat_json(now, pps, ftbl, missing_line_info)
+    }
+
+    // Build the JSON representation of the profile from the given `pps` and
+    // `ftbl`, which may be collections or streams.
+    fn dhat_json<P, F>(
+        &self,
+        now: Instant,
+        pps: P,
+        ftbl: F,
+        missing_line_info: bool,
+    ) -> DhatJson<P, F> {
+        let h = self.heap.as_ref();
+        let is_heap = h.is_some();
+        DhatJson {
+            dhatFileVersion: 2,
+            mode: if is_heap { "rust-heap" } else { "rust-ad-hoc" },
+            verb: "Allocated",
+            bklt: is_heap,
+            bkacc: false,
+            bu: if is_heap { None } else { Some("unit") },
+            bsu: if is_heap { None } else { Some("units") },
+            bksu: if is_heap { None } else { Some("events") },
+            tu: self.time_unit.name(),
+            Mtu: self.time_unit.major_name(),
+            tuth: if is_heap {
+                Some(self.time_unit.short_lived_threshold())
+            } else {
+                None
+            },
+            cmd: self.cmd.clone(),
+            pid: std::process::id(),
+            tg: h.map(|h| {
+                self.time_unit.convert(
+                    h.tgmax_instant
+                        .saturating_duration_since(self.start_instant),
+                )
+            }),
+            te: self
+                .time_unit
+                .convert(now.duration_since(self.start_instant)),
+            pps,
+            ftbl,
+            unresolved: if self.resolve_symbols {
+                None
+            } else {
+                Some(true)
+            },
+            env: self.env.clone(),
+            markers: h.filter(|h| !h.markers.is_empty()).map(|h| {
+                h.markers
+                    .iter()
+                    .map(|(instant, name)| MarkerJson {
+                        t: self
+                            .time_unit
+                            .convert(instant.saturating_duration_since(self.start_instant)),
+                        name: name.clone(),
+                    })
+                    .collect()
+            }),
+            curve: h.filter(|h| !h.curve.is_empty()).map(|h| {
+                h.curve
+                    .iter()
+                    .map(|&(instant, bytes, blocks)| {
+                        let t = self
+                            .time_unit
+                            .convert(instant.saturating_duration_since(self.start_instant));
+                        (t, bytes, blocks)
+                    })
+                    .collect()
+            }),
+            config: ConfigJson {
+                mode: if is_heap { "heap" } else { "ad-hoc" },
+                trim_backtraces: self.trim_backtraces,
+                no_backtraces: self.no_backtraces,
+                min_size: self.min_size,
+                sample_curve: self.sample_curve.map(|d| self.time_unit.convert(d)),
+                peak_metric: match self.peak_metric {
+                    PeakMetric::Bytes => "bytes",
+                    PeakMetric::Blocks => "blocks",
+                },
+                track_alignment: self.track_alignment,
+                net_only: self.net_only,
+                split_by_size_class: self.split_by_size_class,
+            },
+            missing_line_info,
+        }
+    }
+
+    fn print_summary<P, F>(
+        &self,
+        json: &DhatJson<P, F>,
+        truncated: usize,
+        metadata_bytes: usize,
+        out: &mut Output,
+    ) {
+        let total = if self.total_units_f64 == 0.0 {
+            self.total_bytes.separate_with_commas()
+        } else {
+            (self.total_bytes as f64 + self.total_units_f64).separate_with_commas()
+        };
+        out.println(format_args!(
+            "dhat: Total:     {} {} in {} {}",
+            total,
+            json.bsu.unwrap_or("bytes"),
+            self.total_blocks.separate_with_commas(),
+            json.bksu.unwrap_or("blocks"),
+        ));
+        if let Some(h) = &self.heap {
+            out.println(format_args!(
+                "dhat: At t-gmax: {} bytes in {} blocks",
+                h.max_bytes.separate_with_commas(),
+                h.max_blocks.separate_with_commas(),
+            ));
+            if let Some((bytes, blocks)) = h.t_end_before_settle {
+                out.println(format_args!(
+                    "dhat: At t-end:  {} bytes in {} blocks",
+                    bytes.separate_with_commas(),
+                    blocks.separate_with_commas(),
+                ));
+                out.println(format_args!(
+                    "dhat: At t-end+settle: {} bytes in {} blocks",
+                    h.curr_bytes.separate_with_commas(),
+                    h.curr_blocks.separate_with_commas(),
+                ));
+            } else {
+                out.println(format_args!(
+                    "dhat: At t-end:  {} bytes in {} blocks",
+                    h.curr_bytes.separate_with_commas(),
+                    h.curr_blocks.separate_with_commas(),
+                ));
+            }
+            if h.largest_block_bytes > 0 {
+                out.println(format_args!(
+                    "dhat: Largest:   {} bytes",
+                    h.largest_block_bytes.separate_with_commas(),
+                ));
+            }
+            if h.failed_blocks > 0 {
+                out.println(format_args!(
+                    "dhat: Failed:    {} blocks",
+                    h.failed_blocks.separate_with_commas(),
+                ));
+            }
+            if h.preexisting_realloc_blocks > 0 {
+                // Many of these suggest the profiler is started too late.
+                out.println(format_args!(
+                    "dhat: Pre-existing: {} blocks allocated before profiling started were \
+                     reallocated, and counted as new allocations",
+                    h.preexisting_realloc_blocks.separate_with_commas(),
+                ));
+            }
+            if let Some(threshold) = self.detect_thrashing {
+                let n = self
+                    .pp_infos
+                    .iter()
+                    .filter(|pp_info| pp_info.heap.as_ref().unwrap().thrash_cycles > threshold)
+                    .count();
+                if n > 0 {
+                    out.println(format_args!(
+                        "dhat: Thrashing: {} allocation site{} repeatedly allocate and free \
+                         similar-sized blocks; consider reuse or pooling",
+                        n.separate_with_commas(),
+                        if n == 1 { "" } else { "s" },
+                    ));
+                }
+            }
+            for (align, a) in h.align_counts.iter() {
+                out.println(format_args!(
+                    "dhat: Align {}: {} bytes in {} blocks, ~{} bytes of padding",
+                    align,
+                    a.bytes.separate_with_commas(),
+                    a.blocks.separate_with_commas(),
+                    a.padding_bytes.separate_with_commas(),
+                ));
+            }
+        }
+
+        if truncated > 0 {
+            out.println(format_args!(
+                "dhat: Truncated: {} program point{} with backtraces cut short by the \
+                 backtrace budget",
+                truncated.separate_with_commas(),
+                if truncated == 1 { "" } else { "s" },
+            ));
+        }
+
+        // Only mention `dhat`'s own memory use when it's large enough to be
+        // surprising.
+        if metadata_bytes > 100 * 1024 * 1024 {
+            out.println(format_args!(
+                "dhat: Metadata:  {} bytes (approx.) used by dhat itself",
+                metadata_bytes.separate_with_commas(),
+            ));
+        }
+    }
+
+    // Save the profile to `self.file`, if present, or `self.file_name`.
+    fn save_profile<T: Serialize>(&self, json: &T, out: &mut Output) {
+        match &self.file {
+            Some(file) => self.save_to_file(json, file, out),
+            None => self.save(json, &self.file_name, out),
+        }
+    }
+
+    // Save the profile to `file`, replacing anything written to it by an
+    // earlier snapshot. Unlike `save`, this can leave a truncated profile
+    // behind if writing fails. Only regular files can be truncated; anything
+    // else, such as a pipe, is just written to.
+    fn save_to_file<T: Serialize>(&self, json: &T, file: &File, out: &mut Output) {
+        let write = || -> std::io::Result<()> {
+            let mut file = file;
+            if file.metadata()?.is_file() {
+                file.set_len(0)?;
+                file.seek(SeekFrom::Start(0))?;
+            }
+            self.write_json(json, BufWriter::new(file))?.into_inner()?;
+            if self.sync_on_write {
+                file.sync_all()?;
+            }
+            Ok(())
+        };
+        match write() {
+            Ok(()) => out.println(format_args!(
+                "dhat: The data has been saved to the file given to `ProfilerBuilder::file`, and \
+                 is viewable with dhat/dh_view.html"
+            )),
+            Err(e) => out.println(format_args!(
+                "dhat: error: Writing to the file given to `ProfilerBuilder::file` failed: {}",
+                e
+            )),
+        }
+    }
+
+    // Save the profile to `path`, which is usually `self.file_name`.
+    fn save<T: Serialize>(&self, json: &T, path: &Path, out: &mut Output) {
+        let res = write_file_atomically(path, self.sync_on_write, |w| self.write_json(json, w));
+        match res {
+            Ok(()) => {
+                out.println(format_args!(
+                    "dhat: The data has been saved to {}, and is viewable with dhat/dh_view.html",
+                    path.to_string_lossy()
+                ));
+                if self.sync_on_write {
+                    if let Err(e) = sync_parent_dir(path) {
+                        out.warn(format_args!(
+                            "dhat: warning: Syncing the directory of {} failed, so the file \
+                             may not survive a crash: {}",
+                            path.to_string_lossy(),
+                            e
+                        ));
+                    }
+                }
+            }
+            Err(e) => out.println(format_args!(
+                "dhat: error: Writing to {} failed: {}",
+                path.to_string_lossy(),
+                e
+            )),
+        }
+    }
+}
+
+impl Globals {
+    // Write the JSON in the saved profile's format to `writer`, and return
+    // the writer.
+    fn write_json<T: Serialize, W: Write>(&self, json: &T, writer: W) -> std::io::Result<W> {
+        if self.compact_output {
+            let mut ser = serde_json::Serializer::with_formatter(writer, DhatFormatter::default());
+            json.serialize(&mut ser)?;
+            Ok(ser.into_inner())
+        } else {
+            // `to_writer` produces JSON that is compact. `to_writer_pretty`
+            // produces JSON that is readable. This code gives us JSON that is
+            // fairly compact and fairly readable. `DhatFormatter` is more
+            // compact still.
+            let formatter = serde_json::ser::PrettyFormatter::with_indent(b"");
+            let mut ser = serde_json::Serializer::with_formatter(writer, formatter);
+            json.serialize(&mut ser)?;
+            Ok(ser.into_inner())
+        }
+    }
+
+    // Save the profile to `path` as folded stacks, one line per PP, which is
+    // the input format for flamegraph tools such as `inferno-flamegraph`.
+    fn save_folded(&self, json: &DhatJson, path: &Path, weight: FoldedWeight, out: &mut Output) {
+        let write = || -> std::io::Result<()> {
+            let mut file = BufWriter::new(File::create(path)?);
+            for pp in &json.pps {
+                let n = match weight {
+                    FoldedWeight::TotalBytes => match pp.tb {
+                        Units::Int(n) => n,
+                        Units::Float(n) => n.round() as u64,
+                    },
+                    FoldedWeight::MaxBytes => pp.mb.unwrap_or(0) as u64,
+                };
+                if n == 0 {
+                    continue;
+                }
+                // Folded stacks go from the outermost frame to the innermost,
+                // and use `;` as the separator, so it can't appear in frames.
+                let frames: Vec<_> = pp
+                    .fs
+                    .iter()
+                    .rev()
+                    .map(|&ftbl_idx| json.ftbl.strings[ftbl_idx].replace(';', ":"))
+                    .collect();
+                writeln!(file, "{} {}", frames.join(";"), n)?;
+            }
+            file.flush()
+        };
+        match write() {
+            Ok(()) => out.println(format_args!(
+                "dhat: The folded stacks have been saved to {}",
+                path.to_string_lossy()
+            )),
+            Err(e) => out.println(format_args!(
+                "dhat: error: Writing to {} failed: {}",
+                path.to_string_lossy(),
+                e
+            )),
+        }
+    }
+}
+
+impl Globals {
+    // Save the summary stats to `path`, for `ProfilerBuilder::stats_file`.
+    fn save_stats(&self, json: &StatsJson, path: &Path, out: &mut Output) {
+        let write = || -> std::io::Result<()> {
+            let mut file = BufWriter::new(File::create(path)?);
+            serde_json::to_writer_pretty(&mut file, json)?;
+            writeln!(file)?;
+            file.flush()
+        };
+        match write() {
+            Ok(()) => out.println(format_args!(
+                "dhat: The stats have been saved to {}",
+                path.to_string_lossy()
+            )),
+            Err(e) => out.println(format_args!(
+                "dhat: error: Writing to {} failed: {}",
+                path.to_string_lossy(),
+                e
+            )),
+        }
+    }
+
+    // Save the recorded trace events to `path` in the Chrome trace event
+    // format, as counter events for the current bytes, plus an instant event
+    // at the global peak.
+    fn save_chrome_trace(&self, path: &Path, out: &mut Output) {
+        let h = match &self.heap {
+            Some(h) => h,
+            None => return,
+        };
+        let write = || -> std::io::Result<()> {
+            let pid = std::process::id();
+            let event = |name, ph, instant: Instant, bytes| ChromeTraceEvent {
+                name,
+                ph,
+                ts: instant
+                    .saturating_duration_since(self.start_instant)
+                    .as_micros(),
+                pid,
+                tid: 0,
+                s: if ph == "i" { Some("g") } else { None },
+                args: ChromeTraceArgs { bytes },
+            };
+            let mut events: Vec<_> = h
+                .trace_events
+                .iter()
+                .map(|&(instant, bytes)| event("Heap", "C", instant, bytes))
+                .collect();
+            if h.max_bytes > 0 {
+                events.push(event("t-gmax", "i", h.tgmax_instant, h.max_bytes));
+            }
+
+            let mut file = BufWriter::new(File::create(path)?);
+            serde_json::to_writer(
+                &mut file,
+                &ChromeTraceJson {
+                    traceEvents: events,
+                },
+            )?;
+            file.flush()
+        };
+        match write() {
+            Ok(()) if h.trace_events_dropped > 0 => out.println(format_args!(
+                "dhat: The Chrome trace has been saved to {} ({} older events were dropped)",
+                path.to_string_lossy(),
+                h.trace_events_dropped.separate_with_commas(),
+            )),
+            Ok(()) => out.println(format_args!(
+                "dhat: The Chrome trace has been saved to {}",
+                path.to_string_lossy()
+            )),
+            Err(e) => out.println(format_args!(
+                "dhat: error: Writing to {} failed: {}",
+                path.to_string_lossy(),
+                e
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "pprof")]
+impl Globals {
+    // Save the profile to `path` in pprof format.
+    fn save_pprof(&self, json: &DhatJson, path: &Path, out: &mut Output) {
+        let write = || -> std::io::Result<()> {
+            use prost::Message;
+
+            let profile = pprof::Profile::new(json, self.heap.is_some(), self.time_unit);
+            let file = BufWriter::new(File::create(path)?);
+            let mut gz = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+            gz.write_all(&profile.encode_to_vec())?;
+            gz.finish()?.flush()
+        };
+        match write() {
+            Ok(()) => out.println(format_args!(
+                "dhat: The pprof data has been saved to {}",
+                path.to_string_lossy()
+            )),
+            Err(e) => out.println(format_args!(
+                "dhat: error: Writing to {} failed: {}",
+                path.to_string_lossy(),
+                e
+            )),
+        }
+    }
+}
+
+impl HeapGlobals {
+    // `reserve_blocks` is the initial capacity of `live_blocks`.
+    fn new(reserve_blocks: usize) -> Self {
+        Self {
+            live_blocks: FxHashMap::with_capacity_and_hasher(reserve_blocks, Default::default()),
+            curr_blocks: 0,
+            curr_bytes: 0,
+            freed_blocks: 0,
+            freed_bytes: 0,
+            max_blocks: 0,
+            max_bytes: 0,
+            tgmax_instant: Instant::now(),
+            failed_blocks: 0,
+            unrecorded_blocks: FxHashSet::default(),
+            realloc_blocks: 0,
+            realloc_bytes_moved: 0,
+            realloc_in_place: 0,
+            realloc_moved: 0,
+            zst_allocations: 0,
+            preexisting_realloc_blocks: 0,
+            largest_block_bytes: 0,
+            largest_block_pp: None,
+            dealloc_backtraces: FxHashMap::default(),
+            dealloc_counts: FxHashMap::default(),
+            trace_events: VecDeque::new(),
+            trace_events_dropped: 0,
+            markers: vec![],
+            curve: vec![],
+            align_counts: BTreeMap::new(),
+            t_end_before_settle: None,
+            underflowed: false,
+        }
+    }
+}
+
+struct PpInfo {
+    // The total number of blocks and bytes allocated by this PP.
+    total_blocks: u64,
+    total_bytes: u64,
+
+    // The total number of units from `ad_hoc_event_f64` for this PP.
+    total_units_f64: f64,
+
+    heap: Option<HeapPpInfo>,
+}
+
+#[derive(Default)]
+struct HeapPpInfo {
     // The current number of blocks and bytes allocated by this PP.
     curr_blocks: usize,
     curr_bytes: usize,
 
-    // The number of blocks and bytes at the PP max, i.e. when this PP's
-    // `curr_bytes` peaks.
-    max_blocks: usize,
-    max_bytes: usize,
+    // The number of blocks and bytes at the PP max, i.e. when this PP's
+    // `curr_bytes` peaks.
+    max_blocks: usize,
+    max_bytes: usize,
+
+    // Time of the PP max. `None` until the first allocation.
+    max_instant: Option<Instant>,
+
+    // The number of blocks and bytes at the global max, i.e. when
+    // `Globals::curr_bytes` peaks.
+    at_tgmax_blocks: usize,
+    at_tgmax_bytes: usize,
+
+    // Total lifetimes of all blocks allocated by this PP. Includes blocks
+    // explicitly freed and blocks implicitly freed at termination.
+    total_lifetimes_duration: Duration,
+
+    // The number of explicitly freed blocks in each lifetime bucket (see
+    // `lifetime_bucket`), if requested with
+    // `ProfilerBuilder::lifetime_histogram`. Trailing empty buckets are
+    // omitted.
+    lifetime_histogram: Vec<u64>,
+
+    // For `ProfilerBuilder::detect_thrashing`: the number of blocks freed
+    // that left this PP with no live blocks and were in the same size class
+    // as the previous such block, and the size of the most recently freed
+    // block.
+    thrash_cycles: u64,
+    last_freed_size: usize,
+}
+
+impl HeapPpInfo {
+    // Called after a block of `size` bytes from this PP has been freed.
+    fn update_thrash_cycles(&mut self, size: usize) {
+        if self.curr_blocks == 0
+            && size != 0
+            && self.last_freed_size != 0
+            && Tag::size_class(size) == Tag::size_class(self.last_freed_size)
+        {
+            self.thrash_cycles += 1;
+        }
+        self.last_freed_size = size;
+    }
+}
+
+impl PpInfo {
+    fn new_heap() -> Self {
+        Self {
+            total_blocks: 0,
+            total_bytes: 0,
+            total_units_f64: 0.0,
+            heap: Some(HeapPpInfo::default()),
+        }
+    }
+
+    fn new_ad_hoc() -> Self {
+        Self {
+            total_blocks: 0,
+            total_bytes: 0,
+            total_units_f64: 0.0,
+            heap: None,
+        }
+    }
+
+    fn update_counts_for_alloc(&mut self, size: usize, delta: Option<Delta>, now: Instant) {
+        self.total_blocks += 1;
+        self.total_bytes += size as u64;
+
+        let h = self.heap.as_mut().unwrap();
+        if let Some(delta) = delta {
+            // realloc
+            h.curr_blocks += 0; // unchanged
+            h.curr_bytes += delta;
+        } else {
+            // alloc
+            h.curr_blocks += 1;
+            h.curr_bytes += size;
+        }
+
+        // The use of `>=` not `>` means that if there are multiple equal peaks
+        // we record the latest one, like `check_for_global_peak` does.
+        if h.curr_bytes >= h.max_bytes {
+            h.max_blocks = h.curr_blocks;
+            h.max_bytes = h.curr_bytes;
+            h.max_instant = Some(now);
+        }
+    }
+
+    // Returns true if the counts would have underflowed.
+    fn update_counts_for_dealloc(&mut self, size: usize, alloc_duration: Duration) -> bool {
+        let h = self.heap.as_mut().unwrap();
+        let underflow = h.curr_blocks < 1 || h.curr_bytes < size;
+        h.curr_blocks = h.curr_blocks.saturating_sub(1);
+        h.curr_bytes = h.curr_bytes.saturating_sub(size);
+        h.total_lifetimes_duration += alloc_duration;
+        underflow
+    }
+
+    fn update_counts_for_ad_hoc_event(&mut self, weight: usize) {
+        std::assert!(self.heap.is_none());
+        self.total_blocks += 1;
+        self.total_bytes += weight as u64;
+    }
+
+    fn update_counts_for_ad_hoc_event_f64(&mut self, weight: f64) {
+        std::assert!(self.heap.is_none());
+        self.total_blocks += 1;
+        self.total_units_f64 += weight;
+    }
+}
+
+struct ThreadTotals {
+    // The thread's name if it has one, otherwise its `ThreadId` in debug
+    // form.
+    name: String,
+
+    // The total number of blocks and bytes allocated by this thread.
+    total_blocks: u64,
+    total_bytes: u64,
+
+    // The number of bytes currently allocated by this thread, and its peak.
+    // Blocks freed by another thread are still removed from this thread's
+    // count.
+    curr_bytes: usize,
+    max_bytes: usize,
+}
+
+struct LiveBlock {
+    // The index of the PpInfo for this block.
+    pp_info_idx: usize,
+
+    // The size of the block, in bytes.
+    size: usize,
+
+    // When the block was allocated.
+    allocation_instant: Instant,
+
+    // The `THREAD_NUM` of the thread that allocated the block.
+    thread: u64,
+}
+
+// We record info about allocations and deallocations. A wrinkle: the recording
+// done may trigger additional allocations. We must ignore these because (a)
+// they're part of `dhat`'s execution, not the original program's execution,
+// and (b) they would be intercepted and trigger additional allocations, which
+// would be intercepted and trigger additional allocations, and so on, leading
+// to infinite loops.
+//
+// With this type we can run one code path if we are already ignoring
+// allocations. Otherwise, we can a second code path while ignoring
+// allocations. In practice, the first code path is unreachable except within
+// the `GlobalAlloc` methods.
+//
+// WARNING: This type must be used for any code within this crate that can
+// trigger allocations.
+struct IgnoreAllocs {
+    was_already_ignoring_allocs: bool,
+}
+
+thread_local!(static IGNORE_ALLOCS: Cell<bool> = const { Cell::new(false) });
+
+impl IgnoreAllocs {
+    fn new() -> Self {
+        Self {
+            was_already_ignoring_allocs: IGNORE_ALLOCS.with(|b| b.replace(true)),
+        }
+    }
+}
+
+/// If code panics while `IgnoreAllocs` is live, this will still reset
+/// `IGNORE_ALLOCS` so that it can be used again.
+impl Drop for IgnoreAllocs {
+    fn drop(&mut self) {
+        if !self.was_already_ignoring_allocs {
+            IGNORE_ALLOCS.with(|b| b.set(false));
+        }
+    }
+}
+
+/// A type whose lifetime dictates the start and end of profiling.
+///
+/// Profiling starts when the first value of this type is created. Profiling
+/// stops when (a) this value is dropped or [`Profiler::force_finish`] is
+/// called, or (b) a `dhat` assertion fails, whichever comes first. When that
+/// happens, profiling data may be written to file, depending on how the
+/// `Profiler` has been configured. Only one `Profiler` can be running at any
+/// point in time.
+///
+/// A new `Profiler` can be created after the previous one has been dropped,
+/// e.g. to profile several phases of a program separately. The frames that
+/// are trimmed from the top and bottom of backtraces are worked out by the
+/// first `Profiler` in the process and reused by later ones, so successive
+/// `Profiler`s created at the same place produce comparable backtraces.
+//
+// The actual profiler state is stored in `Globals`, so it can be accessed from
+// places like `Alloc::alloc` and `ad_hoc_event()` when the `Profiler`
+// instance isn't within reach.
+#[derive(Debug)]
+pub struct Profiler {
+    // Set by `force_finish`, so that dropping doesn't finish profiling a
+    // second time (or finish a later `Profiler`).
+    finished: AtomicBool,
+}
+
+impl Profiler {
+    /// Initiates allocation profiling.
+    ///
+    /// Typically the first thing in `main`. Its result should be assigned to a
+    /// variable whose lifetime ends at the end of `main`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if another `Profiler` is running.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::new_heap();
+    /// ```
+    pub fn new_heap() -> Self {
+        Self::builder().build()
+    }
+
+    /// Initiates ad hoc profiling.
+    ///
+    /// Typically the first thing in `main`. Its result should be assigned to a
+    /// variable whose lifetime ends at the end of `main`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if another `Profiler` is running.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::new_ad_hoc();
+    /// ```
+    pub fn new_ad_hoc() -> Self {
+        Self::builder().ad_hoc().build()
+    }
+
+    /// Initiates allocation profiling, saving the data to `file_name`. This
+    /// is a shorthand for `Profiler::builder().file_name(file_name).build()`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if another `Profiler` is running.
+    ///
+    /// # Examples
+    /// ```
+    /// let profiler = dhat::Profiler::new_heap_to("my-heap.json");
+    /// # std::mem::forget(profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn new_heap_to<P: AsRef<Path>>(file_name: P) -> Self {
+        Self::builder().file_name(file_name).build()
+    }
+
+    /// Initiates ad hoc profiling, saving the data to `file_name`. This is a
+    /// shorthand for `Profiler::builder().ad_hoc().file_name(file_name).build()`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if another `Profiler` is running.
+    ///
+    /// # Examples
+    /// ```
+    /// let profiler = dhat::Profiler::new_ad_hoc_to("my-ad-hoc.json");
+    /// # std::mem::forget(profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn new_ad_hoc_to<P: AsRef<Path>>(file_name: P) -> Self {
+        Self::builder().ad_hoc().file_name(file_name).build()
+    }
+
+    /// Creates a new [`ProfilerBuilder`], which defaults to heap profiling.
+    pub fn builder() -> ProfilerBuilder {
+        ProfilerBuilder {
+            ad_hoc: false,
+            testing: false,
+            soft_asserts: false,
+            fail_on_leak: false,
+            file_name: None,
+            file: None,
+            timestamp_file_name: false,
+            trim_backtraces: Some(10),
+            backtrace_budget: None,
+            merge_by_line: false,
+            structured_frames: false,
+            no_backtraces: false,
+            trim_top_matching: vec![],
+            path_components: 3,
+            full_paths: false,
+            resolve_symbols: true,
+            eprint_json: false,
+            summary_output: SummaryOutput::Stderr,
+            folded_output: None,
+            save_at_peak: None,
+            stats_file: None,
+            chrome_trace: None,
+            on_double_free: DoubleFreePolicy::Panic,
+            time_unit: TimeUnit::Micros,
+            peak_metric: PeakMetric::Bytes,
+            cmd: None,
+            redact_args: false,
+            end_settle: None,
+            record_dealloc_backtraces: false,
+            min_size: 0,
+            current_thread_only: false,
+            track_alignment: false,
+            lifetime_histogram: false,
+            detect_thrashing: None,
+            net_only: false,
+            split_by_size_class: false,
+            compact_output: false,
+            stream_output: false,
+            sync_on_write: false,
+            metadata_limit: None,
+            reserve_blocks: 0,
+            reserve_backtraces: 0,
+            record_env: vec![],
+            #[cfg(feature = "pprof")]
+            pprof_output: None,
+            #[cfg(unix)]
+            dump_on_signal: None,
+            #[cfg(unix)]
+            reset_on_fork: false,
+            sample_curve: None,
+            rotate: None,
+            on_alloc: None,
+            clock: None,
+        }
+    }
+}
+
+/// A builder for [`Profiler`], for cases beyond the basic ones provided by
+/// [`Profiler`].
+///
+/// Created with [`Profiler::builder`].
+#[derive(Debug)]
+pub struct ProfilerBuilder {
+    ad_hoc: bool,
+    testing: bool,
+    soft_asserts: bool,
+    fail_on_leak: bool,
+    file_name: Option<PathBuf>,
+    file: Option<File>,
+    timestamp_file_name: bool,
+    trim_backtraces: Option<usize>,
+    backtrace_budget: Option<Duration>,
+    merge_by_line: bool,
+    structured_frames: bool,
+    no_backtraces: bool,
+    trim_top_matching: Vec<String>,
+    path_components: usize,
+    full_paths: bool,
+    resolve_symbols: bool,
+    eprint_json: bool,
+    summary_output: SummaryOutput,
+    folded_output: Option<(PathBuf, FoldedWeight)>,
+    save_at_peak: Option<PathBuf>,
+    stats_file: Option<PathBuf>,
+    chrome_trace: Option<(PathBuf, usize)>,
+    on_double_free: DoubleFreePolicy,
+    time_unit: TimeUnit,
+    peak_metric: PeakMetric,
+    cmd: Option<String>,
+    redact_args: bool,
+    end_settle: Option<Duration>,
+    record_dealloc_backtraces: bool,
+    min_size: usize,
+    current_thread_only: bool,
+    track_alignment: bool,
+    lifetime_histogram: bool,
+    detect_thrashing: Option<u64>,
+    net_only: bool,
+    split_by_size_class: bool,
+    compact_output: bool,
+    stream_output: bool,
+    sync_on_write: bool,
+    metadata_limit: Option<usize>,
+    reserve_blocks: usize,
+    reserve_backtraces: usize,
+    record_env: Vec<String>,
+    #[cfg(feature = "pprof")]
+    pprof_output: Option<PathBuf>,
+    #[cfg(unix)]
+    dump_on_signal: Option<i32>,
+    #[cfg(unix)]
+    reset_on_fork: bool,
+    sample_curve: Option<Duration>,
+    rotate: Option<(Duration, usize)>,
+    on_alloc: Option<AllocCallback>,
+    clock: Option<Clock>,
+}
+
+// Expands the placeholders described in `ProfilerBuilder::file_name`. Only
+// touches the file system if `{n}` is present. Non-UTF-8 names are returned
+// unchanged.
+fn expand_file_name(template: &Path) -> PathBuf {
+    let template = match template.to_str() {
+        Some(template) => template,
+        None => return template.to_path_buf(),
+    };
+    let ts = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let name = template
+        .replace("{pid}", &std::process::id().to_string())
+        .replace("{ts}", &ts.to_string());
+    if !name.contains("{n}") {
+        return PathBuf::from(name);
+    }
+    let mut n = 0u64;
+    loop {
+        let candidate = PathBuf::from(name.replace("{n}", &n.to_string()));
+        if !candidate.exists() {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+impl ProfilerBuilder {
+    /// Requests ad hoc profiling.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().ad_hoc().build();
+    /// ```
+    pub fn ad_hoc(mut self) -> Self {
+        self.ad_hoc = true;
+        self
+    }
+
+    /// Requests testing mode, which allows the use of
+    /// [`dhat::assert!`](assert) and related macros, and disables saving of
+    /// profile data on [`Profiler`] drop.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().testing().build();
+    /// ```
+    pub fn testing(mut self) -> Self {
+        self.testing = true;
+        self
+    }
+
+    /// Sets whether a failed [`dhat::assert!`](assert) (or related macro)
+    /// leaves the [`Profiler`] running, so that later assertions can still
+    /// be evaluated. This is useful in a test that checks several conditions
+    /// with [`std::panic::catch_unwind`] and reports every failure.
+    ///
+    /// Normally, a failed assertion stops profiling and saves the profile,
+    /// and any later assertion, or attempt to get stats, panics (unless
+    /// [`Profiler::recover_from_assert`] is called). With soft
+    /// asserts, the first failed assertion saves the profile as it is at that
+    /// point, and later failures don't save it again. Profiling continues
+    /// regardless. A failed assertion still panics in both cases.
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .testing()
+    ///     .soft_asserts(true)
+    ///     .build();
+    /// ```
+    pub fn soft_asserts(mut self, soft: bool) -> Self {
+        self.soft_asserts = soft;
+        self
+    }
+
+    /// Sets whether dropping a testing mode [`Profiler`] (or calling
+    /// [`Profiler::force_finish`]) while any heap blocks are still allocated
+    /// saves the profile and panics, like a failed
+    /// [`dhat::assert!`](assert). The panic message names the allocation site
+    /// with the most bytes still allocated. This is more useful than
+    /// asserting that `curr_blocks` is zero at the end of a test, which shows
+    /// that something leaked, but not what.
+    ///
+    /// It has no effect outside testing mode, on ad hoc profiling, or if the
+    /// thread is already panicking.
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .testing()
+    ///     .fail_on_leak(true)
+    ///     .build();
+    /// ```
+    pub fn fail_on_leak(mut self, fail: bool) -> Self {
+        self.fail_on_leak = fail;
+        self
+    }
+
+    /// Sets the name of the file in which profiling data will be saved.
+    ///
+    /// The following placeholders within the name are expanded when the
+    /// [`Profiler`] is built.
+    /// - `{pid}`: the process ID.
+    /// - `{ts}`: the current time, in seconds since the Unix epoch.
+    /// - `{n}`: the smallest non-negative integer for which no file with the
+    ///   expanded name already exists.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .file_name("heap-{pid}-{ts}.json")
+    ///     .build();
+    /// # std::mem::forget(_profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn file_name<P: AsRef<Path>>(mut self, file_name: P) -> Self {
+        self.file_name = Some(file_name.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets an already-open file in which profiling data will be saved,
+    /// instead of a file opened by name. This is useful when the process
+    /// can't open files by path, e.g. in a sandbox where it is handed an open
+    /// file descriptor at startup. The file must be writable.
+    ///
+    /// If this is called, it takes precedence over
+    /// [`ProfilerBuilder::file_name`], which is then ignored for the main
+    /// profile. Other output files, such as those from
+    /// [`ProfilerBuilder::folded_output`], are still opened by name.
+    ///
+    /// If the file is a regular file, its contents are replaced each time the
+    /// profile is saved, e.g. with [`ProfilerBuilder::dump_on_signal`].
+    /// Otherwise, e.g. for a pipe, each save is appended. Unlike with a file
+    /// name, the profile is written in place, rather than via a temporary file
+    /// that is renamed. With [`ProfilerBuilder::reset_on_fork`], a child process
+    /// saves to the renamed file name instead, so as not to overwrite the
+    /// parent's profile.
+    ///
+    /// # Examples
+    /// ```
+    /// # let dir = std::env::temp_dir();
+    /// let file = std::fs::File::create(dir.join("heap.json")).unwrap();
+    /// let _profiler = dhat::Profiler::builder().file(file).build();
+    /// ```
+    pub fn file(mut self, file: File) -> Self {
+        self.file = Some(file);
+        self
+    }
+
+    /// Requests that the default file name include the process ID and a
+    /// timestamp, i.e. `dhat-heap-{pid}-{ts}.json` or
+    /// `dhat-ad-hoc-{pid}-{ts}.json`, so that repeated runs don't overwrite
+    /// each other's data. See [`ProfilerBuilder::file_name`] for the meaning
+    /// of the placeholders. Has no effect if [`ProfilerBuilder::file_name`] is
+    /// also used.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().timestamp_file_name().build();
+    /// # std::mem::forget(_profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn timestamp_file_name(mut self) -> Self {
+        self.timestamp_file_name = true;
+        self
+    }
+
+    /// Sets how backtrace trimming is performed.
+    ///
+    /// `dhat` can use heuristics to trim uninteresting frames from the top and
+    /// bottom of backtraces, which makes the output easier to read. It can
+    /// also limit the number of frames, which improves performance.
+    ///
+    /// The argument can be specified in several ways.
+    /// - `None`: no backtrace trimming will be performed, and there is no
+    ///   frame count limit. This makes profiling much slower and increases the
+    ///   size of saved data files.
+    /// - `Some(n)`: top and bottom trimming will be performed, and the number
+    ///   of frames will be limited by `n`. Values of `n` less than 4 will be
+    ///   clamped to 4.
+    /// - `Some(usize::MAX)`: top and bottom trimming with be performed, but
+    ///   there is no frame count limit. This makes profiling much slower and
+    ///   increases the size of saved data files.
+    ///
+    /// The default value (used if this function is not called) is `Some(10)`.
+    ///
+    /// The number of frames shown in viewed profiles may differ from the
+    /// number requested here, for two reasons.
+    /// - Inline frames do not count towards this length. In release builds it
+    ///   is common for the number of inline frames to equal or even exceed the
+    ///   number of "real" frames.
+    /// - Backtrace trimming will remove a small number of frames from heap
+    ///   profile backtraces. The number removed will likely be more in a debug
+    ///   build than in a release build.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().trim_backtraces(None).build();
+    /// ```
+    pub fn trim_backtraces(mut self, max_frames: Option<usize>) -> Self {
+        self.trim_backtraces = max_frames.map(|m| std::cmp::max(m, 4));
+        self
+    }
+
+    /// Limits how long getting a single backtrace may take. Once `budget` has
+    /// been exceeded, no more frames are gathered, and the backtrace ends with
+    /// a synthetic `[truncated: backtrace budget exceeded]` frame so it is
+    /// clear that it is incomplete. The summary says how many program points
+    /// have truncated backtraces.
+    ///
+    /// This bounds the time profiling adds to each allocation (or ad hoc
+    /// event) where getting backtraces is slow, most notably on Windows with
+    /// deep stacks, at the cost of backtrace completeness. The budget is
+    /// checked between frames, so a single slow frame can still exceed it.
+    ///
+    /// The default (used if this function is not called) is to not limit the
+    /// time taken.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .backtrace_budget(Duration::from_micros(100))
+    ///     .build();
+    /// ```
+    pub fn backtrace_budget(mut self, budget: Duration) -> Self {
+        self.backtrace_budget = Some(budget);
+        self
+    }
+
+    /// Requests that no backtraces be obtained at all. Every allocation (or
+    /// ad hoc event) is then attributed to a single program point with an
+    /// empty backtrace, so the saved profile has no per-site breakdown. The
+    /// totals and peaks, e.g. those returned by [`HeapStats::get`], are still
+    /// accurate.
+    ///
+    /// Getting backtraces is by far the most expensive part of profiling, so
+    /// this makes profiling much faster. It is well suited to heap usage
+    /// testing that only checks totals or peaks.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().no_backtraces().build();
+    /// ```
+    pub fn no_backtraces(mut self) -> Self {
+        self.no_backtraces = true;
+        self
+    }
+
+    /// Requests that frames in the saved profile be identified by their
+    /// function, file, and line alone, without their code addresses and
+    /// column numbers, and that program points whose backtraces are then
+    /// identical be merged. In optimized builds, inlining often gives a
+    /// single logical call site several code addresses and column numbers,
+    /// which otherwise splits it into several program points.
+    ///
+    /// The merging is done when the profile is saved, so it costs nothing
+    /// while profiling. It does not affect [`Profiler::iter_pps`] or the
+    /// program point counts in [`HeapStats`]. The merged max and at-t-gmax
+    /// counts are the sums of the individual ones, as in [`merge_profiles`].
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().merge_by_line(true).build();
+    /// ```
+    pub fn merge_by_line(mut self, merge_by_line: bool) -> Self {
+        self.merge_by_line = merge_by_line;
+        self
+    }
+
+    /// Requests that the frame table (`ftbl`) in the saved profile hold JSON
+    /// objects instead of formatted strings, for use by other tools. Each
+    /// object has these fields, any of which may be absent.
+    /// - `fn`: the function name, without its trailing hash. Synthetic
+    ///   frames, such as `[root]` and region labels, have only this.
+    /// - `file`, `line`, and `col`: the source location, with the file path
+    ///   trimmed as requested with
+    ///   [`path_components`](ProfilerBuilder::path_components).
+    /// - `ip`: the code address, as a hexadecimal string.
+    ///
+    /// **Warning:** DHAT's viewer and [`merge_profiles`] expect strings, and
+    /// can't read such a profile. Consider using a distinct file name, with
+    /// [`file_name`](ProfilerBuilder::file_name), to avoid confusing it with
+    /// a normal profile. Folded stacks and other outputs are unaffected.
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .structured_frames(true)
+    ///     .file_name("dhat-heap-frames.json")
+    ///     .build();
+    /// # std::mem::forget(_profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn structured_frames(mut self, structured_frames: bool) -> Self {
+        self.structured_frames = structured_frames;
+        self
+    }
+
+    /// Adds to the symbol name prefixes used to trim allocator frames from
+    /// the top of heap profiling backtraces.
+    ///
+    /// `dhat` trims frames from the standard library's allocation functions
+    /// and from [`Alloc`] itself, so that the first frame shown is the code
+    /// that allocated. If allocations go through another layer, such as a
+    /// wrapper allocator or an allocator crate's API, its frames are not
+    /// trimmed, and appear at the top of every backtrace. Frames whose
+    /// symbol names start with any of `patterns` are trimmed as well, along
+    /// with all frames above them. Symbol names are matched without their
+    /// trailing hash, e.g. `my_crate::MyAlloc::alloc` or
+    /// `<my_crate::MyAlloc as core::alloc::global::GlobalAlloc>`.
+    ///
+    /// This has no effect if trimming is disabled with
+    /// [`trim_backtraces(None)`](ProfilerBuilder::trim_backtraces), or for ad
+    /// hoc profiling.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .trim_top_matching(vec!["<my_crate::MyAlloc as ".to_string()])
+    ///     .build();
+    /// ```
+    pub fn trim_top_matching(mut self, patterns: Vec<String>) -> Self {
+        self.trim_top_matching.extend(patterns);
+        self
+    }
+
+    /// Sets how many trailing components of source file paths are kept in
+    /// backtraces.
+    ///
+    /// For example, with the default value of 3, `/aa/bb/cc/dd.rs` is shown as
+    /// `bb/cc/dd.rs`. Increasing this can help distinguish files with similar
+    /// paths, e.g. in different crates within a workspace. `usize::MAX`
+    /// disables the trimming entirely, so full paths are shown. Values less
+    /// than 1 will be clamped to 1.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().path_components(5).build();
+    /// ```
+    pub fn path_components(mut self, n: usize) -> Self {
+        self.path_components = std::cmp::max(n, 1);
+        self
+    }
+
+    /// Sets whether source file paths in backtraces are shown in full, as
+    /// recorded in the debug info, rather than trimmed as described for
+    /// [`ProfilerBuilder::path_components`]. Full paths make it easy to find
+    /// the exact source file, e.g. within a particular version of a
+    /// dependency. When enabled, this overrides any `path_components` value.
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().full_paths(true).build();
+    /// ```
+    pub fn full_paths(mut self, full: bool) -> Self {
+        self.full_paths = full;
+        self
+    }
+
+    /// Sets whether symbols are resolved when profiling data is saved.
+    ///
+    /// Symbol resolution, which determines function names, file names and
+    /// line numbers from code addresses, can be slow, especially on Windows.
+    /// If `false` is passed, resolution is skipped and each frame is saved as
+    /// a raw code address (e.g. `0x10ae8441b`), which can be resolved later
+    /// with a tool like `addr2line`. Saving is much faster, but the saved data
+    /// is much harder to read. Also, the trimming of allocation-related
+    /// frames at the top of heap profiling backtraces is skipped, because it
+    /// relies on symbol names. The saved data is marked with an `unresolved`
+    /// field so tools can tell the difference.
+    ///
+    /// The default value (used if this function is not called) is `true`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().resolve_symbols(false).build();
+    /// ```
+    pub fn resolve_symbols(mut self, resolve: bool) -> Self {
+        self.resolve_symbols = resolve;
+        self
+    }
+
+    /// Sets where the summary lines (e.g. `dhat: Total: ...`) are printed
+    /// when profiling data is saved.
+    ///
+    /// The default value (used if this function is not called) is
+    /// [`SummaryOutput::Stderr`]. [`SummaryOutput::None`] suppresses the
+    /// summary lines but still saves the profiling data.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .summary_output(dhat::SummaryOutput::Stdout)
+    ///     .build();
+    /// ```
+    pub fn summary_output(mut self, dst: SummaryOutput) -> Self {
+        self.summary_output = dst;
+        self
+    }
+
+    /// Requests that the profiling data also be saved as folded stacks, which
+    /// can be turned into a flamegraph with tools such as
+    /// `inferno-flamegraph`.
+    ///
+    /// Each line of the file holds the frames of one backtrace, outermost
+    /// first and separated by `;`, followed by a space and the weight chosen
+    /// by `weight`. The frames are the same as those shown by DHAT's viewer.
+    /// Backtraces with a zero weight are omitted.
+    ///
+    /// The default (used if this function is not called) is to not save
+    /// folded stacks.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .folded_output("dhat-heap.folded", dhat::FoldedWeight::TotalBytes)
+    ///     .build();
+    /// # std::mem::forget(_profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn folded_output<P: AsRef<Path>>(mut self, path: P, weight: FoldedWeight) -> Self {
+        self.folded_output = Some((path.as_ref().to_path_buf(), weight));
+        self
+    }
+
+    /// Requests that a second profile be saved to `path`, showing just the
+    /// blocks that were live at the global peak ("t-gmax"), e.g. to see
+    /// everything that was alive at the worst moment. It is in the same
+    /// format as the main profile, as if profiling had ended at the peak:
+    /// each allocation point's counts are its counts at the peak, and
+    /// allocation points with no live blocks at the peak are omitted.
+    /// Lifetimes are not known, and are shown as zero. It has no effect on
+    /// ad hoc profiling.
+    ///
+    /// The main profile already includes these counts, in its "At t-gmax"
+    /// columns, so this costs nothing while profiling.
+    ///
+    /// The default (used if this function is not called) is to not save a
+    /// second profile.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .save_at_peak("dhat-heap-peak.json")
+    ///     .build();
+    /// # std::mem::forget(_profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn save_at_peak<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.save_at_peak = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Requests that the summary stats also be saved to `path` when
+    /// profiling finishes, as a small JSON object that is easy for scripts,
+    /// e.g. in CI, to read and compare between runs. It has these fields.
+    /// - `mode`: `"heap"` or `"ad-hoc"`.
+    /// - `total_blocks` and `total_bytes`: as in [`HeapStats`]. For ad hoc
+    ///   profiling, the total events and units, as in [`AdHocStats`].
+    /// - `curr_blocks`, `curr_bytes`, `max_blocks`, and `max_bytes`: as in
+    ///   [`HeapStats`]. Omitted for ad hoc profiling.
+    /// - `elapsed_secs`: the time from the start of profiling to the end, in
+    ///   seconds.
+    ///
+    /// Like the main profile, it is not saved in testing mode.
+    ///
+    /// The default (used if this function is not called) is to not save the
+    /// stats.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .stats_file("dhat-stats.json")
+    ///     .build();
+    /// # std::mem::forget(_profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn stats_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.stats_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Requests that a trace of the current heap size over time be saved in
+    /// the Chrome trace event format, which can be viewed in
+    /// `chrome://tracing` or Perfetto alongside other traces.
+    ///
+    /// An event is recorded for every allocation and deallocation, and saved
+    /// as a counter event showing the current number of bytes. An instant
+    /// event marks the global peak ("t-gmax"). To bound memory use, at most
+    /// `capacity` events are kept; when there are more, older events are
+    /// dropped, and the saved trace only covers the end of the run. It has
+    /// no effect on ad hoc profiling.
+    ///
+    /// The default (used if this function is not called) is to not save a
+    /// Chrome trace.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .chrome_trace("dhat-heap.trace.json", 1_000_000)
+    ///     .build();
+    /// # std::mem::forget(_profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn chrome_trace<P: AsRef<Path>>(mut self, path: P, capacity: usize) -> Self {
+        self.chrome_trace = Some((path.as_ref().to_path_buf(), capacity));
+        self
+    }
+
+    /// Requests that the profiling data also be saved in the gzipped protobuf
+    /// format used by `pprof`, which can be viewed with `go tool pprof` and
+    /// other `pprof` tooling.
+    ///
+    /// Each backtrace becomes one sample with two values: the number of
+    /// blocks and bytes allocated (for heap profiling), or the number of
+    /// events and units recorded (for ad hoc profiling). The frames are the
+    /// same as those shown by DHAT's viewer.
+    ///
+    /// Requires the `pprof` feature of this crate. The default (used if this
+    /// function is not called) is to not save `pprof` data.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .pprof_output("dhat-heap.pb.gz")
+    ///     .build();
+    /// # std::mem::forget(_profiler); // Don't write the file in `cargo tests`
+    /// ```
+    #[cfg(feature = "pprof")]
+    pub fn pprof_output<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.pprof_output = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the unit for times (e.g. block lifetimes) in the saved profile.
+    /// Finer units are useful for programs with very short-lived allocations.
+    ///
+    /// The default value (used if this function is not called) is
+    /// [`TimeUnit::Micros`].
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .time_unit(dhat::TimeUnit::Nanos)
+    ///     .build();
+    /// ```
+    pub fn time_unit(mut self, unit: TimeUnit) -> Self {
+        self.time_unit = unit;
+        self
+    }
+
+    /// Sets which count determines the global peak (t-gmax) during heap
+    /// profiling. The counts recorded at the global peak, such as
+    /// [`HeapStats::max_bytes`] and the "At t-gmax" values in the saved
+    /// profile, are all taken at the moment of the chosen peak.
+    ///
+    /// [`PeakMetric::Blocks`] is useful when allocator pressure matters more
+    /// than memory use, e.g. when looking into fragmentation or allocator
+    /// bookkeeping costs.
+    ///
+    /// The default value (used if this function is not called) is
+    /// [`PeakMetric::Bytes`].
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .peak_metric(dhat::PeakMetric::Blocks)
+    ///     .build();
+    /// ```
+    pub fn peak_metric(mut self, metric: PeakMetric) -> Self {
+        self.peak_metric = metric;
+        self
+    }
+
+    /// Sets the command recorded in the saved profile, e.g. to avoid revealing
+    /// file paths and arguments in profiles that are shared publicly. Takes
+    /// precedence over [`ProfilerBuilder::redact_args`].
+    ///
+    /// If this function is not called, the program's command line arguments,
+    /// joined with spaces, are recorded.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .cmd("my-program --release".to_string())
+    ///     .build();
+    /// ```
+    pub fn cmd(mut self, s: String) -> Self {
+        self.cmd = Some(s);
+        self
+    }
+
+    /// Requests that the command recorded in the saved profile be just the
+    /// file name of the program, without its directory and arguments.
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().redact_args(true).build();
+    /// ```
+    pub fn redact_args(mut self, redact: bool) -> Self {
+        self.redact_args = redact;
+        self
+    }
+
+    /// Requests that, when heap profiling ends, `dhat` waits for the given
+    /// duration before taking the final counts, to let background threads
+    /// free things. The summary then shows the counts both before ("At
+    /// t-end") and after ("At t-end+settle") the wait, which helps
+    /// distinguish genuine leaks from memory that is freed shortly after
+    /// `main` returns. The saved profile has the counts from after the wait.
+    ///
+    /// The default (used if this function is not called) is to not wait.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .end_settle(std::time::Duration::from_millis(10))
+    ///     .build();
+    /// ```
+    pub fn end_settle(mut self, d: Duration) -> Self {
+        self.end_settle = Some(d);
+        self
+    }
+
+    /// Requests that backtraces be obtained for deallocations as well as
+    /// allocations during heap profiling, so that [`Profiler::alloc_dealloc_matrix`]
+    /// can show which code frees the blocks allocated by which code. This
+    /// roughly doubles the profiling overhead.
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .record_dealloc_backtraces(true)
+    ///     .build();
+    /// ```
+    pub fn record_dealloc_backtraces(mut self, record: bool) -> Self {
+        self.record_dealloc_backtraces = record;
+        self
+    }
+
+    /// Requests that heap blocks smaller than `bytes` not be recorded during
+    /// heap profiling. This is useful for hiding a flood of tiny allocations
+    /// that are irrelevant to the problem at hand.
+    ///
+    /// Unrecorded blocks are not counted at all, so they skew the totals
+    /// (such as [`HeapStats::total_blocks`] and [`HeapStats::total_bytes`]) by
+    /// design. The current counts stay consistent, because unrecorded blocks
+    /// are never added to them and so are not subtracted when freed. A block
+    /// that is reallocated to a size smaller than `bytes` is treated as
+    /// freed, and a block that was too small when allocated stays unrecorded
+    /// even if reallocated to a larger size.
+    ///
+    /// The default value (used if this function is not called) is 0, i.e.
+    /// all blocks are recorded. It has no effect on ad hoc profiling.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().min_size(16).build();
+    /// ```
+    pub fn min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// Requests that, during heap profiling, only allocations made on the
+    /// thread that builds the [`Profiler`] be recorded. Allocations on other
+    /// threads go straight to the system allocator, without being recorded.
+    /// This is useful for heap usage tests in a test binary where other
+    /// tests run in parallel on other threads.
+    ///
+    /// A recorded block that is freed on another thread is still counted as
+    /// freed, so blocks can be passed between threads without appearing to
+    /// leak. A recorded block that is reallocated on another thread is
+    /// treated as freed, and the new block is not recorded.
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .testing()
+    ///     .current_thread_only(true)
+    ///     .build();
+    /// ```
+    pub fn current_thread_only(mut self, current_thread_only: bool) -> Self {
+        self.current_thread_only = current_thread_only;
+        self
+    }
+
+    /// Requests that heap allocations be counted by their requested
+    /// alignment. When heap profiling finishes, the summary then has a line
+    /// for each alignment seen, showing the number of bytes and blocks
+    /// allocated with it, and a rough estimate of the padding the system
+    /// allocator needs to satisfy the alignment. This helps find
+    /// over-aligned allocations that waste memory, such as those of
+    /// `#[repr(align(N))]` types from dependencies, which can be surprising.
+    /// Reallocations are counted with the block's new size.
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().track_alignment(true).build();
+    /// ```
+    pub fn track_alignment(mut self, track: bool) -> Self {
+        self.track_alignment = track;
+        self
+    }
+
+    /// Requests that, during heap profiling, the lifetime of each freed heap
+    /// block be counted in a histogram for its allocation point. The
+    /// histogram has logarithmic buckets: bucket `i` counts blocks that lived
+    /// for at least 2<sup>i</sup> and less than 2<sup>i+1</sup> nanoseconds.
+    /// It is saved in the profile as the `lh` field of each allocation point
+    /// (which DHAT's viewer ignores), and is available via
+    /// [`Profiler::iter_pps`].
+    ///
+    /// The average lifetime shown by DHAT's viewer can hide whether a site
+    /// allocates many short-lived blocks plus a few long-lived ones; the
+    /// histogram distinguishes the two. Blocks still live when profiling
+    /// finishes are not counted.
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().lifetime_histogram(true).build();
+    /// ```
+    pub fn lifetime_histogram(mut self, enabled: bool) -> Self {
+        self.lifetime_histogram = enabled;
+        self
+    }
+
+    /// Requests that, during heap profiling, allocation sites that repeatedly
+    /// allocate and free blocks of similar sizes be detected, e.g. a hot loop
+    /// that allocates a buffer and frees it straight away, instead of reusing
+    /// it. Such sites are hard to spot in DHAT's viewer.
+    ///
+    /// An alloc/free cycle is counted for a site when one of its blocks is
+    /// freed, leaving it with no live blocks, and the block is in the same
+    /// size class (as used by [`ProfilerBuilder::split_by_size_class`]) as
+    /// the previous block freed by the site. If
+    /// any site has more than `threshold` cycles, the number of such sites is
+    /// printed in the summary. The cycles for each site are available via
+    /// [`Profiler::iter_pps`].
+    ///
+    /// The default (used if this function is not called) is to not detect
+    /// thrashing.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().detect_thrashing(1000).build();
+    /// ```
+    pub fn detect_thrashing(mut self, threshold: u64) -> Self {
+        self.detect_thrashing = Some(threshold);
+        self
+    }
+
+    /// Requests that, during heap profiling, the final profile only count
+    /// heap blocks that are still live when profiling finishes. The total
+    /// blocks and bytes of each allocation point, and of the whole run, are
+    /// then computed from those blocks alone, and allocation points with no
+    /// live blocks are omitted. This is useful for finding leaks, which can
+    /// otherwise be hard to spot among the many short-lived allocations of a
+    /// typical program.
+    ///
+    /// The other counts, such as the global peak, are unchanged. So are
+    /// [`HeapStats`] and any profiles obtained before the end, e.g. with
+    /// [`Profiler::write_to_vec`].
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().net_only(true).build();
+    /// ```
+    pub fn net_only(mut self, net_only: bool) -> Self {
+        self.net_only = net_only;
+        self
+    }
+
+    /// Requests that, during heap profiling, each allocation point be split
+    /// by the size class of its blocks, where size class `i` covers sizes
+    /// from 2<sup>i</sup> up to (but not including) 2<sup>i+1</sup> bytes.
+    /// Each size class appears as a synthetic `[size class: <lo>-<hi> bytes]`
+    /// frame at the top of the allocation point's backtrace. This shows, for
+    /// example, that a single call site makes both 16 byte and 4 KiB
+    /// allocations, which is useful when sizing slabs or arenas.
+    ///
+    /// A block keeps the size class it was allocated with, even if it is
+    /// later reallocated to a different size. This option increases the
+    /// number of allocation points, and therefore dhat's memory use and the
+    /// size of the saved profile.
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .split_by_size_class(true)
+    ///     .build();
+    /// ```
+    pub fn split_by_size_class(mut self, split: bool) -> Self {
+        self.split_by_size_class = split;
+        self
+    }
+
+    /// Requests that the saved profile be laid out like the files produced by
+    /// DHAT itself, with one line per allocation point and one line per
+    /// frame, and no indentation. This makes large profiles smaller and
+    /// faster for the viewer to load, while keeping them readable enough.
+    ///
+    /// The default value (used if this function is not called) is `false`,
+    /// which gives one line per field of each allocation point, and one line
+    /// per frame index.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().compact_output(true).build();
+    /// ```
+    pub fn compact_output(mut self, compact: bool) -> Self {
+        self.compact_output = compact;
+        self
+    }
+
+    /// Requests that, when profiling finishes, the saved profile be written
+    /// to file one allocation point at a time, rather than being built in
+    /// memory in its entirety first. This reduces the peak memory used when
+    /// saving large profiles. The frame table is written last, once all
+    /// allocation points have been written.
+    ///
+    /// This has no effect on snapshots, or if the profile is also needed in
+    /// memory for another output, i.e. if
+    /// [`ProfilerBuilder::folded_output`], the `pprof` feature's
+    /// `pprof_output`, or one of the testing-only `eprint_json` and
+    /// `drop_and_get_memory_output` is used.
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().stream_output(true).build();
+    /// # std::mem::forget(_profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn stream_output(mut self, stream: bool) -> Self {
+        self.stream_output = stream;
+        self
+    }
+
+    /// Sets whether the saved profile is flushed to disk before the message
+    /// saying it has been saved is printed. When enabled, the file's data
+    /// is synced and, on Unix, so is its directory, so the profile survives
+    /// even if the machine or container goes away immediately afterwards.
+    /// This can make saving large profiles slow on slow disks.
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().sync_on_write(true).build();
+    /// # std::mem::forget(_profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn sync_on_write(mut self, sync: bool) -> Self {
+        self.sync_on_write = sync;
+        self
+    }
+
+    /// Sets a limit on the memory used by the profiler's own data structures,
+    /// as estimated by [`Profiler::metadata_bytes`]. If the limit is
+    /// exceeded, profiling stops early: the data collected so far is saved,
+    /// a warning is printed, and nothing more is recorded. This is better
+    /// than a long run being killed for running out of memory with no output
+    /// at all.
+    ///
+    /// The limit is checked whenever an allocation or ad hoc event is
+    /// recorded. Stats can still be obtained after profiling has stopped
+    /// early, but they stay as they were at that point.
+    ///
+    /// The default (used if this function is not called) is no limit.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .metadata_limit(1024 * 1024 * 1024)
+    ///     .build();
+    /// ```
+    pub fn metadata_limit(mut self, bytes: usize) -> Self {
+        self.metadata_limit = Some(bytes);
+        self
+    }
+
+    /// Reserves space for `n` live blocks when heap profiling starts. Without
+    /// this, the table of live blocks is resized repeatedly as the number of
+    /// live blocks grows, which causes pauses that can distort timings when a
+    /// program quickly reaches millions of live blocks. The table can still
+    /// grow beyond `n`.
+    ///
+    /// The memory reserved counts towards [`Profiler::metadata_bytes`].
+    ///
+    /// The default (used if this function is not called) is to reserve
+    /// nothing.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .reserve_blocks(1_000_000)
+    ///     .build();
+    /// ```
+    pub fn reserve_blocks(mut self, n: usize) -> Self {
+        self.reserve_blocks = n;
+        self
+    }
+
+    /// Like [`ProfilerBuilder::reserve_blocks`], but reserves space for `n`
+    /// distinct backtraces, i.e. program points.
+    ///
+    /// The default (used if this function is not called) is to reserve
+    /// nothing.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .reserve_backtraces(10_000)
+    ///     .build();
+    /// ```
+    pub fn reserve_backtraces(mut self, n: usize) -> Self {
+        self.reserve_backtraces = n;
+        self
+    }
+
+    /// Requests that the values of the named environment variables be
+    /// included in the saved profile, as an `env` object mapping names to
+    /// values. This makes archived profiles self-describing.
+    ///
+    /// The values are captured once, when the profiler is built. Variables
+    /// that aren't set are omitted. DHAT's viewer ignores this data.
+    ///
+    /// The default value (used if this function is not called) is an empty
+    /// vector, in which case nothing is recorded.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .record_env(vec!["RUSTFLAGS".to_string(), "CARGO_PKG_VERSION".to_string()])
+    ///     .build();
+    /// ```
+    pub fn record_env(mut self, keys: Vec<String>) -> Self {
+        self.record_env = keys;
+        self
+    }
+
+    /// Sets what happens when a block is allocated at the address of a block
+    /// that `dhat` thinks is still live, which indicates a missed or duplicate
+    /// deallocation.
+    ///
+    /// With [`DoubleFreePolicy::Warn`] or [`DoubleFreePolicy::Ignore`], the
+    /// old block is treated as freed and replaced by the new one.
+    ///
+    /// The default value (used if this function is not called) is
+    /// [`DoubleFreePolicy::Panic`].
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .on_double_free(dhat::DoubleFreePolicy::Warn)
+    ///     .build();
+    /// ```
+    pub fn on_double_free(mut self, policy: DoubleFreePolicy) -> Self {
+        self.on_double_free = policy;
+        self
+    }
+
+    /// Requests that the profiling data be saved whenever the process receives
+    /// the signal `sig` (e.g. `SIGUSR1`), without stopping profiling. Unix
+    /// only.
+    ///
+    /// Signal handlers are very limited in what they can do, so the handler
+    /// installed just sets a flag. A helper thread checks the flag every 100ms
+    /// and, if it is set, saves the data to the usual file name, overwriting
+    /// any previously saved data. The helper thread stops when profiling
+    /// stops, but the signal handler remains installed.
+    ///
+    /// # Examples
+    /// ```
+    /// # #[cfg(unix)] {
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .dump_on_signal(libc::SIGUSR1)
+    ///     .build();
+    /// # }
+    /// ```
+    #[cfg(unix)]
+    pub fn dump_on_signal(mut self, sig: i32) -> Self {
+        self.dump_on_signal = Some(sig);
+        self
+    }
+
+    /// Requests that, when the process forks, the child process discard all
+    /// the profiling data inherited from the parent and start profiling
+    /// afresh, saving to a file name with the child's process ID inserted
+    /// before the extension, e.g. `dhat-heap.1234.json`. Without this, the
+    /// child's profile includes the parent's data and, if the child drops its
+    /// [`Profiler`], overwrites the parent's file. Unix only.
+    ///
+    /// The other output files, such as those from
+    /// [`ProfilerBuilder::folded_output`], are renamed in the same way. Helper
+    /// threads, such as the one started by
+    /// [`ProfilerBuilder::dump_on_signal`], don't exist in the child, so, for
+    /// example, the child's profile is not rotated even if
+    /// [`ProfilerBuilder::rotate`] was used. The
+    /// child's profile is only saved if the child drops its [`Profiler`], so
+    /// it must not exit with something like [`std::process::exit`] or
+    /// `libc::_exit` first.
+    ///
+    /// This works with `pthread_atfork` handlers, which are installed once
+    /// and remain installed for the life of the process.
+    ///
+    /// The default value (used if this function is not called) is `false`.
+    ///
+    /// # Examples
+    /// ```
+    /// # #[cfg(unix)] {
+    /// let _profiler = dhat::Profiler::builder().reset_on_fork(true).build();
+    /// # }
+    /// ```
+    #[cfg(unix)]
+    pub fn reset_on_fork(mut self, reset: bool) -> Self {
+        self.reset_on_fork = reset;
+        self
+    }
+
+    /// Requests that the current heap size be sampled every `interval`
+    /// during heap profiling, so that memory usage over time can be plotted.
+    ///
+    /// A helper thread takes the samples, and stops when profiling stops.
+    /// The samples are written to the saved profile in a `curve` array, each
+    /// one being an array of the time (relative to the start of profiling,
+    /// in the profile's time unit), the current bytes, and the current
+    /// blocks. DHAT's viewer ignores them. This has no effect for ad hoc
+    /// profiling.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .sample_curve(Duration::from_millis(10))
+    ///     .build();
+    /// ```
+    pub fn sample_curve(mut self, interval: Duration) -> Self {
+        self.sample_curve = Some(interval);
+        self
+    }
+
+    /// Requests that a snapshot of the profile be saved every `interval`, to
+    /// a numbered file next to the main one, e.g. `dhat-heap.0.json`,
+    /// `dhat-heap.1.json`, and so on. Only the `keep` most recent snapshots
+    /// are kept; older ones are deleted. This gives a rolling window of
+    /// profiles, with bounded disk usage, for long-running processes.
+    ///
+    /// A helper thread saves the snapshots, and stops when profiling stops.
+    /// Profiling continues after each snapshot, and the main profile is
+    /// still saved when profiling finishes. Values of `keep` less than 1 are
+    /// clamped to 1. Snapshots are not saved in testing mode.
+    ///
+    /// The default (used if this function is not called) is to not save any
+    /// snapshots.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .rotate(Duration::from_secs(3600), 24)
+    ///     .build();
+    /// ```
+    pub fn rotate(mut self, interval: Duration, keep: usize) -> Self {
+        self.rotate = Some((interval, std::cmp::max(keep, 1)));
+        self
+    }
+
+    /// Requests that `f` be called for every allocation and reallocation
+    /// recorded during heap profiling, e.g. to forward allocation events to
+    /// another tracing or metrics system as they happen. See [`AllocEvent`]
+    /// for what is passed to `f`.
+    ///
+    /// **Warning:** `f` is called from within the global allocator, while
+    /// `dhat`'s global lock is held, and so it slows down every allocation.
+    /// It should be fast. It must not call any `dhat` functions, which would
+    /// deadlock or panic. Any allocations it does are not recorded.
+    ///
+    /// The default (used if this function is not called) is to not call
+    /// anything.
+    ///
+    /// # Examples
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// static BIG_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+    ///
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .on_alloc(Box::new(|event| {
+    ///         if event.size >= 4096 {
+    ///             BIG_ALLOCS.fetch_add(1, Ordering::Relaxed);
+    ///         }
+    ///     }))
+    ///     .build();
+    /// ```
+    pub fn on_alloc(mut self, f: Box<dyn Fn(AllocEvent) + Send>) -> Self {
+        self.on_alloc = Some(AllocCallback(f));
+        self
+    }
+
+    /// Requests that `f` be used to get the current time, instead of
+    /// [`Instant::now`]. All the times in the saved profile, such as block
+    /// lifetimes and the times of the global peak and the end, are then
+    /// measured with it. This is useful for deterministic testing of those
+    /// times.
+    ///
+    /// `f` should never go backwards. It is called from within the global
+    /// allocator with the same restrictions as the function passed to
+    /// [`on_alloc`](ProfilerBuilder::on_alloc).
+    ///
+    /// The default (used if this function is not called) is to use
+    /// [`Instant::now`].
+    ///
+    /// [`Instant::now`]: std::time::Instant::now
+    ///
+    /// # Examples
+    /// ```
+    /// use std::time::Instant;
+    ///
+    /// let start = Instant::now();
+    /// let _profiler = dhat::Profiler::builder()
+    ///     .clock(Box::new(move || start))
+    ///     .build();
+    /// ```
+    pub fn clock(mut self, f: Box<dyn Fn() -> Instant + Send>) -> Self {
+        self.clock = Some(Clock(f));
+        self
+    }
+
+    // For testing purposes only. Useful for seeing what went wrong if a test
+    // fails on CI.
+    #[doc(hidden)]
+    pub fn eprint_json(mut self) -> Self {
+        self.eprint_json = true;
+        self
+    }
+
+    /// Creates a [`Profiler`] from the builder and initiates profiling.
+    ///
+    /// If the `DHAT_DISABLE` environment variable is set to a non-empty value
+    /// other than `0`, profiling is disabled, which takes precedence over all
+    /// of the builder's settings. A [`Profiler`] is still created, but
+    /// [`Alloc`] passes every allocation straight through to the system
+    /// allocator, ad hoc events are ignored, no helper threads are started,
+    /// and nothing is saved or printed when the [`Profiler`] is dropped. Stats
+    /// can still be got, but are all zero. This lets profiling be turned off
+    /// at launch time in a binary built with `dhat`, without recompiling.
+    ///
+    /// # Panics
+    ///
+    /// Panics if another [`Profiler`] is running. Use
+    /// [`ProfilerBuilder::try_build`] to handle that case without panicking.
+    pub fn build(self) -> Profiler {
+        match self.try_build() {
+            Ok(profiler) => profiler,
+            Err(_) => panic!("dhat: creating a profiler while a profiler is already running"),
+        }
+    }
+
+    /// Creates a [`Profiler`] from the builder and initiates profiling, or
+    /// returns an error if that isn't possible. This is useful for library
+    /// code and test harnesses that may run while another [`Profiler`]
+    /// exists. Like [`ProfilerBuilder::build`], it respects `DHAT_DISABLE`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if another [`Profiler`] is running, including one
+    /// that has had a `dhat` assertion fail.
+    ///
+    /// # Examples
+    /// ```
+    /// let profiler = dhat::Profiler::builder().try_build();
+    /// if let Err(e) = &profiler {
+    ///     eprintln!("not profiling: {}", e);
+    /// }
+    /// # std::mem::forget(profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn try_build(mut self) -> Result<Profiler, ProfilerError> {
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+
+        // Errors from starting the helpers are printed once `TRI_GLOBALS` has
+        // been unlocked.
+        let mut out = Output::new(SummaryOutput::None);
+        let res = {
+            let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+            match phase {
+                Phase::Ready => {
+                    let file_name = if let Some(file_name) = &self.file_name {
+                        file_name.clone()
+                    } else {
+                        let mode = if !self.ad_hoc { "heap" } else { "ad-hoc" };
+                        if self.timestamp_file_name {
+                            PathBuf::from(format!("dhat-{}-{{pid}}-{{ts}}.json", mode))
+                        } else {
+                            PathBuf::from(format!("dhat-{}.json", mode))
+                        }
+                    };
+                    let file_name = expand_file_name(&file_name);
+                    let h = if !self.ad_hoc {
+                        Some(HeapGlobals::new(self.reserve_blocks))
+                    } else {
+                        None
+                    };
+                    let mut g = Globals::new(&self, file_name, h);
+                    g.file = self.file.take();
+                    g.on_alloc = self.on_alloc.take();
+                    if let Some(clock) = self.clock.take() {
+                        // The start time must come from the clock as well.
+                        g.start_instant = clock.0();
+                        if let Some(h) = &mut g.heap {
+                            h.tgmax_instant = g.start_instant;
+                        }
+                        g.clock = Some(clock);
+                    }
+                    let disabled = disabled_by_env();
+                    if disabled {
+                        g.stopped_early = true;
+                    } else {
+                        #[cfg(unix)]
+                        if let Some(sig) = self.dump_on_signal {
+                            g.stop_dump_thread = Some(start_dump_on_signal(sig, &mut out));
+                        }
+                        #[cfg(unix)]
+                        if self.reset_on_fork {
+                            install_fork_handlers(&mut out);
+                        }
+                        if let (Some(interval), false) = (self.sample_curve, self.ad_hoc) {
+                            g.stop_sampler_thread = Some(start_curve_sampler(interval, &mut out));
+                        }
+                        if let (Some((interval, keep)), false) = (self.rotate, self.testing) {
+                            g.stop_rotate_thread = Some(start_rotate(interval, keep, &mut out));
+                        }
+                    }
+                    *phase = Phase::Running(g);
+                    let profiled_thread = if self.current_thread_only {
+                        THREAD_NUM.with(|&n| n)
+                    } else {
+                        0
+                    };
+                    PROFILED_THREAD.store(profiled_thread, Ordering::Relaxed);
+                    HEAP_PROFILING.store(!self.ad_hoc && !disabled, Ordering::Release);
+                    Ok(())
+                }
+                Phase::Running(_) => Err(ProfilerError::AlreadyRunning),
+                Phase::PostAssert(_) => Err(ProfilerError::PostAssert),
+            }
+        };
+        out.print();
+        res.map(|()| Profiler {
+            finished: AtomicBool::new(false),
+        })
+    }
+}
+
+// Is profiling disabled by the `DHAT_DISABLE` environment variable?
+fn disabled_by_env() -> bool {
+    std::env::var_os("DHAT_DISABLE").is_some_and(|v| !v.is_empty() && v != "0")
+}
+
+/// The reasons why [`ProfilerBuilder::try_build`] can fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProfilerError {
+    /// Another [`Profiler`] is running.
+    AlreadyRunning,
+
+    /// Another [`Profiler`] exists, and a `dhat` assertion has failed while
+    /// it was running.
+    PostAssert,
+}
+
+impl std::fmt::Display for ProfilerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProfilerError::AlreadyRunning => write!(f, "a profiler is already running"),
+            ProfilerError::PostAssert => {
+                write!(f, "a profiler is already running and has asserted")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProfilerError {}
+
+// Set by the signal handler installed by `ProfilerBuilder::dump_on_signal`.
+#[cfg(unix)]
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn dump_signal_handler(_sig: libc::c_int) {
+    // Signal handlers can't allocate or lock, so we just set a flag.
+    DUMP_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+// Install the signal handler and start the helper thread that saves the data
+// when the signal arrives. Returns the flag that tells the thread to stop.
+// Errors are written to `out`, because `TRI_GLOBALS` is locked.
+#[cfg(unix)]
+fn start_dump_on_signal(sig: i32, out: &mut Output) -> Arc<AtomicBool> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = dump_signal_handler as extern "C" fn(libc::c_int) as usize;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(sig, &action, std::ptr::null_mut()) != 0 {
+            out.warn(format_args!(
+                "dhat: error: Installing a handler for signal {} failed",
+                sig
+            ));
+        }
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop2 = Arc::clone(&stop);
+    // Unnamed for the same reason as the curve sampler thread.
+    let res = std::thread::Builder::new().spawn(move || {
+        // Nothing done on this thread should be profiled.
+        let _ignore_allocs = IgnoreAllocs::new();
+        loop {
+            std::thread::sleep(Duration::from_millis(100));
+            if stop2.load(Ordering::Relaxed) {
+                break;
+            }
+            if DUMP_REQUESTED.swap(false, Ordering::Relaxed) {
+                let out = {
+                    let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+                    // Check `stop2` again, because profiling may have
+                    // stopped while we were waiting for the lock.
+                    if stop2.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    match phase {
+                        Phase::Running(g) if !g.stopped_early => Some(g.snapshot()),
+                        _ => None,
+                    }
+                };
+                if let Some(out) = out {
+                    out.print();
+                }
+            }
+        }
+    });
+    if let Err(e) = res {
+        out.warn(format_args!(
+            "dhat: error: Starting the signal dump thread failed: {}",
+            e
+        ));
+    }
+    stop
+}
+
+// The state held across a `fork` by the handlers installed for
+// `ProfilerBuilder::reset_on_fork`: the fresh state for the child, if it is
+// to be reset, the lock on `TRI_GLOBALS`, so no other thread can leave it
+// locked (or `Globals` half-updated) in the child, and an `IgnoreAllocs`, so
+// the forking thread doesn't try to take that lock again if it allocates.
+// They are dropped in that order, so in the parent the unused fresh state is
+// freed while allocations are still ignored.
+#[cfg(unix)]
+#[allow(clippy::type_complexity)]
+static FORK_STATE: Mutex<
+    Option<(
+        Option<Box<ForkReset>>,
+        mintex::MutexGuard<'static, Phase<Globals>>,
+        IgnoreAllocs,
+    )>,
+> = Mutex::new(None);
+
+// The state that `Globals::reset_for_fork` swaps into the child's `Globals`.
+// The child's fork handler must not allocate or free memory, so this is
+// built beforehand, by `fork_prepare`, and afterwards it holds the parent's
+// data.
+#[cfg(unix)]
+struct ForkReset {
+    file_name: ForkFileName,
+    folded_output: Option<ForkFileName>,
+    chrome_trace: Option<ForkFileName>,
+    save_at_peak: Option<ForkFileName>,
+    stats_file: Option<ForkFileName>,
+    #[cfg(feature = "pprof")]
+    pprof_output: Option<ForkFileName>,
+    file: Option<File>,
+    start_instant: Instant,
+    pp_infos: Vec<PpInfo>,
+    frames: FrameTable,
+    backtraces: FxHashMap<Backtrace, usize>,
+    thread_totals: FxHashMap<u64, ThreadTotals>,
+    heap: Option<HeapGlobals>,
+    stop_dump_thread: Option<Arc<AtomicBool>>,
+    stop_sampler_thread: Option<Arc<AtomicBool>>,
+    stop_rotate_thread: Option<Arc<AtomicBool>>,
+    // Afterwards, the data discarded by any earlier reset.
+    prev: Option<Box<ForkReset>>,
+}
+
+#[cfg(unix)]
+impl ForkReset {
+    fn new(g: &Globals) -> Self {
+        ForkReset {
+            file_name: ForkFileName::new(&g.file_name),
+            folded_output: g
+                .folded_output
+                .as_ref()
+                .map(|(path, _)| ForkFileName::new(path)),
+            chrome_trace: g
+                .chrome_trace
+                .as_ref()
+                .map(|(path, _)| ForkFileName::new(path)),
+            save_at_peak: g.save_at_peak.as_deref().map(ForkFileName::new),
+            stats_file: g.stats_file.as_deref().map(ForkFileName::new),
+            #[cfg(feature = "pprof")]
+            pprof_output: g.pprof_output.as_deref().map(ForkFileName::new),
+            file: None,
+            start_instant: g.now(),
+            pp_infos: Vec::default(),
+            frames: FrameTable::default(),
+            backtraces: FxHashMap::default(),
+            thread_totals: FxHashMap::default(),
+            heap: g.heap.as_ref().map(|_| HeapGlobals::new(0)),
+            stop_dump_thread: None,
+            stop_sampler_thread: None,
+            stop_rotate_thread: None,
+            prev: None,
+        }
+    }
+}
+
+// A file name with room reserved for a process ID, so that the child's fork
+// handler can number it like `numbered_file_name` does without allocating.
+#[cfg(unix)]
+struct ForkFileName {
+    // The path up to and including the `.` before the process ID, and
+    // afterwards the old path.
+    path: std::ffi::OsString,
+    // The extension, with its leading `.`, or empty if there is none.
+    ext: std::ffi::OsString,
+}
+
+#[cfg(unix)]
+impl ForkFileName {
+    fn new(path: &Path) -> Self {
+        let mut prefix = path
+            .with_file_name(path.file_stem().unwrap_or_default())
+            .into_os_string();
+        prefix.push(".");
+        let mut ext = std::ffi::OsString::new();
+        if let Some(e) = path.extension() {
+            ext.push(".");
+            ext.push(e);
+        }
+        // A `u32` has at most 10 digits.
+        prefix.reserve(10 + ext.len());
+        ForkFileName { path: prefix, ext }
+    }
+
+    // Replace `path` with this file name, numbered with `pid`.
+    fn swap(&mut self, path: &mut PathBuf, pid: u32) {
+        let mut digits = [0u8; 10];
+        let mut rest = &mut digits[..];
+        let _ = write!(rest, "{}", pid);
+        let unused = rest.len();
+        let len = digits.len() - unused;
+        self.path.push(std::str::from_utf8(&digits[..len]).unwrap());
+        self.path.push(&self.ext);
+        let name = PathBuf::from(std::mem::take(&mut self.path));
+        self.path = std::mem::replace(path, name).into_os_string();
+    }
+}
+
+#[cfg(unix)]
+static FORK_HANDLERS: std::sync::Once = std::sync::Once::new();
+
+#[cfg(unix)]
+fn install_fork_handlers(out: &mut Output) {
+    FORK_HANDLERS.call_once(|| unsafe {
+        if libc::pthread_atfork(Some(fork_prepare), Some(fork_parent), Some(fork_child)) != 0 {
+            out.warn(format_args!(
+                "dhat: error: Installing the fork handlers failed"
+            ));
+        }
+    });
+}
+
+#[cfg(unix)]
+unsafe extern "C" fn fork_prepare() {
+    let ignore_allocs = IgnoreAllocs::new();
+    let guard = TRI_GLOBALS.lock();
+    let reset = match &*guard {
+        Phase::Running(g) if g.reset_on_fork && !g.stopped_early => {
+            Some(Box::new(ForkReset::new(g)))
+        }
+        _ => None,
+    };
+    *FORK_STATE.lock() = Some((reset, guard, ignore_allocs));
+}
+
+#[cfg(unix)]
+unsafe extern "C" fn fork_parent() {
+    FORK_STATE.lock().take();
+}
+
+// The child has only the forking thread, and `fork_prepare` ensured that no
+// other thread was using `TRI_GLOBALS` at the time. Nothing is allocated or
+// freed here, because a child of a multithreaded process may only call
+// async-signal-safe functions.
+#[cfg(unix)]
+unsafe extern "C" fn fork_child() {
+    if let Some((reset, mut guard, ignore_allocs)) = FORK_STATE.lock().take() {
+        if let (Some(reset), Phase::Running(g)) = (reset, &mut *guard) {
+            g.reset_for_fork(reset, std::process::id());
+        }
+        drop(guard);
+        drop(ignore_allocs);
+    }
+}
+
+// Insert `n`, e.g. a PID, before the extension of `path`, e.g.
+// `dhat-heap.json` becomes `dhat-heap.1234.json`.
+fn numbered_file_name(path: &Path, n: impl std::fmt::Display) -> PathBuf {
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{}", n));
+    if let Some(ext) = path.extension() {
+        file_name.push(".");
+        file_name.push(ext);
+    }
+    path.with_file_name(file_name)
+}
+
+// Start the helper thread that samples the current heap size for
+// `ProfilerBuilder::sample_curve`. Returns the flag that tells the thread to
+// stop. Errors are written to `out`, because `TRI_GLOBALS` is locked.
+fn start_curve_sampler(interval: Duration, out: &mut Output) -> Arc<AtomicBool> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop2 = Arc::clone(&stop);
+    // The thread is unnamed because the standard library copies a thread's
+    // name on the new thread before running its closure, and that allocation
+    // would be profiled.
+    let res = std::thread::Builder::new().spawn(move || {
+        // Nothing done on this thread should be profiled.
+        let _ignore_allocs = IgnoreAllocs::new();
+        loop {
+            std::thread::sleep(interval);
+            let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+            // Check `stop2` after getting the lock, because profiling
+            // may have stopped while we were waiting for it.
+            if stop2.load(Ordering::Relaxed) {
+                break;
+            }
+            if let Phase::Running(Globals {
+                heap: Some(h),
+                stopped_early: false,
+                clock,
+                ..
+            }) = phase
+            {
+                h.curve
+                    .push((Clock::now(clock), h.curr_bytes, h.curr_blocks));
+            }
+        }
+    });
+    if let Err(e) = res {
+        out.warn(format_args!(
+            "dhat: error: Starting the curve sampler thread failed: {}",
+            e
+        ));
+    }
+    stop
+}
+
+// Start the helper thread that saves snapshots for `ProfilerBuilder::rotate`.
+// Returns the flag that tells the thread to stop. Errors are written to `out`,
+// because `TRI_GLOBALS` is locked.
+fn start_rotate(interval: Duration, keep: usize, out: &mut Output) -> Arc<AtomicBool> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop2 = Arc::clone(&stop);
+    // Unnamed for the same reason as the curve sampler thread.
+    let res = std::thread::Builder::new().spawn(move || {
+        // Nothing done on this thread should be profiled.
+        let _ignore_allocs = IgnoreAllocs::new();
+        let mut saved: VecDeque<PathBuf> = VecDeque::new();
+        for seq in 0u64.. {
+            std::thread::sleep(interval);
+            let out = {
+                let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+                // Check `stop2` after getting the lock, because profiling
+                // may have stopped while we were waiting for it.
+                if stop2.load(Ordering::Relaxed) {
+                    break;
+                }
+                match phase {
+                    Phase::Running(g) if !g.stopped_early => {
+                        let mut out = Output::new(g.summary_output);
+                        let path = numbered_file_name(&g.file_name, seq);
+                        let json = g.snapshot_json();
+                        g.save(&json, &path, &mut out);
+                        saved.push_back(path);
+                        if saved.len() > keep {
+                            // It may have been removed by someone else, which
+                            // is fine.
+                            let _ = std::fs::remove_file(saved.pop_front().unwrap());
+                        }
+                        Some(out)
+                    }
+                    _ => None,
+                }
+            };
+            if let Some(out) = out {
+                out.print();
+            }
+        }
+    });
+    if let Err(e) = res {
+        out.warn(format_args!(
+            "dhat: error: Starting the rotation thread failed: {}",
+            e
+        ));
+    }
+    stop
+}
+
+/// Where the summary lines are printed when profiling data is saved. Used
+/// with [`ProfilerBuilder::summary_output`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SummaryOutput {
+    /// Print the summary lines to `stderr`. The default.
+    Stderr,
+
+    /// Print the summary lines to `stdout`.
+    Stdout,
+
+    /// Don't print the summary lines.
+    None,
+}
+
+// Text produced while `TRI_GLOBALS` is locked, to be printed once it has been
+// unlocked. Printing while it is locked can deadlock, because another thread
+// may hold the stdio lock and then allocate, which requires `TRI_GLOBALS`.
+#[must_use]
+struct Output {
+    summary_output: SummaryOutput,
+    summary: String,
+    warnings: String,
+    json: Option<String>,
+}
+
+impl Output {
+    fn new(summary_output: SummaryOutput) -> Self {
+        Output {
+            summary_output,
+            summary: String::new(),
+            warnings: String::new(),
+            json: None,
+        }
+    }
+
+    // All summary lines go through here. Must be called while ignoring
+    // allocations, because formatting can allocate.
+    fn println(&mut self, args: std::fmt::Arguments) {
+        if self.summary_output != SummaryOutput::None {
+            self.summary.push_str(&format!("{}\n", args));
+        }
+    }
+
+    // Warnings are always printed to `stderr`, regardless of
+    // `summary_output`. Must be called while ignoring allocations.
+    fn warn(&mut self, args: std::fmt::Arguments) {
+        self.warnings.push_str(&format!("{}\n", args));
+    }
+
+    // Print everything, with a single pre-formatted print per stream. (The
+    // print macros are used rather than writing to the streams directly so
+    // that the test harness can capture the output.) Must be called while
+    // ignoring allocations, and with `TRI_GLOBALS` unlocked.
+    fn print(self) {
+        match self.summary_output {
+            SummaryOutput::Stderr => eprint!("{}", self.summary),
+            SummaryOutput::Stdout => print!("{}", self.summary),
+            SummaryOutput::None => {}
+        }
+        if !self.warnings.is_empty() {
+            eprint!("{}", self.warnings);
+        }
+        if let Some(json) = self.json {
+            eprint!("{}", json);
+        }
+    }
+}
+
+/// The unit for times in the saved profile. Used with
+/// [`ProfilerBuilder::time_unit`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeUnit {
+    /// Nanoseconds.
+    Nanos,
+
+    /// Microseconds. The default.
+    Micros,
+
+    /// Milliseconds.
+    Millis,
+}
+
+impl TimeUnit {
+    // The number of nanoseconds in one unit.
+    fn nanos(self) -> u128 {
+        match self {
+            TimeUnit::Nanos => 1,
+            TimeUnit::Micros => 1_000,
+            TimeUnit::Millis => 1_000_000,
+        }
+    }
+
+    fn convert(self, d: Duration) -> u128 {
+        d.as_nanos() / self.nanos()
+    }
+
+    // The `tu` field in the JSON.
+    fn name(self) -> &'static str {
+        match self {
+            TimeUnit::Nanos => "ns",
+            TimeUnit::Micros => "µs",
+            TimeUnit::Millis => "ms",
+        }
+    }
+
+    // The `Mtu` field in the JSON, which is the name for one million units.
+    fn major_name(self) -> &'static str {
+        match self {
+            TimeUnit::Nanos => "ms",
+            TimeUnit::Micros => "s",
+            TimeUnit::Millis => "ks",
+        }
+    }
+
+    // The `tuth` field in the JSON: blocks with shorter average lifetimes
+    // than this are considered short-lived. This is 10µs, except that it
+    // can't be expressed in milliseconds, so the smallest non-zero value is
+    // used instead.
+    fn short_lived_threshold(self) -> usize {
+        match self {
+            TimeUnit::Nanos => 10_000,
+            TimeUnit::Micros => 10,
+            TimeUnit::Millis => 1,
+        }
+    }
+}
+
+/// What to do when a block is allocated at the address of a block that is
+/// still live. Used with [`ProfilerBuilder::on_double_free`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DoubleFreePolicy {
+    /// Panic. The default.
+    Panic,
+
+    /// Print the address and the backtraces of both allocations to `stderr`,
+    /// and continue.
+    Warn,
+
+    /// Continue silently.
+    Ignore,
+}
+
+/// The weight given to each backtrace in folded stacks output. Used with
+/// [`ProfilerBuilder::folded_output`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FoldedWeight {
+    /// The total number of bytes allocated over the entire run. For ad hoc
+    /// profiling, the total number of units.
+    TotalBytes,
+
+    /// The number of bytes allocated when this backtrace's live bytes peaked.
+    /// Not meaningful for ad hoc profiling, where it is always zero.
+    MaxBytes,
+}
+
+/// The count that determines the global peak (t-gmax) during heap profiling.
+/// Used with [`ProfilerBuilder::peak_metric`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeakMetric {
+    /// The peak is when the number of live bytes is highest. The default.
+    Bytes,
+
+    /// The peak is when the number of live blocks is highest.
+    Blocks,
+}
+
+// Get a backtrace according to `$g`'s settings. A macro rather than a `Global`
+// method to avoid putting an extra frame into backtraces.
+macro_rules! new_backtrace {
+    ($g:expr) => {{
+        if $g.no_backtraces {
+            Backtrace::new(Box::default(), None)
+        } else {
+            let frames_to_trim = FRAMES_TO_TRIM.get_or_init(|| {
+                // This is the first backtrace from profiling in this process.
+                // Work out what we will be trimming from the top and bottom of
+                // all backtraces. `None` here because we don't want any frame
+                // trimming for this backtrace.
+                let bt = new_backtrace_inner(
+                    None,
+                    None,
+                    &FxHashMap::default(),
+                    &mut FrameTable::default(),
+                );
+                let start_bt = $g.start_bt.as_ref();
+                bt.get_frames_to_trim(start_bt.expect("dhat: missing start backtrace"))
+            });
+
+            // Get the backtrace.
+            new_backtrace_inner(
+                $g.trim_backtraces,
+                $g.backtrace_budget,
+                frames_to_trim,
+                &mut $g.frames,
+            )
+        }
+    }};
+}
+
+// Get a backtrace, possibly trimmed.
+//
+// Note: it's crucial that there only be a single call to `backtrace::trace()`
+// that is used everywhere, so that all traces will have the same backtrace
+// function IPs in their top frames. (With multiple call sites we would have
+// multiple closures, giving multiple instances of `backtrace::trace<F>`, and
+// monomorphisation would put them into different functions in the binary.)
+// Without this, top frame trimming wouldn't work. That's why this is a
+// function (with `inline(never)` just to be safe) rather than a macro like
+// `new_backtrace`. The frame for this function will be removed by top frame
+// trimming.
+//
+// Each frame not already in `frames` is added to it. If getting the backtrace
+// takes longer than `budget`, it is truncated.
+#[inline(never)]
+fn new_backtrace_inner(
+    trim_backtraces: Option<usize>,
+    budget: Option<Duration>,
+    frames_to_trim: &FxHashMap<usize, TB>,
+    frames: &mut FrameTable,
+) -> Backtrace {
+    // Get the backtrace, trimming if necessary at the top and bottom and for
+    // length.
+    let mut ips = Vec::new();
+    let deadline = budget.map(|budget| Instant::now() + budget);
+    let mut truncated = false;
+    backtrace::trace(|frame| {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            truncated = true;
+            return false; // stop
+        }
+
+        let ip = frame.ip() as usize;
+        if trim_backtraces.is_some() {
+            match frames_to_trim.get(&ip) {
+                Some(TB::Top) => return true,     // ignore frame and continue
+                Some(TB::Bottom) => return false, // ignore frame and stop
+                _ => {}                           // use this frame
+            }
+        }
+
+        frames.entry(ip).or_insert_with(|| frame.clone().into());
+        ips.push(ip);
+
+        if let Some(max_frames) = trim_backtraces {
+            ips.len() < max_frames // stop if we have enough frames
+        } else {
+            true // continue
+        }
+    });
+    let bt = Backtrace::new(ips.into(), current_region());
+    if truncated {
+        bt.truncated()
+    } else {
+        bt
+    }
+}
+
+/// A global allocator that tracks allocations and deallocations on behalf of
+/// the [`Profiler`] type.
+///
+/// It must be set as the global allocator (via `#[global_allocator]`) when
+/// doing heap profiling.
+#[derive(Debug)]
+pub struct Alloc;
+
+// The structure shared by the `GlobalAlloc` methods, so that they can't
+// diverge. If allocations aren't being recorded (because they are being
+// ignored, heap profiling isn't running, or `$recorded` is false), `$sys`, the
+// system allocator operation, is just evaluated. Otherwise, it is evaluated
+// with `TRI_GLOBALS` locked, and then, if heap profiling is running, `$record`
+// is evaluated with `$res` bound to the result of `$sys` and `$g` bound to the
+// globals. `$record` can drop `$guard` to unlock `TRI_GLOBALS`, e.g. before
+// printing output. The result of `$sys` is the result of the whole thing,
+// unless `$record` returns early.
+//
+// A macro rather than a function to avoid putting extra frames into
+// backtraces.
+macro_rules! with_recording {
+    ($recorded:expr, $sys:expr, |$guard:ident, $g:ident, $res:ident| $record:block) => {{
+        let ignore_allocs = IgnoreAllocs::new();
+        if ignore_allocs.was_already_ignoring_allocs
+            || !HEAP_PROFILING.load(Ordering::Acquire)
+            || !$recorded
+        {
+            $sys
+        } else {
+            let mut $guard = TRI_GLOBALS.lock();
+            let $res = $sys;
+            if let Phase::Running($g @ Globals { heap: Some(_), .. }) = &mut *$guard {
+                $record
+            }
+            $res
+        }
+    }};
+}
+
+// The body of `alloc` and `alloc_zeroed`, which differ only in the system
+// allocator operation `$sys`.
+macro_rules! alloc_and_record {
+    ($layout:expr, $sys:expr) => {{
+        let layout: Layout = $layout;
+        with_recording!(is_profiled_thread(), $sys, |guard, g, ptr| {
+            let h = g.heap.as_mut().unwrap();
+            if layout.size() == 0 {
+                // Not recorded as a live block, because its address may not
+                // be unique. A null result isn't a failure in this case.
+                h.zst_allocations += 1;
+                return ptr;
+            }
+            if ptr.is_null() {
+                h.failed_blocks += 1;
+                return ptr;
+            }
+            if g.stopped_early {
+                return ptr;
+            }
+
+            let size = layout.size();
+            if PAUSED.load(Ordering::Relaxed) || size < g.min_size {
+                h.unrecorded_blocks.insert(ptr as usize);
+                return ptr;
+            }
+
+            let mut bt = new_backtrace!(g);
+            if g.split_by_size_class {
+                bt = bt.with_tag(Tag::size_class(size));
+            }
+            let pp_info_idx = g.get_pp_info(bt, PpInfo::new_heap);
+
+            let now = g.now();
+            let warning = g.record_block(ptr, pp_info_idx, size, now);
+            g.update_counts_for_alloc(pp_info_idx, size, layout.align(), None, now);
+
+            let limit = g.check_metadata_limit();
+            if warning.is_some() || limit.is_some() {
+                drop(guard);
+                warning.into_iter().chain(limit).for_each(Output::print);
+            }
+        })
+    }};
+}
+
+unsafe impl GlobalAlloc for Alloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        alloc_and_record!(layout, System.alloc(layout))
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        alloc_and_record!(layout, System.alloc_zeroed(layout))
+    }
+
+    unsafe fn realloc(&self, old_ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        with_recording!(
+            true,
+            System.realloc(old_ptr, layout, new_size),
+            |guard, g, new_ptr| {
+                if new_ptr.is_null() && new_size != 0 {
+                    // The old block is untouched, so only the failure is
+                    // recorded.
+                    g.heap.as_mut().unwrap().failed_blocks += 1;
+                    return new_ptr;
+                }
+                if g.stopped_early {
+                    return new_ptr;
+                }
+
+                let old_size = layout.size();
+
+                // A block allocated while paused or too small stays
+                // unrecorded.
+                let h = g.heap.as_mut().unwrap();
+                if h.unrecorded_blocks.remove(&(old_ptr as usize)) {
+                    if !new_ptr.is_null() {
+                        h.unrecorded_blocks.insert(new_ptr as usize);
+                    }
+                    return new_ptr;
+                }
+
+                // While paused, or if the new block is too small, a recorded
+                // block is treated as freed, and the new block is unrecorded.
+                // This includes a reallocation to size zero, which the
+                // `GlobalAlloc` contract disallows, but which some allocators
+                // treat as a deallocation, possibly returning null (which
+                // doesn't indicate failure in this case). On threads whose
+                // allocations aren't recorded, a recorded block is likewise
+                // treated as freed, but the new block is not tracked at all,
+                // like other blocks allocated on those threads.
+                let other_thread = !is_profiled_thread();
+                if other_thread
+                    || PAUSED.load(Ordering::Relaxed)
+                    || new_size == 0
+                    || new_size < g.min_size
+                {
+                    if !new_ptr.is_null() && !other_thread {
+                        h.unrecorded_blocks.insert(new_ptr as usize);
+                    }
+                    if let Some(LiveBlock {
+                        pp_info_idx,
+                        allocation_instant,
+                        thread,
+                        ..
+                    }) = h.live_blocks.remove(&(old_ptr as usize))
+                    {
+                        g.check_for_global_peak();
+                        let alloc_duration = g.now().saturating_duration_since(allocation_instant);
+                        g.update_counts_for_dealloc(pp_info_idx, old_size, alloc_duration, thread);
+                    }
+                    return new_ptr;
+                }
+
+                let delta = Delta::new(old_size, new_size);
+
+                if delta.shrinking {
+                    // Total bytes is coming down from a possible peak.
+                    g.check_for_global_peak();
+                }
+
+                // Remove the record of the existing live block and get the
+                // `PpInfo`. If it's not in the live block table, it must
+                // have been allocated before `TRI_GLOBALS` was set up, and
+                // we treat it like an `alloc`.
+                let h = g.heap.as_mut().unwrap();
+                let live_block = h.live_blocks.remove(&(old_ptr as usize));
+                let (pp_info_idx, delta) = if let Some(live_block) = live_block {
+                    h.realloc_blocks += 1;
+                    h.realloc_bytes_moved += new_size as u64;
+                    if new_ptr == old_ptr {
+                        h.realloc_in_place += 1;
+                    } else {
+                        h.realloc_moved += 1;
+                    }
+                    g.update_thread_for_realloc(live_block.thread, old_size);
+                    (live_block.pp_info_idx, Some(delta))
+                } else {
+                    h.preexisting_realloc_blocks += 1;
+                    let mut bt = new_backtrace!(g);
+                    if g.split_by_size_class {
+                        bt = bt.with_tag(Tag::size_class(new_size));
+                    }
+                    let pp_info_idx = g.get_pp_info(bt, PpInfo::new_heap);
+                    (pp_info_idx, None)
+                };
+
+                let now = g.now();
+                let warning = g.record_block(new_ptr, pp_info_idx, new_size, now);
+                g.update_counts_for_alloc(pp_info_idx, new_size, layout.align(), delta, now);
+
+                let limit = g.check_metadata_limit();
+                if warning.is_some() || limit.is_some() {
+                    drop(guard);
+                    warning.into_iter().chain(limit).for_each(Output::print);
+                }
+            }
+        )
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        with_recording!(true, System.dealloc(ptr, layout), |_guard, g, _res| {
+            if g.stopped_early {
+                return;
+            }
+
+            let size = layout.size();
+
+            // Remove the record of the live block and get the `PpInfo`. If
+            // it's not in the live block table, it must have been allocated
+            // before `TRI_GLOBALS` was set up, while paused, or below the
+            // minimum size, and we just ignore it. Note that this is done
+            // even while paused.
+            let h = g.heap.as_mut().unwrap();
+            if h.unrecorded_blocks.remove(&(ptr as usize)) {
+                return;
+            }
+            if let Some(LiveBlock {
+                pp_info_idx,
+                allocation_instant,
+                thread,
+                ..
+            }) = h.live_blocks.remove(&(ptr as usize))
+            {
+                // Total bytes is coming down from a possible peak.
+                g.check_for_global_peak();
+
+                let alloc_duration = g.now().saturating_duration_since(allocation_instant);
+                g.update_counts_for_dealloc(pp_info_idx, size, alloc_duration, thread);
+
+                if g.record_dealloc_backtraces {
+                    let bt = new_backtrace!(g);
+                    g.record_dealloc(pp_info_idx, bt);
+                }
+            }
+        })
+    }
+}
+
+/// An allocator that tracks the allocations of individual containers on behalf
+/// of the [`Profiler`] type.
+///
+/// This implements the unstable [`Allocator`](std::alloc::Allocator) trait,
+/// and so requires a nightly compiler and the `allocator-api` feature of this
+/// crate. It lets heap profiling be limited to particular containers, e.g.
+/// those created with `Vec::new_in(dhat::TrackingAllocator)`, instead of
+/// covering every allocation in the program.
+///
+/// A [`Profiler`] doing heap profiling must be running for allocations to be
+/// recorded; otherwise they are passed straight through to the system
+/// allocator. [`Alloc`] can still be used as the global allocator at the same
+/// time, in which case the allocations from both are merged into a single
+/// profile.
+///
+/// # Examples
+/// ```
+/// #![feature(allocator_api)]
+///
+/// let _profiler = dhat::Profiler::new_heap();
+///
+/// let mut v = Vec::new_in(dhat::TrackingAllocator);
+/// v.push(1);
+/// ```
+#[cfg(feature = "allocator-api")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TrackingAllocator;
+
+#[cfg(feature = "allocator-api")]
+impl TrackingAllocator {
+    // `GlobalAlloc` doesn't allow zero-sized allocations, but `Allocator`
+    // does. This is what they get instead.
+    fn dangling(layout: Layout) -> std::ptr::NonNull<[u8]> {
+        let ptr = std::ptr::NonNull::new(std::ptr::without_provenance_mut(layout.align())).unwrap();
+        std::ptr::NonNull::slice_from_raw_parts(ptr, 0)
+    }
 
-    // The number of blocks and bytes at the global max, i.e. when
-    // `Globals::curr_bytes` peaks.
-    at_tgmax_blocks: usize,
-    at_tgmax_bytes: usize,
+    // Shared by `grow` and `shrink`. `Alloc::realloc` can only be used when
+    // the alignment is unchanged and neither size is zero; otherwise we
+    // allocate, copy, and deallocate.
+    unsafe fn resize(
+        &self,
+        ptr: std::ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<std::ptr::NonNull<[u8]>, std::alloc::AllocError> {
+        use std::alloc::Allocator;
+
+        if old_layout.align() == new_layout.align()
+            && old_layout.size() != 0
+            && new_layout.size() != 0
+        {
+            let new_ptr = Alloc.realloc(ptr.as_ptr(), old_layout, new_layout.size());
+            let new_ptr = std::ptr::NonNull::new(new_ptr).ok_or(std::alloc::AllocError)?;
+            Ok(std::ptr::NonNull::slice_from_raw_parts(
+                new_ptr,
+                new_layout.size(),
+            ))
+        } else {
+            let new_ptr = self.allocate(new_layout)?;
+            let size = std::cmp::min(old_layout.size(), new_layout.size());
+            std::ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr() as *mut u8, size);
+            self.deallocate(ptr, old_layout);
+            Ok(new_ptr)
+        }
+    }
+}
 
-    // Total lifetimes of all blocks allocated by this PP. Includes blocks
-    // explicitly freed and blocks implicitly freed at termination.
-    total_lifetimes_duration: Duration,
+#[cfg(feature = "allocator-api")]
+unsafe impl std::alloc::Allocator for TrackingAllocator {
+    fn allocate(&self, layout: Layout) -> Result<std::ptr::NonNull<[u8]>, std::alloc::AllocError> {
+        if layout.size() == 0 {
+            return Ok(Self::dangling(layout));
+        }
+        let ptr = unsafe { Alloc.alloc(layout) };
+        let ptr = std::ptr::NonNull::new(ptr).ok_or(std::alloc::AllocError)?;
+        Ok(std::ptr::NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: std::ptr::NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            Alloc.dealloc(ptr.as_ptr(), layout)
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: std::ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<std::ptr::NonNull<[u8]>, std::alloc::AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: std::ptr::NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<std::ptr::NonNull<[u8]>, std::alloc::AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
 }
 
-impl PpInfo {
-    fn new_heap() -> Self {
-        Self {
-            total_blocks: 0,
-            total_bytes: 0,
-            heap: Some(HeapPpInfo::default()),
+/// The kind of profiling being done by a [`Profiler`]. Returned by
+/// [`current_mode`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Heap profiling.
+    Heap,
+
+    /// Ad hoc profiling.
+    AdHoc,
+}
+
+/// Returns `true` if a [`Profiler`] is running, i.e. it has been built and
+/// not yet dropped, and no `dhat` assertion has failed.
+///
+/// This never panics, so it can be used to guard optional instrumentation.
+///
+/// # Examples
+/// ```
+/// if dhat::is_profiling() {
+///     // Do some expensive instrumentation.
+/// }
+/// ```
+pub fn is_profiling() -> bool {
+    current_mode().is_some()
+}
+
+/// Returns the kind of profiling being done by the running [`Profiler`], or
+/// `None` if no profiler is running (including after a `dhat` assertion has
+/// failed).
+///
+/// This never panics, so it can be used to guard optional instrumentation.
+///
+/// # Examples
+/// ```
+/// if dhat::current_mode() == Some(dhat::Mode::AdHoc) {
+///     dhat::ad_hoc_event(100);
+/// }
+/// ```
+pub fn current_mode() -> Option<Mode> {
+    let _ignore_allocs = IgnoreAllocs::new();
+
+    let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+    match phase {
+        Phase::Running(Globals { heap: Some(_), .. }) => Some(Mode::Heap),
+        Phase::Running(Globals { heap: None, .. }) => Some(Mode::AdHoc),
+        Phase::Ready | Phase::PostAssert(_) => None,
+    }
+}
+
+/// Gets the current heap stats, or `None` if no [`Profiler`] is running, it
+/// is doing ad hoc profiling, or a `dhat` assertion has failed.
+///
+/// This is a non-panicking alternative to [`HeapStats::get`], which is useful
+/// for code that may run under either kind of profiling, or none.
+///
+/// # Examples
+/// ```
+/// if let Some(stats) = dhat::try_heap_stats() {
+///     println!("{} bytes are allocated", stats.curr_bytes);
+/// }
+/// ```
+pub fn try_heap_stats() -> Option<HeapStats> {
+    let _ignore_allocs = IgnoreAllocs::new();
+
+    let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+    match phase {
+        Phase::Running(g) => g.try_get_heap_stats(),
+        Phase::Ready | Phase::PostAssert(_) => None,
+    }
+}
+
+/// Gets the current ad hoc stats, or `None` if no [`Profiler`] is running, it
+/// is doing heap profiling, or a `dhat` assertion has failed.
+///
+/// This is a non-panicking alternative to [`AdHocStats::get`], which is
+/// useful for code that may run under either kind of profiling, or none.
+///
+/// # Examples
+/// ```
+/// if let Some(stats) = dhat::try_ad_hoc_stats() {
+///     println!("{} events have occurred", stats.total_events);
+/// }
+/// ```
+pub fn try_ad_hoc_stats() -> Option<AdHocStats> {
+    let _ignore_allocs = IgnoreAllocs::new();
+
+    let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+    match phase {
+        Phase::Running(g) => g.try_get_ad_hoc_stats(),
+        Phase::Ready | Phase::PostAssert(_) => None,
+    }
+}
+
+/// Runs `f` under a heap profiler in testing mode, and returns its result
+/// along with the heap stats at the end of `f`. This is a shorthand for
+/// building a testing-mode [`Profiler`], calling `f`, calling
+/// [`HeapStats::get`], and dropping the `Profiler`.
+///
+/// Allocations still live when `f` returns, including any owned by its
+/// result, are counted in `curr_blocks` and `curr_bytes`.
+///
+/// # Panics
+///
+/// Panics if another `Profiler` is running.
+///
+/// # Examples
+/// ```
+/// let (v, stats) = dhat::profile_heap(|| vec![0u8; 1000]);
+/// println!("{} bytes were allocated", stats.total_bytes);
+/// # drop(v);
+/// ```
+pub fn profile_heap<R, F: FnOnce() -> R>(f: F) -> (R, HeapStats) {
+    let _profiler = Profiler::builder().testing().build();
+    let result = f();
+    (result, HeapStats::get())
+}
+
+// Set by `pause` and cleared by `resume`.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Pauses the recording of new allocations during heap profiling, until
+/// [`resume`] is called.
+///
+/// While paused, allocations pass through [`Alloc`] unrecorded. Those blocks
+/// remain unrecorded even if they are reallocated or freed after resuming.
+/// Deallocations of blocks that were allocated while not paused are still
+/// recorded, so that the counts stay consistent. A reallocation of such a
+/// block while paused is recorded as a deallocation of the old block, and the
+/// new block is unrecorded, like any other block allocated while paused. It
+/// is not counted as a reallocation.
+///
+/// This is useful for excluding, say, a noisy setup routine from a profile.
+/// It applies to all threads. It has no effect on ad hoc profiling. It is
+/// distinct from the per-thread mechanism that `dhat` uses internally to
+/// avoid recording its own allocations.
+///
+/// # Examples
+/// ```
+/// let _profiler = dhat::Profiler::new_heap();
+///
+/// dhat::pause();
+/// let setup = vec![0u8; 1024]; // not recorded
+/// dhat::resume();
+/// ```
+pub fn pause() {
+    PAUSED.store(true, Ordering::Relaxed);
+}
+
+/// Resumes the recording of new allocations after a call to [`pause`].
+///
+/// # Examples
+/// ```
+/// dhat::pause();
+/// dhat::resume();
+/// ```
+pub fn resume() {
+    PAUSED.store(false, Ordering::Relaxed);
+}
+
+/// Records a named marker at the current time, e.g. to show when a phase of
+/// the program starts or ends.
+///
+/// The markers are written to the saved profile in a `markers` array, with
+/// times relative to the start of profiling, in the profile's time unit
+/// (microseconds by default; see [`ProfilerBuilder::time_unit`]). DHAT's
+/// viewer ignores them, but other tools can use them to relate changes in
+/// memory usage to the program's phases. A call to this function has no
+/// effect if a [`Profiler`] is not running or not doing heap profiling.
+///
+/// # Examples
+/// ```
+/// let _profiler = dhat::Profiler::new_heap();
+///
+/// dhat::marker("request-start");
+/// let v = vec![0u8; 1024];
+/// dhat::marker("request-end");
+/// # std::mem::forget(_profiler); // Don't write the file in `cargo tests`
+/// ```
+pub fn marker(name: &str) {
+    let ignore_allocs = IgnoreAllocs::new();
+    std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+
+    let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+    if let Phase::Running(Globals {
+        heap: Some(h),
+        stopped_early: false,
+        clock,
+        ..
+    }) = phase
+    {
+        h.markers.push((Clock::now(clock), name.to_string()));
+    }
+}
+
+// The body of the `ad_hoc_event*` functions, which differ only in how they
+// get the backtrace, `$bt`, and which `Globals` method, `$update`, they use to
+// update the counts with `$weight`. `$bt` is evaluated with `$g` bound to the
+// globals.
+//
+// A macro rather than a function to avoid putting an extra frame into
+// backtraces.
+macro_rules! record_ad_hoc_event {
+    (|$g:ident| $bt:expr, $update:ident($weight:expr)) => {{
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+
+        let mut guard = TRI_GLOBALS.lock();
+        let phase: &mut Phase<Globals> = &mut guard;
+        if let Phase::Running(
+            $g @ Globals {
+                heap: None,
+                stopped_early: false,
+                ..
+            },
+        ) = phase
+        {
+            let bt = $bt;
+            let pp_info_idx = $g.get_pp_info(bt, PpInfo::new_ad_hoc);
+
+            // Update counts.
+            $g.$update(pp_info_idx, $weight);
+
+            if let Some(out) = $g.check_metadata_limit() {
+                drop(guard);
+                out.print();
+            }
+        }
+    }};
+}
+
+/// Registers an event during ad hoc profiling.
+///
+/// The meaning of the weight argument is determined by the user. A call to
+/// this function has no effect if a [`Profiler`] is not running or not doing ad
+/// hoc profiling.
+pub fn ad_hoc_event(weight: usize) {
+    record_ad_hoc_event!(
+        |g| new_backtrace!(g),
+        update_counts_for_ad_hoc_event(weight)
+    );
+}
+
+/// Registers an event during ad hoc profiling, distinguished by a label as
+/// well as by its backtrace.
+///
+/// This is like [`ad_hoc_event`], but events with the same backtrace and
+/// different labels are recorded as different program points, and the label
+/// appears as a synthetic `[label: <label>]` frame at the top of each one's
+/// backtrace. This is useful for distinguishing categories of events
+/// recorded from shared code, such as a helper function that every caller
+/// goes through. A call to this function has no effect if a [`Profiler`] is
+/// not running or not doing ad hoc profiling.
+///
+/// # Examples
+/// ```
+/// let _profiler = dhat::Profiler::new_ad_hoc();
+///
+/// fn record(kind: &'static str, bytes: usize) {
+///     dhat::ad_hoc_event_labeled(kind, bytes);
+/// }
+/// record("cache-miss", 64);
+/// record("cache-hit", 64);
+/// # std::mem::forget(_profiler); // Don't write the file in `cargo tests`
+/// ```
+pub fn ad_hoc_event_labeled(label: &'static str, weight: usize) {
+    record_ad_hoc_event!(
+        |g| new_backtrace!(g).with_tag(Tag::Label(label)),
+        update_counts_for_ad_hoc_event(weight)
+    );
+}
+
+/// Registers an event during ad hoc profiling, attributed to the location of
+/// the call rather than to a backtrace.
+///
+/// This is like [`ad_hoc_event`], but instead of getting a backtrace, which
+/// is expensive, it uses the caller's source location, which is almost free.
+/// In the saved profile, each call site is a program point with a single
+/// frame of the form `file:line:column`. This is useful for hot annotation
+/// points where the call site alone is enough.
+///
+/// # Examples
+/// ```
+/// let _profiler = dhat::Profiler::new_ad_hoc();
+/// dhat::ad_hoc_event_here(100);
+/// # std::mem::forget(_profiler); // Don't write the file in `cargo tests`
+/// ```
+#[track_caller]
+pub fn ad_hoc_event_here(weight: usize) {
+    let location = Location::caller();
+    record_ad_hoc_event!(
+        |g| Backtrace::from_location(location, current_region()),
+        update_counts_for_ad_hoc_event(weight)
+    );
+}
+
+/// Registers an event with a fractional weight during ad hoc profiling.
+///
+/// This is like [`ad_hoc_event`], but is useful for weights such as elapsed
+/// times or ratios. If both functions are used during a single profiling run
+/// the weights are summed in the saved data, but [`AdHocStats`] reports them
+/// separately.
+pub fn ad_hoc_event_f64(weight: f64) {
+    record_ad_hoc_event!(
+        |g| new_backtrace!(g),
+        update_counts_for_ad_hoc_event_f64(weight)
+    );
+}
+
+/// Merges several saved profiles into one, e.g. the profiles written by
+/// multiple processes that each handled one shard of a workload.
+///
+/// Program points from different profiles with identical frame sequences are
+/// combined by summing their counts. The peak values (`mb`, `mbk`, `gb`,
+/// `gbk`) are also summed, which overestimates the combined peak unless the
+/// processes reached their peaks at the same time. The merged profile's end
+/// time is the latest of the input end times, and its global peak time is
+/// that of the input profile with the largest global peak. Other fields, such
+/// as `pid`, are taken from the first profile; `cmd` is combined if the
+/// commands differ.
+///
+/// All the profiles must have the same mode (heap or ad hoc) and time unit.
+///
+/// # Errors
+///
+/// Returns an error if any profile cannot be read or parsed, if the profiles
+/// are incompatible, if `paths` is empty, or if the output cannot be written.
+///
+/// # Examples
+/// ```no_run
+/// use std::path::Path;
+///
+/// dhat::merge_profiles(
+///     &[Path::new("shard-0/dhat-heap.json"), Path::new("shard-1/dhat-heap.json")],
+///     Path::new("dhat-heap.json"),
+/// )
+/// .unwrap();
+/// ```
+pub fn merge_profiles(paths: &[&Path], out: &Path) -> std::io::Result<()> {
+    use serde_json::{Map, Value};
+    use std::io::{Error, ErrorKind};
+
+    let invalid = |path: &Path, msg: String| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), msg),
+        )
+    };
+
+    // The fields of each PP that are summed when PPs are combined.
+    const SUMMED_FIELDS: [&str; 9] = ["tb", "tbk", "tl", "mb", "mbk", "gb", "gbk", "eb", "ebk"];
+
+    let mut merged: Option<Map<String, Value>> = None;
+    let mut cmds: Vec<String> = vec![];
+    let mut unresolved = false;
+    let mut te: u64 = 0;
+    // The `tg` and total `gb` of the input with the largest global peak.
+    let mut peak: Option<(Value, u64)> = None;
+    let mut ftbl: Vec<String> = vec![];
+    let mut ftbl_indices: FxHashMap<String, usize> = FxHashMap::default();
+    let mut pps: Vec<Map<String, Value>> = vec![];
+    let mut pp_indices: FxHashMap<Vec<usize>, usize> = FxHashMap::default();
+
+    for &path in paths {
+        let json: Value = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        let Value::Object(mut json) = json else {
+            return Err(invalid(path, "not a DHAT profile".to_string()));
+        };
+
+        // Check compatibility with the first profile.
+        if let Some(first) = &merged {
+            for field in ["dhatFileVersion", "mode", "tu"] {
+                if json.get(field) != first.get(field) {
+                    return Err(invalid(
+                        path,
+                        format!("`{}` differs from that of the first profile", field),
+                    ));
+                }
+            }
+        }
+
+        if let Some(cmd) = json.get("cmd").and_then(Value::as_str) {
+            if !cmds.iter().any(|c| c == cmd) {
+                cmds.push(cmd.to_string());
+            }
+        }
+        unresolved |= json.get("unresolved") == Some(&Value::Bool(true));
+        te = te.max(json.get("te").and_then(Value::as_u64).unwrap_or(0));
+
+        // Map this profile's frame table indices to merged ones.
+        let Some(Value::Array(file_ftbl)) = json.remove("ftbl") else {
+            return Err(invalid(path, "missing `ftbl`".to_string()));
+        };
+        let mut frame_map = Vec::with_capacity(file_ftbl.len());
+        for frame in file_ftbl {
+            let Value::String(frame) = frame else {
+                return Err(invalid(path, "non-string frame in `ftbl`".to_string()));
+            };
+            let idx = *ftbl_indices.entry(frame).or_insert_with_key(|frame| {
+                ftbl.push(frame.clone());
+                ftbl.len() - 1
+            });
+            frame_map.push(idx);
+        }
+
+        let Some(Value::Array(file_pps)) = json.remove("pps") else {
+            return Err(invalid(path, "missing `pps`".to_string()));
+        };
+        let mut total_gb = 0;
+        for pp in file_pps {
+            let Value::Object(mut pp) = pp else {
+                return Err(invalid(path, "non-object PP in `pps`".to_string()));
+            };
+            total_gb += pp.get("gb").and_then(Value::as_u64).unwrap_or(0);
+
+            let fs = pp
+                .get("fs")
+                .and_then(Value::as_array)
+                .ok_or_else(|| invalid(path, "PP without `fs`".to_string()))?
+                .iter()
+                .map(|f| {
+                    f.as_u64()
+                        .and_then(|f| frame_map.get(f as usize).copied())
+                        .ok_or_else(|| invalid(path, "bad frame index in `fs`".to_string()))
+                })
+                .collect::<std::io::Result<Vec<usize>>>()?;
+
+            if let Some(&pp_idx) = pp_indices.get(&fs) {
+                let merged_pp = &mut pps[pp_idx];
+                for field in SUMMED_FIELDS {
+                    if let Some(n) = pp.remove(field) {
+                        let sum = match merged_pp.get(field) {
+                            Some(m) => match (m.as_u64(), n.as_u64()) {
+                                (Some(m), Some(n)) => Value::from(m + n),
+                                _ => Value::from(
+                                    m.as_f64().unwrap_or(0.0) + n.as_f64().unwrap_or(0.0),
+                                ),
+                            },
+                            None => n,
+                        };
+                        merged_pp.insert(field.to_string(), sum);
+                    }
+                }
+                // Lifetime histograms are summed bucket by bucket.
+                if let Some(Value::Array(n)) = pp.remove("lh") {
+                    let mut lh: Vec<u64> = match merged_pp.get("lh") {
+                        Some(Value::Array(m)) => m.iter().filter_map(Value::as_u64).collect(),
+                        _ => vec![],
+                    };
+                    if lh.len() < n.len() {
+                        lh.resize(n.len(), 0);
+                    }
+                    for (m, n) in lh.iter_mut().zip(n.iter().filter_map(Value::as_u64)) {
+                        *m += n;
+                    }
+                    merged_pp.insert("lh".to_string(), Value::from(lh));
+                }
+            } else {
+                pp.insert("fs".to_string(), Value::from(fs.clone()));
+                pp_indices.insert(fs, pps.len());
+                pps.push(pp);
+            }
+        }
+
+        if let Some(tg) = json.get("tg") {
+            if peak.as_ref().is_none_or(|(_, gb)| total_gb > *gb) {
+                peak = Some((tg.clone(), total_gb));
+            }
+        }
+
+        if merged.is_none() {
+            merged = Some(json);
         }
     }
 
-    fn new_ad_hoc() -> Self {
-        Self {
-            total_blocks: 0,
-            total_bytes: 0,
-            heap: None,
+    let Some(mut merged) = merged else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "dhat: no profiles to merge",
+        ));
+    };
+    merged.insert("cmd".to_string(), Value::from(cmds.join("; ")));
+    if unresolved {
+        merged.insert("unresolved".to_string(), Value::Bool(true));
+    }
+    merged.insert("te".to_string(), Value::from(te));
+    if let Some((tg, _)) = peak {
+        merged.insert("tg".to_string(), tg);
+    }
+    merged.insert(
+        "pps".to_string(),
+        Value::Array(pps.into_iter().map(Value::Object).collect()),
+    );
+    merged.insert("ftbl".to_string(), Value::from(ftbl));
+
+    // Written in the same way as `Globals::save`, without syncing, as with
+    // the default `ProfilerBuilder::sync_on_write`.
+    write_file_atomically(out, false, |buffered_file| {
+        let formatter = serde_json::ser::PrettyFormatter::with_indent(b"");
+        let mut ser = serde_json::Serializer::with_formatter(buffered_file, formatter);
+        merged.serialize(&mut ser)?;
+        Ok(ser.into_inner())
+    })
+}
+
+// Write a file to `path` with `write`. The data is written to a temporary
+// file in the same directory, which is then renamed, so that a crash, kill or
+// I/O error while writing never leaves a truncated file behind.
+// (`fs::rename` replaces an existing file on all platforms.) With `sync`, the
+// data is synced before the rename, so the renamed file is never empty after
+// a crash. The caller should then call `sync_parent_dir`, so the rename
+// itself is durable.
+fn write_file_atomically(
+    path: &Path,
+    sync: bool,
+    write: impl FnOnce(BufWriter<File>) -> std::io::Result<BufWriter<File>>,
+) -> std::io::Result<()> {
+    let mut tmp_file_name = path.to_path_buf().into_os_string();
+    tmp_file_name.push(".tmp");
+    let tmp_file_name = PathBuf::from(tmp_file_name);
+
+    let write = || -> std::io::Result<()> {
+        let buffered_file = write(BufWriter::new(File::create(&tmp_file_name)?))?;
+        let file = buffered_file.into_inner()?;
+        if sync {
+            file.sync_all()?;
         }
+        std::fs::rename(&tmp_file_name, path)
+    };
+    let res = write();
+    if res.is_err() {
+        // Don't leave a partial temporary file behind. This may fail, e.g. if
+        // the file was never created, which is fine.
+        let _ = std::fs::remove_file(&tmp_file_name);
     }
+    res
+}
 
-    fn update_counts_for_alloc(&mut self, size: usize, delta: Option<Delta>) {
-        self.total_blocks += 1;
-        self.total_bytes += size as u64;
+// Sync the directory containing `path`, so that a rename into it is durable.
+// A failure here doesn't mean the file wasn't written, so callers report it
+// as a warning. Directories can't be opened as files on Windows, where
+// renames are durable anyway.
+fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
 
-        let h = self.heap.as_mut().unwrap();
-        if let Some(delta) = delta {
-            // realloc
-            h.curr_blocks += 0; // unchanged
-            h.curr_bytes += delta;
-        } else {
-            // alloc
-            h.curr_blocks += 1;
-            h.curr_bytes += size;
+// The labels of the live `Region`s on this thread, innermost last.
+thread_local!(static REGIONS: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) });
+
+// Get the label of the innermost live `Region` on this thread, if any. This
+// can be called during thread teardown, after `REGIONS` has been destroyed.
+fn current_region() -> Option<&'static str> {
+    REGIONS
+        .try_with(|regions| regions.borrow().last().copied())
+        .unwrap_or(None)
+}
+
+/// A scope guard that labels allocations and ad hoc events with a logical
+/// phase of the program, such as "parsing" or "codegen".
+///
+/// While a `Region` is live, every program point recorded on the same thread
+/// is keyed by the region's label as well as its backtrace, and the label
+/// appears as a synthetic `[region: <label>]` frame at the top of the
+/// backtrace in the saved profile. Regions can be nested, in which case the
+/// innermost one applies. Regions on one thread do not affect other threads.
+///
+/// # Examples
+/// ```
+/// let _profiler = dhat::Profiler::new_heap();
+///
+/// let v = {
+///     let _region = dhat::Region::new("parsing");
+///     vec![1, 2, 3]
+/// };
+/// ```
+#[derive(Debug)]
+pub struct Region {
+    // Not `Send`, because the label stack is per-thread.
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+impl Region {
+    /// Starts a region with the given label, which lasts until the returned
+    /// value is dropped.
+    pub fn new(label: &'static str) -> Self {
+        let _ignore_allocs = IgnoreAllocs::new();
+        REGIONS.with(|regions| regions.borrow_mut().push(label));
+        Region {
+            _not_send: std::marker::PhantomData,
+        }
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        let _ignore_allocs = IgnoreAllocs::new();
+        REGIONS.with(|regions| regions.borrow_mut().pop());
+    }
+}
+
+impl Profiler {
+    fn drop_inner(&self, memory_output: Option<&mut String>) {
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+
+        // If requested, record the t-end counts and then wait for a while
+        // (without holding the lock), so that blocks freed shortly after the
+        // end can be distinguished from leaks.
+        let settle = {
+            let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+            match phase {
+                Phase::Running(g @ Globals { heap: Some(_), .. })
+                    if !g.testing && !g.stopped_early =>
+                {
+                    let settle = g.end_settle;
+                    let h = g.heap.as_mut().unwrap();
+                    if settle.is_some() {
+                        h.t_end_before_settle = Some((h.curr_bytes, h.curr_blocks));
+                    }
+                    settle
+                }
+                _ => None,
+            }
+        };
+        if let Some(settle) = settle {
+            std::thread::sleep(settle);
+        }
+
+        let mut leak = None;
+        let out = {
+            let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+            HEAP_PROFILING.store(false, Ordering::Release);
+            match std::mem::replace(phase, Phase::Ready) {
+                Phase::Ready => unreachable!(),
+                Phase::Running(g) if !g.testing && !g.stopped_early => {
+                    Some(g.finish(memory_output))
+                }
+                Phase::Running(g) if g.testing && g.fail_on_leak && !std::thread::panicking() => {
+                    leak = g.get_leak();
+                    leak.is_some().then(|| g.finish(memory_output))
+                }
+                Phase::Running(_) | Phase::PostAssert(_) => None,
+            }
+        };
+        if let Some(out) = out {
+            out.print();
+        }
+        if let Some((site, bytes, blocks)) = leak {
+            drop(ignore_allocs);
+            panic!(
+                "dhat: leak detected: {} bytes in {} blocks still allocated; the most is \
+                 allocated at {}",
+                bytes, blocks, site
+            );
+        }
+    }
+
+    /// Gets the total number of bytes and blocks allocated by each thread so
+    /// far during heap profiling.
+    ///
+    /// Each element is a `(name, bytes, blocks)` triple. The name is the
+    /// thread's name if it has one, otherwise its
+    /// [`ThreadId`](std::thread::ThreadId) in debug form.
+    /// Allocations made while a thread is being torn down may be attributed to
+    /// `<unknown thread>`.
+    /// Elements are sorted by decreasing byte count. Reallocations are
+    /// attributed to the thread that performed them. The result is empty when
+    /// doing ad hoc profiling.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called after a `dhat` assertion has failed, or after
+    /// [`Profiler::force_finish`].
+    ///
+    /// # Examples
+    /// ```
+    /// let profiler = dhat::Profiler::new_heap();
+    /// for (name, bytes, blocks) in profiler.per_thread_totals() {
+    ///     println!("{}: {} bytes in {} blocks", name, bytes, blocks);
+    /// }
+    /// # std::mem::forget(profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn per_thread_totals(&self) -> Vec<(String, u64, u64)> {
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+        if self.finished.load(Ordering::Relaxed) {
+            panic!("dhat: getting per-thread totals after the profiler has finished");
+        }
+
+        let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+        match phase {
+            Phase::Ready => unreachable!(),
+            Phase::Running(g) => g.get_per_thread_totals(),
+            Phase::PostAssert(_) => {
+                panic!("dhat: getting per-thread totals after the profiler has asserted")
+            }
+        }
+    }
+
+    /// Gets the peak number of bytes allocated by each thread at any one time
+    /// so far during heap profiling. This identifies the thread that held
+    /// the most memory at once, e.g. in a thread-local cache, which the
+    /// totals from [`Profiler::per_thread_totals`] cannot.
+    ///
+    /// Each element is a `(name, bytes)` pair, named like those from
+    /// [`Profiler::per_thread_totals`], and elements are sorted by decreasing
+    /// byte count. Each block counts towards the thread that allocated (or
+    /// last reallocated) it until it is freed, even if another thread frees
+    /// it, so the peaks are approximate when blocks are passed between
+    /// threads. The result is empty when doing ad hoc profiling.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called after a `dhat` assertion has failed, or after
+    /// [`Profiler::force_finish`].
+    ///
+    /// # Examples
+    /// ```
+    /// let profiler = dhat::Profiler::new_heap();
+    /// for (name, bytes) in profiler.per_thread_peaks() {
+    ///     println!("{}: peaked at {} bytes", name, bytes);
+    /// }
+    /// # std::mem::forget(profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn per_thread_peaks(&self) -> Vec<(String, usize)> {
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+        if self.finished.load(Ordering::Relaxed) {
+            panic!("dhat: getting per-thread peaks after the profiler has finished");
         }
 
-        // The use of `>=` not `>` means that if there are multiple equal peaks
-        // we record the latest one, like `check_for_global_peak` does.
-        if h.curr_bytes >= h.max_bytes {
-            h.max_blocks = h.curr_blocks;
-            h.max_bytes = h.curr_bytes;
+        let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+        match phase {
+            Phase::Ready => unreachable!(),
+            Phase::Running(g) => g.get_per_thread_peaks(),
+            Phase::PostAssert(_) => {
+                panic!("dhat: getting per-thread peaks after the profiler has asserted")
+            }
         }
     }
 
-    fn update_counts_for_dealloc(&mut self, size: usize, alloc_duration: Duration) {
-        let h = self.heap.as_mut().unwrap();
-        h.curr_blocks -= 1;
-        h.curr_bytes -= size;
-        h.total_lifetimes_duration += alloc_duration;
-    }
+    /// Gets a report for every program point (i.e. unique backtrace) at which
+    /// allocations or ad hoc events have occurred, for building custom
+    /// reports such as CSV files or tables.
+    ///
+    /// The counts are taken when this is called. The frames of each report are
+    /// looked up lazily, as the iterator is advanced, because that requires
+    /// looking up debug info, which can be slow. Reports are in the order
+    /// that their program points were first seen.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called after a `dhat` assertion has failed, or after
+    /// [`Profiler::force_finish`].
+    ///
+    /// # Examples
+    /// ```
+    /// let profiler = dhat::Profiler::new_heap();
+    /// let _v = vec![1, 2, 3];
+    /// for pp in profiler.iter_pps() {
+    ///     println!("{},{},{}", pp.total_bytes, pp.total_blocks, pp.frames.join(";"));
+    /// }
+    /// # std::mem::forget(profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn iter_pps(&self) -> impl Iterator<Item = PpReport> {
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+        if self.finished.load(Ordering::Relaxed) {
+            panic!("dhat: getting program points after the profiler has finished");
+        }
 
-    fn update_counts_for_ad_hoc_event(&mut self, weight: usize) {
-        std::assert!(self.heap.is_none());
-        self.total_blocks += 1;
-        self.total_bytes += weight as u64;
+        let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+        let (frame_format, frames, pps) = match phase {
+            Phase::Ready => unreachable!(),
+            Phase::Running(g) => g.get_pp_reports(),
+            Phase::PostAssert(_) => {
+                panic!("dhat: getting program points after the profiler has asserted")
+            }
+        };
+        pps.into_iter().map(move |(bt, mut report)| {
+            // The frame strings are for the caller, not part of the profile.
+            let _ignore_allocs = IgnoreAllocs::new();
+            report.frames.extend(bt.tag_string());
+            if let Some(label) = bt.region {
+                report.frames.push(format!("[region: {}]", label));
+            }
+            report
+                .frames
+                .extend(frame_format.frame_strings(&frames, &bt));
+            report
+        })
     }
-}
-
-struct LiveBlock {
-    // The index of the PpInfo for this block.
-    pp_info_idx: usize,
-
-    // When the block was allocated.
-    allocation_instant: Instant,
-}
-
-// We record info about allocations and deallocations. A wrinkle: the recording
-// done may trigger additional allocations. We must ignore these because (a)
-// they're part of `dhat`'s execution, not the original program's execution,
-// and (b) they would be intercepted and trigger additional allocations, which
-// would be intercepted and trigger additional allocations, and so on, leading
-// to infinite loops.
-//
-// With this type we can run one code path if we are already ignoring
-// allocations. Otherwise, we can a second code path while ignoring
-// allocations. In practice, the first code path is unreachable except within
-// the `GlobalAlloc` methods.
-//
-// WARNING: This type must be used for any code within this crate that can
-// trigger allocations.
-struct IgnoreAllocs {
-    was_already_ignoring_allocs: bool,
-}
-
-thread_local!(static IGNORE_ALLOCS: Cell<bool> = Cell::new(false));
 
-impl IgnoreAllocs {
-    fn new() -> Self {
-        Self {
-            was_already_ignoring_allocs: IGNORE_ALLOCS.with(|b| b.replace(true)),
+    /// Gets information about every block that is currently allocated during
+    /// heap profiling, which is useful for investigating leaks.
+    ///
+    /// Elements are sorted by decreasing size. Getting the top frame of each
+    /// block requires looking up debug info, so this can be slow if there are
+    /// many distinct allocation points. The result is empty when doing ad hoc
+    /// profiling.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called after a `dhat` assertion has failed, or after
+    /// [`Profiler::force_finish`].
+    ///
+    /// # Examples
+    /// ```
+    /// let profiler = dhat::Profiler::new_heap();
+    /// let _v = vec![1, 2, 3];
+    /// for block in profiler.live_blocks() {
+    ///     println!("{} bytes at {}", block.size, block.top_frame);
+    /// }
+    /// # std::mem::forget(profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn live_blocks(&self) -> Vec<LiveBlockInfo> {
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+        if self.finished.load(Ordering::Relaxed) {
+            panic!("dhat: getting live blocks after the profiler has finished");
         }
-    }
-}
 
-/// If code panics while `IgnoreAllocs` is live, this will still reset
-/// `IGNORE_ALLOCS` so that it can be used again.
-impl Drop for IgnoreAllocs {
-    fn drop(&mut self) {
-        if !self.was_already_ignoring_allocs {
-            IGNORE_ALLOCS.with(|b| b.set(false));
+        let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+        match phase {
+            Phase::Ready => unreachable!(),
+            Phase::Running(g) => g.get_live_blocks(),
+            Phase::PostAssert(_) => {
+                panic!("dhat: getting live blocks after the profiler has asserted")
+            }
         }
     }
-}
-
-/// A type whose lifetime dictates the start and end of profiling.
-///
-/// Profiling starts when the first value of this type is created. Profiling
-/// stops when (a) this value is dropped or (b) a `dhat` assertion fails,
-/// whichever comes first. When that happens, profiling data may be written to
-/// file, depending on how the `Profiler` has been configured. Only one
-/// `Profiler` can be running at any point in time.
-//
-// The actual profiler state is stored in `Globals`, so it can be accessed from
-// places like `Alloc::alloc` and `ad_hoc_event()` when the `Profiler`
-// instance isn't within reach.
-#[derive(Debug)]
-pub struct Profiler;
 
-impl Profiler {
-    /// Initiates allocation profiling.
+    /// Gets the number of deallocations for each pair of allocation site and
+    /// deallocation site, when enabled with
+    /// [`ProfilerBuilder::record_dealloc_backtraces`].
     ///
-    /// Typically the first thing in `main`. Its result should be assigned to a
-    /// variable whose lifetime ends at the end of `main`.
+    /// Each element is an `(alloc_frame, dealloc_frame, count)` triple, where
+    /// the frames are the first interesting frames of the respective
+    /// backtraces, in the same form as in the saved profile. Elements are
+    /// sorted by decreasing count. Getting the frames requires looking up
+    /// debug info, so this can be slow. The result is empty when not enabled,
+    /// or when doing ad hoc profiling.
     ///
     /// # Panics
     ///
-    /// Panics if another `Profiler` is running.
+    /// Panics if called after a `dhat` assertion has failed, or after
+    /// [`Profiler::force_finish`].
     ///
     /// # Examples
     /// ```
-    /// let _profiler = dhat::Profiler::new_heap();
+    /// let profiler = dhat::Profiler::builder()
+    ///     .record_dealloc_backtraces(true)
+    ///     .build();
+    /// for (alloc_frame, dealloc_frame, count) in profiler.alloc_dealloc_matrix() {
+    ///     println!("{}: allocated at {}, freed at {}", count, alloc_frame, dealloc_frame);
+    /// }
+    /// # std::mem::forget(profiler); // Don't write the file in `cargo tests`
     /// ```
-    pub fn new_heap() -> Self {
-        Self::builder().build()
+    pub fn alloc_dealloc_matrix(&self) -> Vec<(String, String, u64)> {
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+        if self.finished.load(Ordering::Relaxed) {
+            panic!("dhat: getting the alloc/dealloc matrix after the profiler has finished");
+        }
+
+        let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+        match phase {
+            Phase::Ready => unreachable!(),
+            Phase::Running(g) => g.get_alloc_dealloc_matrix(),
+            Phase::PostAssert(_) => {
+                panic!("dhat: getting the alloc/dealloc matrix after the profiler has asserted")
+            }
+        }
     }
 
-    /// Initiates ad hoc profiling.
+    /// Gets heap stats for the allocations whose backtraces have a frame
+    /// containing `needle`, e.g. a function name such as
+    /// `"my_crate::parse"`, or a file name. Frames are matched in the same
+    /// form as they appear in the saved profile, but before any trimming.
+    /// The label of a [`Region`] is also matched.
     ///
-    /// Typically the first thing in `main`. Its result should be assigned to a
-    /// variable whose lifetime ends at the end of `main`.
+    /// `max_blocks` and `max_bytes` are the matching allocations' shares of
+    /// the global peak. `failed_blocks`, `zst_allocations`, `largest_block`,
+    /// and the `realloc_*` fields are not tracked per backtrace, and are
+    /// always zero.
+    ///
+    /// This looks up debug info for every backtrace, so it is slow, and is
+    /// intended for tests and diagnostics, rather than frequent use.
     ///
     /// # Panics
     ///
-    /// Panics if another `Profiler` is running.
+    /// Panics if called during ad hoc profiling, after a `dhat` assertion has
+    /// failed, or after [`Profiler::force_finish`].
     ///
     /// # Examples
     /// ```
-    /// let _profiler = dhat::Profiler::new_ad_hoc();
+    /// let profiler = dhat::Profiler::builder().testing().build();
+    ///
+    /// fn parse() -> Vec<u8> {
+    ///     vec![0; 100]
+    /// }
+    /// let v = parse();
+    ///
+    /// let stats = profiler.stats_matching("parse");
+    /// println!("`parse` has allocated {} bytes", stats.total_bytes);
     /// ```
-    pub fn new_ad_hoc() -> Self {
-        Self::builder().ad_hoc().build()
-    }
+    pub fn stats_matching(&self, needle: &str) -> HeapStats {
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+        if self.finished.load(Ordering::Relaxed) {
+            panic!("dhat: getting heap stats after the profiler has finished");
+        }
 
-    /// Creates a new [`ProfilerBuilder`], which defaults to heap profiling.
-    pub fn builder() -> ProfilerBuilder {
-        ProfilerBuilder {
-            ad_hoc: false,
-            testing: false,
-            file_name: None,
-            trim_backtraces: Some(10),
-            eprint_json: false,
+        let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+        match phase {
+            Phase::Ready => unreachable!(),
+            Phase::Running(g) => g.get_heap_stats_matching(needle),
+            Phase::PostAssert(_) => {
+                panic!("dhat: getting heap stats after the profiler has asserted")
+            }
         }
     }
-}
 
-/// A builder for [`Profiler`], for cases beyond the basic ones provided by
-/// [`Profiler`].
-///
-/// Created with [`Profiler::builder`].
-#[derive(Debug)]
-pub struct ProfilerBuilder {
-    ad_hoc: bool,
-    testing: bool,
-    file_name: Option<PathBuf>,
-    trim_backtraces: Option<usize>,
-    eprint_json: bool,
-}
-
-impl ProfilerBuilder {
-    /// Requests ad hoc profiling.
+    /// Gets the allocation site holding the most bytes at the global peak
+    /// ("t-gmax"), i.e. the site that the viewer lists first when sorting by
+    /// "At t-gmax (bytes)".
+    ///
+    /// The site is the first interesting frame of its backtrace, in the same
+    /// form as in the saved profile, and is returned with the number of bytes
+    /// it held at the peak. Getting the frame requires looking up debug info,
+    /// so this can be slow. The result is `None` if no memory has been
+    /// allocated, or when doing ad hoc profiling.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called after a `dhat` assertion has failed, or after
+    /// [`Profiler::force_finish`].
     ///
     /// # Examples
     /// ```
-    /// let _profiler = dhat::Profiler::builder().ad_hoc().build();
+    /// let profiler = dhat::Profiler::new_heap();
+    /// let v = vec![0u8; 1000];
+    /// if let Some((frame, bytes)) = profiler.peak_site() {
+    ///     println!("{} bytes at the peak were allocated at {}", bytes, frame);
+    /// }
+    /// # std::mem::forget(profiler); // Don't write the file in `cargo tests`
     /// ```
-    pub fn ad_hoc(mut self) -> Self {
-        self.ad_hoc = true;
-        self
+    pub fn peak_site(&self) -> Option<(String, usize)> {
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+        if self.finished.load(Ordering::Relaxed) {
+            panic!("dhat: getting the peak site after the profiler has finished");
+        }
+
+        let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+        match phase {
+            Phase::Ready => unreachable!(),
+            Phase::Running(g) => g.get_peak_site(),
+            Phase::PostAssert(_) => {
+                panic!("dhat: getting the peak site after the profiler has asserted")
+            }
+        }
     }
 
-    /// Requests testing mode, which allows the use of
-    /// [`dhat::assert!`](assert) and related macros, and disables saving of
-    /// profile data on [`Profiler`] drop.
+    /// Gets the allocation site of the largest single block allocated so far
+    /// (see [`HeapStats::largest_block`]), e.g. a giant buffer.
+    ///
+    /// The site is the first interesting frame of its backtrace, in the same
+    /// form as in the saved profile, and is returned with the block's size in
+    /// bytes. Getting the frame requires looking up debug info, so this can
+    /// be slow. The result is `None` if no memory has been allocated, or when
+    /// doing ad hoc profiling.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called after a `dhat` assertion has failed, or after
+    /// [`Profiler::force_finish`].
     ///
     /// # Examples
     /// ```
-    /// let _profiler = dhat::Profiler::builder().testing().build();
+    /// let profiler = dhat::Profiler::new_heap();
+    /// let v = vec![0u8; 1000];
+    /// if let Some((frame, bytes)) = profiler.largest_block_site() {
+    ///     println!("the largest block, {} bytes, was allocated at {}", bytes, frame);
+    /// }
+    /// # std::mem::forget(profiler); // Don't write the file in `cargo tests`
     /// ```
-    pub fn testing(mut self) -> Self {
-        self.testing = true;
-        self
+    pub fn largest_block_site(&self) -> Option<(String, usize)> {
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+        if self.finished.load(Ordering::Relaxed) {
+            panic!("dhat: getting the largest block site after the profiler has finished");
+        }
+
+        let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+        match phase {
+            Phase::Ready => unreachable!(),
+            Phase::Running(g) => g.get_largest_block_site(),
+            Phase::PostAssert(_) => {
+                panic!("dhat: getting the largest block site after the profiler has asserted")
+            }
+        }
     }
 
-    /// Sets the name of the file in which profiling data will be saved.
+    /// Gets the total number of bytes allocated so far, grouped by the source
+    /// file of each allocation point, for a coarse view of which files
+    /// allocate the most, e.g. "30% of allocations originate in
+    /// `parser.rs`".
+    ///
+    /// Each allocation point is attributed to the file of the first frame of
+    /// its backtrace that is outside the standard library, after the
+    /// trimming done for the saved profile (see
+    /// [`ProfilerBuilder::trim_top_matching`]). File names are shortened as
+    /// in the saved profile. Allocation points whose file is unknown (e.g. due to missing
+    /// debug info) are grouped under `"???"`. Elements are sorted by
+    /// decreasing byte count. When doing ad hoc profiling, the counts are
+    /// units rather than bytes.
+    ///
+    /// This looks up debug info for every backtrace, so it is slow, and is
+    /// intended for diagnostics, rather than frequent use.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called after a `dhat` assertion has failed, or after
+    /// [`Profiler::force_finish`].
     ///
     /// # Examples
     /// ```
-    /// let file_name = format!("heap-{}.json", std::process::id());
-    /// let _profiler = dhat::Profiler::builder().file_name(file_name).build();
-    /// # std::mem::forget(_profiler); // Don't write the file in `cargo tests`
+    /// let profiler = dhat::Profiler::new_heap();
+    /// let _v = vec![0u8; 1000];
+    /// for (file, bytes) in profiler.bytes_by_file() {
+    ///     println!("{}: {} bytes", file, bytes);
+    /// }
+    /// # std::mem::forget(profiler); // Don't write the file in `cargo tests`
     /// ```
-    pub fn file_name<P: AsRef<Path>>(mut self, file_name: P) -> Self {
-        self.file_name = Some(file_name.as_ref().to_path_buf());
-        self
+    pub fn bytes_by_file(&self) -> Vec<(String, u64)> {
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+        if self.finished.load(Ordering::Relaxed) {
+            panic!("dhat: getting bytes by file after the profiler has finished");
+        }
+
+        let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+        match phase {
+            Phase::Ready => unreachable!(),
+            Phase::Running(g) => g.get_bytes_by_file(),
+            Phase::PostAssert(_) => {
+                panic!("dhat: getting bytes by file after the profiler has asserted")
+            }
+        }
     }
 
-    /// Sets how backtrace trimming is performed.
+    /// Gets an estimate of the number of bytes used by the profiler's own
+    /// data structures, such as the records of backtraces and live blocks.
     ///
-    /// `dhat` can use heuristics to trim uninteresting frames from the top and
-    /// bottom of backtraces, which makes the output easier to read. It can
-    /// also limit the number of frames, which improves performance.
+    /// These allocations are not included in [`HeapStats`]. On long runs with
+    /// many distinct backtraces or live blocks they can be large, in which
+    /// case the estimate is also printed in the summary.
     ///
-    /// The argument can be specified in several ways.
-    /// - `None`: no backtrace trimming will be performed, and there is no
-    ///   frame count limit. This makes profiling much slower and increases the
-    ///   size of saved data files.
-    /// - `Some(n)`: top and bottom trimming will be performed, and the number
-    ///   of frames will be limited by `n`. Values of `n` less than 4 will be
-    ///   clamped to 4.
-    /// - `Some(usize::MAX)`: top and bottom trimming with be performed, but
-    ///   there is no frame count limit. This makes profiling much slower and
-    ///   increases the size of saved data files.
+    /// # Panics
     ///
-    /// The default value (used if this function is not called) is `Some(10)`.
+    /// Panics if called after a `dhat` assertion has failed, or after
+    /// [`Profiler::force_finish`].
     ///
-    /// The number of frames shown in viewed profiles may differ from the
-    /// number requested here, for two reasons.
-    /// - Inline frames do not count towards this length. In release builds it
-    ///   is common for the number of inline frames to equal or even exceed the
-    ///   number of "real" frames.
-    /// - Backtrace trimming will remove a small number of frames from heap
-    ///   profile backtraces. The number removed will likely be more in a debug
-    ///   build than in a release build.
+    /// # Examples
+    /// ```
+    /// let profiler = dhat::Profiler::new_heap();
+    /// println!("dhat is using about {} bytes", profiler.metadata_bytes());
+    /// # std::mem::forget(profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn metadata_bytes(&self) -> usize {
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+        if self.finished.load(Ordering::Relaxed) {
+            panic!("dhat: getting metadata bytes after the profiler has finished");
+        }
+
+        let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+        match phase {
+            Phase::Ready => unreachable!(),
+            Phase::Running(g) => g.metadata_bytes(),
+            Phase::PostAssert(_) => {
+                panic!("dhat: getting metadata bytes after the profiler has asserted")
+            }
+        }
+    }
+
+    /// Gets the number of blocks allocated per second since profiling
+    /// started, i.e. [`HeapStats::total_blocks`] divided by the elapsed time.
+    /// (For ad hoc profiling, this is the number of events per second.)
+    ///
+    /// # Panics
+    ///
+    /// Panics if called after a `dhat` assertion has failed, or after
+    /// [`Profiler::force_finish`].
     ///
     /// # Examples
     /// ```
-    /// let _profiler = dhat::Profiler::builder().trim_backtraces(None).build();
+    /// let profiler = dhat::Profiler::new_heap();
+    /// let _v = vec![1, 2, 3];
+    /// println!("{:.1} blocks/s", profiler.alloc_rate());
+    /// # std::mem::forget(profiler); // Don't write the file in `cargo tests`
     /// ```
-    pub fn trim_backtraces(mut self, max_frames: Option<usize>) -> Self {
-        self.trim_backtraces = max_frames.map(|m| std::cmp::max(m, 4));
-        self
-    }
+    pub fn alloc_rate(&self) -> f64 {
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+        if self.finished.load(Ordering::Relaxed) {
+            panic!("dhat: getting the allocation rate after the profiler has finished");
+        }
 
-    // For testing purposes only. Useful for seeing what went wrong if a test
-    // fails on CI.
-    #[doc(hidden)]
-    pub fn eprint_json(mut self) -> Self {
-        self.eprint_json = true;
-        self
+        let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+        match phase {
+            Phase::Ready => unreachable!(),
+            Phase::Running(g) => g.alloc_rate(),
+            Phase::PostAssert(_) => {
+                panic!("dhat: getting the allocation rate after the profiler has asserted")
+            }
+        }
     }
 
-    /// Creates a [`Profiler`] from the builder and initiates profiling.
+    /// Serializes the profile data collected so far into a byte buffer, in
+    /// the same format as the saved profile. Unlike dropping the `Profiler`,
+    /// this does not stop profiling, write any files, or print a summary.
+    ///
+    /// This is useful for tests, and for programs that send the profile
+    /// somewhere rather than writing it to a file. The result can be viewed
+    /// with DHAT's viewer once written to a file.
     ///
     /// # Panics
     ///
-    /// Panics if another [`Profiler`] is running.
-    pub fn build(self) -> Profiler {
+    /// Panics if called after a `dhat` assertion has failed, or after
+    /// [`Profiler::force_finish`].
+    ///
+    /// # Examples
+    /// ```
+    /// let profiler = dhat::Profiler::new_heap();
+    /// let v = vec![0u8; 1000];
+    /// let json = profiler.write_to_vec();
+    /// assert!(json.starts_with(b"{"));
+    /// # std::mem::forget(profiler); // Don't write the file in `cargo tests`
+    /// ```
+    pub fn write_to_vec(&self) -> Vec<u8> {
         let ignore_allocs = IgnoreAllocs::new();
         std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+        if self.finished.load(Ordering::Relaxed) {
+            panic!("dhat: writing the profile after the profiler has finished");
+        }
 
         let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
         match phase {
-            Phase::Ready => {
-                let file_name = if let Some(file_name) = self.file_name {
-                    file_name
-                } else if !self.ad_hoc {
-                    PathBuf::from("dhat-heap.json")
-                } else {
-                    PathBuf::from("dhat-ad-hoc.json")
-                };
-                let h = if !self.ad_hoc {
-                    Some(HeapGlobals::new())
-                } else {
-                    None
-                };
-                *phase = Phase::Running(Globals::new(
-                    self.testing,
-                    file_name,
-                    self.trim_backtraces,
-                    self.eprint_json,
-                    h,
-                ));
-            }
-            Phase::Running(_) | Phase::PostAssert => {
-                panic!("dhat: creating a profiler while a profiler is already running")
+            Phase::Ready => unreachable!(),
+            Phase::Running(g) => g.write_to_vec(),
+            Phase::PostAssert(_) => {
+                panic!("dhat: writing the profile after the profiler has asserted")
             }
         }
-        Profiler
     }
-}
-
-// Get a backtrace according to `$g`'s settings. A macro rather than a `Global`
-// method to avoid putting an extra frame into backtraces.
-macro_rules! new_backtrace {
-    ($g:expr) => {{
-        if $g.frames_to_trim.is_none() {
-            // This is the first backtrace from profiling. Work out what we
-            // will be trimming from the top and bottom of all backtraces.
-            // `None` here because we don't want any frame trimming for this
-            // backtrace.
-            let bt = new_backtrace_inner(None, &FxHashMap::default());
-            $g.frames_to_trim = Some(bt.get_frames_to_trim(&$g.start_bt));
-        }
 
-        // Get the backtrace.
-        new_backtrace_inner($g.trim_backtraces, $g.frames_to_trim.as_ref().unwrap())
-    }};
-}
+    /// Resumes profiling after a [`dhat::assert!`](assert) (or related macro)
+    /// has failed, so that later `dhat` assertions can be evaluated and stats
+    /// can be got again. This is useful when failures are caught with
+    /// [`std::panic::catch_unwind`], e.g. by a property testing framework that
+    /// runs many cases with a single [`Profiler`]. It has no effect if no
+    /// assertion has failed.
+    ///
+    /// Nothing is reset, so later assertions see the counts accumulated
+    /// before the failure as well as after it. Allocations made between the
+    /// failure and this call are not recorded. The profile saved on the
+    /// failure is overwritten if another assertion fails.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// let profiler = dhat::Profiler::builder().testing().build();
+    ///
+    /// for case in 0..10 {
+    ///     let res = std::panic::catch_unwind(|| {
+    ///         let _v = vec![0u8; case];
+    ///         dhat::assert!(case < 5);
+    ///     });
+    ///     if res.is_err() {
+    ///         profiler.recover_from_assert();
+    ///     }
+    /// }
+    /// ```
+    pub fn recover_from_assert(&self) {
+        let ignore_allocs = IgnoreAllocs::new();
+        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
 
-// Get a backtrace, possibly trimmed.
-//
-// Note: it's crucial that there only be a single call to `backtrace::trace()`
-// that is used everywhere, so that all traces will have the same backtrace
-// function IPs in their top frames. (With multiple call sites we would have
-// multiple closures, giving multiple instances of `backtrace::trace<F>`, and
-// monomorphisation would put them into different functions in the binary.)
-// Without this, top frame trimming wouldn't work. That's why this is a
-// function (with `inline(never)` just to be safe) rather than a macro like
-// `new_backtrace`. The frame for this function will be removed by top frame
-// trimming.
-#[inline(never)]
-fn new_backtrace_inner(
-    trim_backtraces: Option<usize>,
-    frames_to_trim: &FxHashMap<usize, TB>,
-) -> Backtrace {
-    // Get the backtrace, trimming if necessary at the top and bottom and for
-    // length.
-    let mut frames = Vec::new();
-    backtrace::trace(|frame| {
-        let ip = frame.ip() as usize;
-        if trim_backtraces.is_some() {
-            match frames_to_trim.get(&ip) {
-                Some(TB::Top) => return true,     // ignore frame and continue
-                Some(TB::Bottom) => return false, // ignore frame and stop
-                _ => {}                           // use this frame
+        let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+        *phase = match std::mem::replace(phase, Phase::Ready) {
+            Phase::PostAssert(g) => {
+                HEAP_PROFILING.store(g.heap.is_some() && !g.stopped_early, Ordering::Release);
+                Phase::Running(g)
             }
+            other => other,
+        };
+    }
+
+    /// Stops profiling and writes the output, just like dropping the
+    /// `Profiler` would, but without needing to drop it. This is useful when
+    /// the `Profiler` is hard to drop at a precise point, e.g. because it is
+    /// stored in a `static`. Dropping the `Profiler` afterwards does nothing,
+    /// and calling this function again does nothing.
+    ///
+    /// Once this has been called, a new `Profiler` can be built.
+    ///
+    /// # Examples
+    /// ```
+    /// let profiler = dhat::Profiler::new_heap();
+    ///
+    /// let _v = vec![1, 2, 3, 4];
+    ///
+    /// // Write the profile now, rather than at the end of the scope.
+    /// profiler.force_finish();
+    /// ```
+    pub fn force_finish(&self) {
+        if !self.finished.swap(true, Ordering::Relaxed) {
+            self.drop_inner(None);
         }
+    }
 
-        frames.push(frame.clone().into());
+    // For testing purposes only.
+    #[doc(hidden)]
+    pub fn drop_and_get_memory_output(&mut self) -> String {
+        let mut memory_output = String::new();
+        self.drop_inner(Some(&mut memory_output));
+        memory_output
+    }
+}
 
-        if let Some(max_frames) = trim_backtraces {
-            frames.len() < max_frames // stop if we have enough frames
-        } else {
-            true // continue
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        if !*self.finished.get_mut() {
+            self.drop_inner(None);
         }
-    });
-    Backtrace(frames.into())
+    }
 }
 
-/// A global allocator that tracks allocations and deallocations on behalf of
-/// the [`Profiler`] type.
-///
-/// It must be set as the global allocator (via `#[global_allocator]`) when
-/// doing heap profiling.
-#[derive(Debug)]
-pub struct Alloc;
+// How to turn a backtrace into frame strings outside of `to_json`, where the
+// `Globals` may not be available.
+#[derive(Clone)]
+struct FrameFormat {
+    resolve_symbols: bool,
+    trim_heap_frames: bool,
+    trim_top_matching: Vec<String>,
+    path_components: usize,
+}
 
-unsafe impl GlobalAlloc for Alloc {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let ignore_allocs = IgnoreAllocs::new();
-        if ignore_allocs.was_already_ignoring_allocs {
-            System.alloc(layout)
+impl FrameFormat {
+    // Get the strings for the interesting frames of a backtrace, as they
+    // would appear in the saved profile, apart from any region label.
+    // `frames` must contain the frames of `bt`.
+    fn frame_strings(&self, frames: &FrameTable, bt: &Backtrace) -> Vec<String> {
+        if let Some(s) = bt.location_string(self.path_components) {
+            return vec![s];
+        }
+        if !self.resolve_symbols {
+            return bt
+                .ips
+                .iter()
+                .map(|ip| format!("{:#x}", ip))
+                .chain(bt.truncated_string())
+                .collect();
+        }
+
+        let resolved = Backtrace::resolve_all(frames, [bt]);
+        let first_symbol_to_show = if self.trim_heap_frames {
+            bt.first_heap_symbol_to_show(&resolved, &self.trim_top_matching)
         } else {
-            let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
-            let ptr = System.alloc(layout);
-            if ptr.is_null() {
-                return ptr;
-            }
+            0
+        };
+        bt.symbols(&resolved)
+            .skip(first_symbol_to_show)
+            .map(|(frame, symbol)| Backtrace::frame_to_string(frame, symbol, self.path_components))
+            .chain(bt.truncated_string())
+            .collect()
+    }
 
-            if let Phase::Running(g @ Globals { heap: Some(_), .. }) = phase {
-                let size = layout.size();
-                let bt = new_backtrace!(g);
-                let pp_info_idx = g.get_pp_info(bt, PpInfo::new_heap);
+    // Get the first user frame of `bt`, whose frames must be in `resolved`.
+    // That is the first frame shown in the saved profile that isn't in the
+    // standard library, such as the frames of `alloc::alloc::alloc` and
+    // `Vec::with_capacity`, or in `dhat`. Symbols must be resolved.
+    fn first_user_symbol<'a>(
+        &self,
+        resolved: &'a ResolvedFrames,
+        bt: &'a Backtrace,
+    ) -> Option<(
+        &'a backtrace::BacktraceFrame,
+        &'a backtrace::BacktraceSymbol,
+    )> {
+        let first_symbol_to_show = if self.trim_heap_frames {
+            bt.first_heap_symbol_to_show(resolved, &self.trim_top_matching)
+        } else {
+            0
+        };
+        // The standard library's source paths start with `/rustc/`, which
+        // also catches things like `<u8 as alloc::..::SpecFromElem>`.
+        let is_std = |symbol: &backtrace::BacktraceSymbol| {
+            symbol
+                .filename()
+                .is_some_and(|path| path.starts_with("/rustc/"))
+                || symbol.name().is_some_and(|name| {
+                    let s = format!("{:#}", name);
+                    let s = s.strip_prefix('<').unwrap_or(&s);
+                    // `__rg_` and `__rustc::` are the global allocator shims.
+                    ["alloc::", "core::", "std::", "dhat::", "__rg_", "__rustc::"]
+                        .iter()
+                        .any(|prefix| s.starts_with(prefix))
+                })
+        };
+        bt.symbols(resolved)
+            .skip(first_symbol_to_show)
+            .find(|(_, symbol)| !is_std(symbol))
+    }
 
-                let now = Instant::now();
-                g.record_block(ptr, pp_info_idx, now);
-                g.update_counts_for_alloc(pp_info_idx, size, None, now);
+    // Get the string for the first user frame of `bt` (see
+    // `first_user_symbol`), as it would appear in the saved profile. Falls
+    // back to the first frame shown in the saved profile.
+    fn user_frame_string(&self, frames: &FrameTable, bt: &Backtrace) -> String {
+        if bt.location.is_some() || !self.resolve_symbols {
+            return self
+                .frame_strings(frames, bt)
+                .into_iter()
+                .next()
+                .unwrap_or_else(|| "???".to_string());
+        }
+        let resolved = Backtrace::resolve_all(frames, [bt]);
+        match self.first_user_symbol(&resolved, bt) {
+            Some((frame, symbol)) => {
+                Backtrace::frame_to_string(frame, symbol, self.path_components)
             }
-            ptr
+            None => self
+                .frame_strings(frames, bt)
+                .into_iter()
+                .next()
+                .unwrap_or_else(|| "???".to_string()),
         }
     }
 
-    unsafe fn realloc(&self, old_ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
-        let ignore_allocs = IgnoreAllocs::new();
-        if ignore_allocs.was_already_ignoring_allocs {
-            System.realloc(old_ptr, layout, new_size)
+    // Get the source file of the first user frame of `bt` (see
+    // `first_user_symbol`), whose frames must be in `resolved`. "???" if it
+    // is unknown.
+    fn top_file_string(&self, resolved: &ResolvedFrames, bt: &Backtrace) -> String {
+        let path = if let Some(location) = bt.location {
+            Some(Path::new(location.file()))
+        } else if self.resolve_symbols {
+            self.first_user_symbol(resolved, bt)
+                .and_then(|(_, symbol)| symbol.filename())
         } else {
-            let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
-            let new_ptr = System.realloc(old_ptr, layout, new_size);
-            if new_ptr.is_null() {
-                return new_ptr;
-            }
+            None
+        };
+        match path {
+            Some(path) => trim_path(path, self.path_components).display().to_string(),
+            None => "???".to_string(),
+        }
+    }
+}
 
-            if let Phase::Running(g @ Globals { heap: Some(_), .. }) = phase {
-                let old_size = layout.size();
-                let delta = Delta::new(old_size, new_size);
+// Unresolved frames, keyed by IP. See `Globals::frames`.
+type FrameTable = FxHashMap<usize, backtrace::BacktraceFrame>;
 
-                if delta.shrinking {
-                    // Total bytes is coming down from a possible peak.
-                    g.check_for_global_peak();
-                }
+// Resolved frames, keyed by IP. See `Backtrace::resolve_all`.
+type ResolvedFrames = FxHashMap<usize, backtrace::BacktraceFrame>;
 
-                // Remove the record of the existing live block and get the
-                // `PpInfo`. If it's not in the live block table, it must
-                // have been allocated before `TRI_GLOBALS` was set up, and
-                // we treat it like an `alloc`.
-                let h = g.heap.as_mut().unwrap();
-                let live_block = h.live_blocks.remove(&(old_ptr as usize));
-                let (pp_info_idx, delta) = if let Some(live_block) = live_block {
-                    (live_block.pp_info_idx, Some(delta))
-                } else {
-                    let bt = new_backtrace!(g);
-                    let pp_info_idx = g.get_pp_info(bt, PpInfo::new_heap);
-                    (pp_info_idx, None)
-                };
+// The IPs of a backtrace's frames, plus what else distinguishes it from
+// other backtraces.
+#[derive(Clone, Debug)]
+struct Backtrace {
+    // The IPs, innermost first. The frames themselves are kept in a
+    // `FrameTable`.
+    ips: Box<[usize]>,
 
-                let now = Instant::now();
-                g.record_block(new_ptr, pp_info_idx, now);
-                g.update_counts_for_alloc(pp_info_idx, new_size, delta, now);
-            }
-            new_ptr
-        }
-    }
+    // The label of the innermost `Region` live when the backtrace was taken.
+    region: Option<&'static str>,
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let ignore_allocs = IgnoreAllocs::new();
-        if ignore_allocs.was_already_ignoring_allocs {
-            System.dealloc(ptr, layout)
-        } else {
-            let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
-            System.dealloc(ptr, layout);
+    // A hash of the other fields, computed once on creation, because
+    // rehashing deep stacks on every lookup in `Globals::backtraces` is
+    // expensive.
+    hash: u64,
 
-            if let Phase::Running(g @ Globals { heap: Some(_), .. }) = phase {
-                let size = layout.size();
+    // The caller location from `ad_hoc_event_here`, which stands in for the
+    // frames; a backtrace with a location has no frames.
+    location: Option<&'static Location<'static>>,
 
-                // Remove the record of the live block and get the
-                // `PpInfo`. If it's not in the live block table, it must
-                // have been allocated before `TRI_GLOBALS` was set up, and
-                // we just ignore it.
-                let h = g.heap.as_mut().unwrap();
-                if let Some(LiveBlock {
-                    pp_info_idx,
-                    allocation_instant,
-                }) = h.live_blocks.remove(&(ptr as usize))
-                {
-                    // Total bytes is coming down from a possible peak.
-                    g.check_for_global_peak();
+    // Something else that distinguishes backtraces, if anything.
+    tag: Option<Tag>,
 
-                    let alloc_duration = allocation_instant.elapsed();
-                    g.update_counts_for_dealloc(pp_info_idx, size, alloc_duration);
-                }
-            }
-        }
+    // Was getting the backtrace stopped early by
+    // `ProfilerBuilder::backtrace_budget`?
+    truncated: bool,
+}
+
+// Something other than the frames that distinguishes PPs. It appears as a
+// synthetic innermost frame, so the viewer groups everything with the same
+// tag together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Tag {
+    // The size class of the blocks, for `ProfilerBuilder::split_by_size_class`.
+    // Size class `i` covers sizes from 2^i up to (but not including)
+    // 2^(i+1) bytes.
+    SizeClass(u32),
+
+    // The label from `ad_hoc_event_labeled`.
+    Label(&'static str),
+}
+
+impl Tag {
+    // The size class of `size`, which must be non-zero.
+    fn size_class(size: usize) -> Self {
+        Tag::SizeClass(usize::BITS - 1 - size.leading_zeros())
     }
 }
 
-/// Registers an event during ad hoc profiling.
-///
-/// The meaning of the weight argument is determined by the user. A call to
-/// this function has no effect if a [`Profiler`] is not running or not doing ad
-/// hoc profiling.
-pub fn ad_hoc_event(weight: usize) {
-    let ignore_allocs = IgnoreAllocs::new();
-    std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+impl Backtrace {
+    fn new(ips: Box<[usize]>, label: Option<&'static str>) -> Self {
+        Backtrace::new_inner(ips, label, None)
+    }
 
-    let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
-    if let Phase::Running(g @ Globals { heap: None, .. }) = phase {
-        let bt = new_backtrace!(g);
-        let pp_info_idx = g.get_pp_info(bt, PpInfo::new_ad_hoc);
+    // A single-frame "backtrace" for a caller location, which is much cheaper
+    // to get than a real backtrace.
+    fn from_location(location: &'static Location<'static>, label: Option<&'static str>) -> Self {
+        Backtrace::new_inner(Box::default(), label, Some(location))
+    }
 
-        // Update counts.
-        g.update_counts_for_ad_hoc_event(pp_info_idx, weight);
+    fn new_inner(
+        ips: Box<[usize]>,
+        region: Option<&'static str>,
+        location: Option<&'static Location<'static>>,
+    ) -> Self {
+        let mut hasher = rustc_hash::FxHasher::default();
+        ips.hash(&mut hasher);
+        region.hash(&mut hasher);
+        location.hash(&mut hasher);
+        let hash = hasher.finish();
+        Backtrace {
+            ips,
+            region,
+            hash,
+            location,
+            tag: None,
+            truncated: false,
+        }
     }
-}
 
-impl Profiler {
-    fn drop_inner(&mut self, memory_output: Option<&mut String>) {
-        let ignore_allocs = IgnoreAllocs::new();
-        std::assert!(!ignore_allocs.was_already_ignoring_allocs);
+    // Distinguish this backtrace from others with the same frames.
+    fn with_tag(mut self, tag: Tag) -> Self {
+        let mut hasher = rustc_hash::FxHasher::default();
+        self.hash.hash(&mut hasher);
+        tag.hash(&mut hasher);
+        self.hash = hasher.finish();
+        self.tag = Some(tag);
+        self
+    }
 
-        let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
-        match std::mem::replace(phase, Phase::Ready) {
-            Phase::Ready => unreachable!(),
-            Phase::Running(g) => {
-                if !g.testing {
-                    g.finish(memory_output)
-                }
+    // Mark this backtrace as incomplete, distinguishing it from an otherwise
+    // identical complete one.
+    fn truncated(mut self) -> Self {
+        let mut hasher = rustc_hash::FxHasher::default();
+        self.hash.hash(&mut hasher);
+        true.hash(&mut hasher);
+        self.hash = hasher.finish();
+        self.truncated = true;
+        self
+    }
+
+    // The synthetic outermost frame string marking an incomplete backtrace,
+    // if this is one.
+    fn truncated_string(&self) -> Option<String> {
+        self.truncated
+            .then(|| "[truncated: backtrace budget exceeded]".to_string())
+    }
+
+    // The synthetic frame string for the tag, if there is one.
+    fn tag_string(&self) -> Option<String> {
+        self.tag.map(|tag| match tag {
+            Tag::SizeClass(size_class) => {
+                let lo = 1usize << size_class;
+                let hi = lo.wrapping_shl(1).wrapping_sub(1);
+                format!("[size class: {}-{} bytes]", lo, hi)
+            }
+            Tag::Label(label) => format!("[label: {}]", label),
+        })
+    }
+
+    // The frame string for the caller location, if there is one. Like
+    // `frame_to_string`, but there is no IP or symbol name.
+    fn location_string(&self, path_components: usize) -> Option<String> {
+        self.location.map(|location| {
+            format!(
+                "{}:{}:{}",
+                trim_path(Path::new(location.file()), path_components).display(),
+                location.line(),
+                location.column(),
+            )
+        })
+    }
+
+    // Resolve the symbols for every frame in `bts`, getting the frames from
+    // `frames`. Backtraces often share many frames, e.g. those of common
+    // library functions, so each unique IP is resolved only once.
+    fn resolve_all<'a, I>(frames: &FrameTable, bts: I) -> ResolvedFrames
+    where
+        I: IntoIterator<Item = &'a Backtrace>,
+    {
+        let mut resolved = ResolvedFrames::default();
+        for bt in bts {
+            for ip in bt.ips.iter() {
+                resolved.entry(*ip).or_insert_with(|| frames[ip].clone());
             }
-            Phase::PostAssert => {}
         }
+        let mut frames: Vec<_> = resolved.values_mut().collect();
+        Backtrace::resolve_frames(&mut frames);
+        resolved
     }
 
-    // For testing purposes only.
-    #[doc(hidden)]
-    pub fn drop_and_get_memory_output(&mut self) -> String {
-        let mut memory_output = String::new();
-        self.drop_inner(Some(&mut memory_output));
-        memory_output
+    #[cfg(not(feature = "parallel-resolve"))]
+    fn resolve_frames(frames: &mut [&mut backtrace::BacktraceFrame]) {
+        for frame in frames.iter_mut() {
+            frame.resolve();
+        }
     }
-}
 
-impl Drop for Profiler {
-    fn drop(&mut self) {
-        self.drop_inner(None);
+    // Spread the work across one thread per available core. The caller may
+    // be holding `TRI_GLOBALS`, so each worker must ignore its own
+    // allocations; otherwise it would block on the lock and the join would
+    // deadlock.
+    #[cfg(feature = "parallel-resolve")]
+    fn resolve_frames(frames: &mut [&mut backtrace::BacktraceFrame]) {
+        let n_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        if n_threads == 1 || frames.len() < 2 {
+            for frame in frames.iter_mut() {
+                frame.resolve();
+            }
+            return;
+        }
+
+        let chunk_size = frames.len().div_ceil(n_threads);
+        std::thread::scope(|s| {
+            for chunk in frames.chunks_mut(chunk_size) {
+                s.spawn(move || {
+                    let _ignore_allocs = IgnoreAllocs::new();
+                    for frame in chunk.iter_mut() {
+                        frame.resolve();
+                    }
+                });
+            }
+        });
     }
-}
 
-// A wrapper for `backtrace::Backtrace` that implements `Eq` and `Hash`, which
-// only look at the frame IPs. This assumes that any two
-// `backtrace::Backtrace`s with the same frame IPs are equivalent.
-#[derive(Debug)]
-struct Backtrace(backtrace::Backtrace);
+    // Iterate over the symbols of this backtrace, innermost first, using
+    // frames from `resolve_all`. A frame can have multiple symbols if
+    // functions have been inlined into it.
+    fn symbols<'a>(
+        &'a self,
+        resolved: &'a ResolvedFrames,
+    ) -> impl Iterator<
+        Item = (
+            &'a backtrace::BacktraceFrame,
+            &'a backtrace::BacktraceSymbol,
+        ),
+    > {
+        self.ips.iter().flat_map(move |ip| {
+            let frame = &resolved[ip];
+            frame.symbols().iter().map(move |symbol| (frame, symbol))
+        })
+    }
 
-impl Backtrace {
     // The top frame symbols in a backtrace (those relating to backtracing
     // itself) are typically the same, and look something like this (Mac or
     // Linux release build, Dec 2021):
@@ -1418,8 +6792,14 @@ impl Backtrace {
     // can be discarded.
     fn get_frames_to_trim(&self, start_bt: &Backtrace) -> FxHashMap<usize, TB> {
         let mut frames_to_trim = FxHashMap::default();
-        let frames1 = self.0.frames();
-        let frames2 = start_bt.0.frames();
+        let frames1 = &self.ips;
+        let frames2 = &start_bt.ips;
+
+        // A backtrace can be empty on targets where unwinding doesn't work.
+        // There is nothing to trim then.
+        if frames1.is_empty() || frames2.is_empty() {
+            return frames_to_trim;
+        }
 
         let (mut i1, mut i2) = (0, 0);
         loop {
@@ -1430,10 +6810,10 @@ impl Backtrace {
                 frames_to_trim.retain(|_, v| *v == TB::Bottom);
                 break;
             }
-            if frames1[i1].ip() != frames2[i2].ip() {
+            if frames1[i1] != frames2[i2] {
                 break;
             }
-            frames_to_trim.insert(frames1[i1].ip() as usize, TB::Top);
+            frames_to_trim.insert(frames1[i1], TB::Top);
             i1 += 1;
             i2 += 1;
         }
@@ -1447,10 +6827,10 @@ impl Backtrace {
                 frames_to_trim.retain(|_, v| *v == TB::Top);
                 break;
             }
-            if frames1[i1].ip() != frames2[i2].ip() {
+            if frames1[i1] != frames2[i2] {
                 break;
             }
-            frames_to_trim.insert(frames1[i1].ip() as usize, TB::Bottom);
+            frames_to_trim.insert(frames1[i1], TB::Bottom);
             i1 -= 1;
             i2 -= 1;
         }
@@ -1480,7 +6860,9 @@ impl Backtrace {
     // previously distinct traces becoming the same, which makes dh_view.html
     // abort. If that ever happens, look to see if something is going wrong
     // here.
-    fn first_heap_symbol_to_show(&self) -> usize {
+    //
+    // `extra` holds further prefixes, from `ProfilerBuilder::trim_top_matching`.
+    fn first_heap_symbol_to_show(&self, resolved: &ResolvedFrames, extra: &[String]) -> usize {
         // Examples of symbols that this search will match:
         // - alloc::alloc::{alloc,realloc,exchange_malloc}
         // - <alloc::alloc::Global as core::alloc::Allocator>::{allocate,grow}
@@ -1489,12 +6871,31 @@ impl Backtrace {
         //
         // Be careful when changing this, because to do it properly requires
         // testing both debug and release builds on multiple platforms.
-        self.first_symbol_to_show(|s| {
+        let first = self.first_symbol_to_show(resolved, |s| {
             s.starts_with("alloc::alloc::")
                 || s.starts_with("<alloc::alloc::")
                 || s.starts_with("<dhat::Alloc")
                 || s.starts_with("__rg_")
-        })
+        });
+
+        // Unlike the built-in prefixes, which are for frames in or near the
+        // global allocator, user-provided prefixes are for frames in wrapper
+        // code that should be hidden entirely, so the matching frame is
+        // trimmed as well.
+        if extra.is_empty() {
+            return first;
+        }
+        let symbols: Vec<_> = self.symbols(resolved).map(|(_, symbol)| symbol).collect();
+        let last_extra = symbols.iter().rposition(|symbol| {
+            symbol.name().is_some_and(|name| {
+                let s = format!("{:#}", name);
+                extra.iter().any(|prefix| s.starts_with(prefix.as_str()))
+            })
+        });
+        match last_extra {
+            Some(i) => std::cmp::max(first, i + 1),
+            None => first,
+        }
     }
 
     // The top frame symbols in a trimmed ad hoc profiling backtrace are always
@@ -1508,14 +6909,9 @@ impl Backtrace {
     }
 
     // Find the first symbol to show, based on the predicate `p`.
-    fn first_symbol_to_show<P: Fn(&str) -> bool>(&self, p: P) -> usize {
+    fn first_symbol_to_show<P: Fn(&str) -> bool>(&self, resolved: &ResolvedFrames, p: P) -> usize {
         // Get the symbols into a vector so we can reverse iterate over them.
-        let symbols: Vec<_> = self
-            .0
-            .frames()
-            .iter()
-            .flat_map(|f| f.symbols().iter())
-            .collect();
+        let symbols: Vec<_> = self.symbols(resolved).map(|(_, symbol)| symbol).collect();
 
         for (i, symbol) in symbols.iter().enumerate().rev() {
             // Use `{:#}` to print the "alternate" form of the symbol name,
@@ -1531,18 +6927,27 @@ impl Backtrace {
 
     // Useful for debugging.
     #[allow(dead_code)]
-    fn eprint(&self) {
-        for frame in self.0.frames().iter() {
-            for symbol in frame.symbols().iter() {
-                eprintln!("{}", Backtrace::frame_to_string(frame, symbol));
-            }
+    fn eprint(&self, frames: &FrameTable) {
+        let resolved = Backtrace::resolve_all(frames, [self]);
+        for (frame, symbol) in self.symbols(&resolved) {
+            eprintln!("{}", Backtrace::frame_to_string(frame, symbol, 3));
         }
     }
 
     fn frame_to_string(
         frame: &backtrace::BacktraceFrame,
         symbol: &backtrace::BacktraceSymbol,
+        path_components: usize,
     ) -> String {
+        if symbol.filename().is_none() {
+            // Without debug info there is no file or line, so show the
+            // symbol's module path instead, which is better than nothing.
+            let name = symbol
+                .name()
+                .map_or_else(|| "???".to_string(), |name| format!("{:#}", name));
+            let module = symbol_module(&name).unwrap_or("???");
+            return format!("{:?}: {} ({})", frame.ip(), name, module);
+        }
         format!(
             // Use `{:#}` to print the "alternate" form of the symbol name,
             // which omits the trailing hash (e.g. `::ha68e4508a38cc95a`).
@@ -1550,7 +6955,7 @@ impl Backtrace {
             frame.ip(),
             symbol.name().unwrap_or_else(|| SymbolName::new(b"???")),
             match symbol.filename() {
-                Some(path) => trim_path(path),
+                Some(path) => trim_path(path, path_components),
                 None => Path::new("???"),
             }
             .display(),
@@ -1558,23 +6963,39 @@ impl Backtrace {
             symbol.colno().unwrap_or(0),
         )
     }
+
+    // Like `frame_to_string`, but without the IP and column number, for
+    // `ProfilerBuilder::merge_by_line`.
+    fn frame_to_line_string(symbol: &backtrace::BacktraceSymbol, path_components: usize) -> String {
+        let name = symbol
+            .name()
+            .map_or_else(|| "???".to_string(), |name| format!("{:#}", name));
+        match symbol.filename() {
+            Some(path) => format!(
+                "{} ({}:{})",
+                name,
+                trim_path(path, path_components).display(),
+                symbol.lineno().unwrap_or(0),
+            ),
+            None => format!("{} ({})", name, symbol_module(&name).unwrap_or("???")),
+        }
+    }
 }
 
 impl PartialEq for Backtrace {
     fn eq(&self, other: &Self) -> bool {
-        let mut frames1 = self.0.frames().iter();
-        let mut frames2 = other.0.frames().iter();
-        loop {
-            let ip1 = frames1.next().map(|f| f.ip());
-            let ip2 = frames2.next().map(|f| f.ip());
-            if ip1 != ip2 {
-                return false;
-            }
-            if ip1 == None {
-                return true;
-            }
-            // Otherwise, continue.
+        // Comparing the hashes is a cheap way of rejecting most non-equal
+        // backtraces. Equal hashes can collide, so the IPs must still be
+        // compared.
+        if self.hash != other.hash
+            || self.region != other.region
+            || self.location != other.location
+            || self.tag != other.tag
+            || self.truncated != other.truncated
+        {
+            return false;
         }
+        self.ips == other.ips
     }
 }
 
@@ -1582,21 +7003,51 @@ impl Eq for Backtrace {}
 
 impl Hash for Backtrace {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        for frame in self.0.frames().iter() {
-            frame.ip().hash(state);
+        state.write_u64(self.hash);
+    }
+}
+
+// Gets the module path from a demangled symbol name, i.e. everything before
+// the final `::` that isn't within generic arguments (e.g. `a::b` for
+// `a::b::f::<c::D>`). Returns `None` if there isn't one.
+fn symbol_module(name: &str) -> Option<&str> {
+    // The positions of the `::` separators outside generic arguments. The
+    // `>` in `->` (in function types) doesn't close generic arguments.
+    let bytes = name.as_bytes();
+    let mut seps = vec![];
+    let mut depth = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'<' => depth += 1,
+            b'>' if i == 0 || bytes[i - 1] != b'-' => depth -= 1,
+            b':' if depth == 0 && bytes.get(i + 1) == Some(&b':') => {
+                seps.push(i);
+                i += 1;
+            }
+            _ => {}
         }
+        i += 1;
     }
+
+    let mut seps = seps.into_iter().rev();
+    let mut last = seps.next()?;
+    // A trailing `::<...>` is the function's generic arguments, not a path
+    // segment.
+    if bytes.get(last + 2) == Some(&b'<') {
+        last = seps.next()?;
+    }
+    Some(&name[..last])
 }
 
-// Trims a path with more than three components down to three (e.g.
-// `/aa/bb/cc/dd.rs` becomes `bb/cc/dd.rs`), otherwise returns `path`
+// Trims a path with more than `n` components down to `n` (e.g. with `n` equal
+// to 3, `/aa/bb/cc/dd.rs` becomes `bb/cc/dd.rs`), otherwise returns `path`
 // unchanged.
-fn trim_path(path: &Path) -> &Path {
-    const N: usize = 3;
+fn trim_path(path: &Path, n: usize) -> &Path {
     let len = path.components().count();
-    if len > N {
+    if len > n {
         let mut c = path.components();
-        c.nth(len - (N + 1));
+        c.nth(len - (n + 1));
         c.as_path()
     } else {
         path
@@ -1619,26 +7070,168 @@ pub struct HeapStats {
     /// Number of bytes currently allocated.
     pub curr_bytes: usize,
 
+    /// Number of blocks freed over the entire run. A reallocation counts as
+    /// freeing the old block (and allocating a new one, in `total_blocks`).
+    pub freed_blocks: u64,
+
+    /// Number of bytes freed over the entire run, including the old sizes of
+    /// reallocated blocks. `total_bytes - freed_bytes` equals `curr_bytes`,
+    /// except when blocks allocated before profiling started are freed or
+    /// reallocated.
+    pub freed_bytes: u64,
+
     /// Number of blocks (a.k.a. allocations) allocated at the global peak,
-    /// i.e. when `curr_bytes` peaked.
+    /// i.e. when `curr_bytes` peaked (or `curr_blocks`, with
+    /// [`PeakMetric::Blocks`]).
     pub max_blocks: usize,
 
     /// Number of bytes allocated at the global peak, i.e. when `curr_bytes`
-    /// peaked.
+    /// peaked (or `curr_blocks`, with [`PeakMetric::Blocks`]).
     pub max_bytes: usize,
+
+    /// Number of distinct program points (i.e. unique backtraces) at which
+    /// allocations have occurred.
+    pub pp_count: usize,
+
+    /// Number of allocations (including reallocations) that failed because
+    /// the system allocator returned null, e.g. via [`Vec::try_reserve`].
+    pub failed_blocks: u64,
+
+    /// Number of reallocations of blocks allocated during the run. These are
+    /// also counted in `total_blocks`.
+    pub realloc_blocks: u64,
+
+    /// Number of bytes in the new blocks of the reallocations counted in
+    /// `realloc_blocks`, i.e. an upper bound on the bytes copied by them.
+    pub realloc_bytes_moved: u64,
+
+    /// Number of the reallocations counted in `realloc_blocks` that kept the
+    /// block at the same address, so no data was copied.
+    pub realloc_in_place: u64,
+
+    /// Number of the reallocations counted in `realloc_blocks` that moved the
+    /// block to a new address, copying its data. Pre-sizing containers can
+    /// avoid these.
+    pub realloc_moved: u64,
+
+    /// Number of zero-sized allocations. These are not counted in any of the
+    /// other fields, because they don't use any memory. (Rust's standard
+    /// collections don't make such allocations, but other code using the
+    /// global allocator directly might.)
+    pub zst_allocations: u64,
+
+    /// Size in bytes of the largest single block allocated, including by
+    /// reallocation. [`Profiler::largest_block_site`] gives where it was
+    /// allocated.
+    pub largest_block: usize,
 }
 
-/// Stats from ad hoc profiling.
+/// A recorded allocation, as passed to the callback given to
+/// [`ProfilerBuilder::on_alloc`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AllocEvent {
+    /// Size of the block, in bytes. For a reallocation, this is the new size.
+    pub size: usize,
+
+    /// Whether this is a reallocation of a block that was already recorded.
+    pub is_realloc: bool,
+
+    /// An index identifying the program point (i.e. unique backtrace) of the
+    /// allocation. Indices are assigned from zero, in the order in which the
+    /// program points are first seen. A reallocated block keeps the index
+    /// of its original allocation.
+    pub pp_index: usize,
+}
+
+/// Information about a block that is currently allocated, as returned by
+/// [`Profiler::live_blocks`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LiveBlockInfo {
+    /// Size of the block, in bytes.
+    pub size: usize,
+
+    /// Time since the block was allocated (or last reallocated).
+    pub age: Duration,
+
+    /// The first interesting frame of the backtrace at which the block was
+    /// allocated, in the same form as in the saved profile.
+    pub top_frame: String,
+}
+
+/// Information about a program point (i.e. unique backtrace), as returned by
+/// [`Profiler::iter_pps`].
+///
+/// For ad hoc profiling, `total_bytes` and `total_blocks` are the total
+/// weight (excluding fractional weights from [`ad_hoc_event_f64`]) and number
+/// of events, and the other counts are zero.
 #[derive(Clone, Debug, PartialEq, Eq)]
 #[non_exhaustive]
+pub struct PpReport {
+    /// Number of bytes allocated over the entire run.
+    pub total_bytes: u64,
+
+    /// Number of blocks (a.k.a. allocations) allocated over the entire run.
+    pub total_blocks: u64,
+
+    /// Number of bytes allocated at this program point's own peak.
+    pub max_bytes: usize,
+
+    /// Number of bytes allocated at the global peak, i.e. when the total
+    /// number of allocated bytes peaked.
+    pub at_tgmax_bytes: usize,
+
+    /// Number of bytes currently allocated.
+    pub curr_bytes: usize,
+
+    /// The number of freed blocks in each lifetime bucket, if requested with
+    /// [`ProfilerBuilder::lifetime_histogram`]; otherwise empty. Element `i`
+    /// counts blocks that lived for at least 2<sup>i</sup> and less than
+    /// 2<sup>i+1</sup> nanoseconds. Trailing empty buckets are omitted.
+    pub lifetime_histogram: Vec<u64>,
+
+    /// The number of times a block was freed, leaving no live blocks from
+    /// this program point, in the same size class as the previous such block, if
+    /// requested with [`ProfilerBuilder::detect_thrashing`]; otherwise zero.
+    pub thrash_cycles: u64,
+
+    /// The interesting frames of the backtrace, innermost first, in the same
+    /// form as in the saved profile.
+    pub frames: Vec<String>,
+}
+
+/// Stats from ad hoc profiling.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
 pub struct AdHocStats {
     /// Number of events recorded for the entire run.
     pub total_events: u64,
 
-    /// Number of units recorded for the entire run.
+    /// Number of units recorded for the entire run by [`ad_hoc_event`].
     pub total_units: u64,
+
+    /// Number of units recorded for the entire run by [`ad_hoc_event_f64`].
+    pub total_units_f64: f64,
+
+    /// Number of distinct program points (i.e. unique backtraces) at which
+    /// events have been recorded.
+    pub pp_count: usize,
 }
 
+// `total_units_f64` is compared bitwise, so that this is an equivalence
+// relation and `Eq` can be implemented.
+impl PartialEq for AdHocStats {
+    fn eq(&self, other: &Self) -> bool {
+        self.total_events == other.total_events
+            && self.total_units == other.total_units
+            && self.total_units_f64.to_bits() == other.total_units_f64.to_bits()
+            && self.pp_count == other.pp_count
+    }
+}
+
+impl Eq for AdHocStats {}
+
 impl HeapStats {
     /// Gets the current heap stats.
     ///
@@ -1656,11 +7249,191 @@ impl HeapStats {
                 panic!("dhat: getting heap stats when no profiler is running")
             }
             Phase::Running(g) => g.get_heap_stats(),
-            Phase::PostAssert => {
+            Phase::PostAssert(_) => {
                 panic!("dhat: getting heap stats after the profiler has asserted")
             }
         }
     }
+
+    /// Gets the current heap stats, or `None` if they can't be got without
+    /// risking a deadlock, or if [`try_heap_stats`] would return `None`.
+    ///
+    /// Unlike [`HeapStats::get`], this never panics, and it is safe to call
+    /// from code that runs while `dhat` itself is active on the current
+    /// thread, such as the condition of a [`dhat::assert!`](assert), where
+    /// `dhat` may already hold its internal lock. In that case it returns
+    /// `None` instead of deadlocking. The lock may still be held briefly by another
+    /// thread, in which case this waits for it.
+    ///
+    /// # Examples
+    /// ```
+    /// if let Some(stats) = dhat::HeapStats::try_get() {
+    ///     println!("{} bytes are allocated", stats.curr_bytes);
+    /// }
+    /// ```
+    pub fn try_get() -> Option<Self> {
+        let ignore_allocs = IgnoreAllocs::new();
+        if ignore_allocs.was_already_ignoring_allocs {
+            return None;
+        }
+
+        let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+        match phase {
+            Phase::Running(g) => g.try_get_heap_stats(),
+            Phase::Ready | Phase::PostAssert(_) => None,
+        }
+    }
+
+    /// Gets the average size of the allocated blocks, i.e. `total_bytes`
+    /// divided by `total_blocks`, or zero if no blocks have been allocated.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().testing().build();
+    ///
+    /// let _v = vec![1, 2, 3];
+    /// println!("{:.1} bytes", dhat::HeapStats::get().avg_alloc_size());
+    /// ```
+    pub fn avg_alloc_size(&self) -> f64 {
+        if self.total_blocks == 0 {
+            0.0
+        } else {
+            self.total_bytes as f64 / self.total_blocks as f64
+        }
+    }
+
+    /// Produces a human-readable table comparing these stats with `other`,
+    /// showing both values and the signed change from `self` to `other` for
+    /// every field. Useful for making test failure messages actionable.
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().testing().build();
+    ///
+    /// let before = dhat::HeapStats::get();
+    /// let _v = vec![1, 2, 3];
+    /// let after = dhat::HeapStats::get();
+    /// println!("{}", before.diff(&after));
+    /// ```
+    pub fn diff(&self, other: &HeapStats) -> String {
+        let rows: [(&str, u64, u64); 16] = [
+            ("total_blocks", self.total_blocks, other.total_blocks),
+            ("total_bytes", self.total_bytes, other.total_bytes),
+            (
+                "curr_blocks",
+                self.curr_blocks as u64,
+                other.curr_blocks as u64,
+            ),
+            (
+                "curr_bytes",
+                self.curr_bytes as u64,
+                other.curr_bytes as u64,
+            ),
+            ("freed_blocks", self.freed_blocks, other.freed_blocks),
+            ("freed_bytes", self.freed_bytes, other.freed_bytes),
+            (
+                "max_blocks",
+                self.max_blocks as u64,
+                other.max_blocks as u64,
+            ),
+            ("max_bytes", self.max_bytes as u64, other.max_bytes as u64),
+            ("pp_count", self.pp_count as u64, other.pp_count as u64),
+            ("failed_blocks", self.failed_blocks, other.failed_blocks),
+            ("realloc_blocks", self.realloc_blocks, other.realloc_blocks),
+            (
+                "realloc_bytes_moved",
+                self.realloc_bytes_moved,
+                other.realloc_bytes_moved,
+            ),
+            (
+                "realloc_in_place",
+                self.realloc_in_place,
+                other.realloc_in_place,
+            ),
+            ("realloc_moved", self.realloc_moved, other.realloc_moved),
+            (
+                "zst_allocations",
+                self.zst_allocations,
+                other.zst_allocations,
+            ),
+            (
+                "largest_block",
+                self.largest_block as u64,
+                other.largest_block as u64,
+            ),
+        ];
+
+        let mut s = format!(
+            "{:<20} {:>20} {:>20} {:>21}\n",
+            "field", "self", "other", "delta"
+        );
+        for (name, a, b) in rows {
+            let delta = i128::from(b) - i128::from(a);
+            s.push_str(&format!(
+                "{:<20} {:>20} {:>20} {:>+21}\n",
+                name, a, b, delta
+            ));
+        }
+        s
+    }
+
+    /// Gets the signed change in the main counts from `baseline` to these
+    /// stats, for assertions that are robust to unrelated changes, e.g. "this
+    /// operation does at most 2 allocations". Use it with
+    /// [`dhat::assert_delta_le!`](assert_delta_le) and
+    /// [`dhat::assert_delta_eq!`](assert_delta_eq).
+    ///
+    /// # Examples
+    /// ```
+    /// let _profiler = dhat::Profiler::builder().testing().build();
+    ///
+    /// let baseline = dhat::HeapStats::get();
+    /// let _v = vec![1, 2, 3];
+    /// let delta = dhat::HeapStats::get().delta_from(&baseline);
+    /// dhat::assert_delta_le!(delta, total_blocks, 2);
+    /// ```
+    pub fn delta_from(&self, baseline: &HeapStats) -> HeapStatsDelta {
+        // Wrapping then casting gives the right signed result, so long as
+        // the change fits in an `i64`.
+        let delta = |a: u64, b: u64| a.wrapping_sub(b) as i64;
+        HeapStatsDelta {
+            total_blocks: delta(self.total_blocks, baseline.total_blocks),
+            total_bytes: delta(self.total_bytes, baseline.total_bytes),
+            curr_blocks: delta(self.curr_blocks as u64, baseline.curr_blocks as u64),
+            curr_bytes: delta(self.curr_bytes as u64, baseline.curr_bytes as u64),
+            max_blocks: delta(self.max_blocks as u64, baseline.max_blocks as u64),
+            max_bytes: delta(self.max_bytes as u64, baseline.max_bytes as u64),
+            pp_count: delta(self.pp_count as u64, baseline.pp_count as u64),
+        }
+    }
+}
+
+/// The change in heap stats from a baseline, as returned by
+/// [`HeapStats::delta_from`]. Each field is the signed change in the
+/// [`HeapStats`] field of the same name.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HeapStatsDelta {
+    /// Change in the number of blocks allocated over the entire run.
+    pub total_blocks: i64,
+
+    /// Change in the number of bytes allocated over the entire run.
+    pub total_bytes: i64,
+
+    /// Change in the number of blocks currently allocated.
+    pub curr_blocks: i64,
+
+    /// Change in the number of bytes currently allocated.
+    pub curr_bytes: i64,
+
+    /// Change in the number of blocks allocated at the global peak.
+    pub max_blocks: i64,
+
+    /// Change in the number of bytes allocated at the global peak.
+    pub max_bytes: i64,
+
+    /// Change in the number of distinct program points.
+    pub pp_count: i64,
 }
 
 impl AdHocStats {
@@ -1680,7 +7453,7 @@ impl AdHocStats {
                 panic!("dhat: getting ad hoc stats when no profiler is running")
             }
             Phase::Running(g) => g.get_ad_hoc_stats(),
-            Phase::PostAssert => {
+            Phase::PostAssert(_) => {
                 panic!("dhat: getting ad hoc stats after the profiler has asserted")
             }
         }
@@ -1700,7 +7473,8 @@ where
     let ignore_allocs = IgnoreAllocs::new();
     std::assert!(!ignore_allocs.was_already_ignoring_allocs);
 
-    let phase: &mut Phase<Globals> = &mut TRI_GLOBALS.lock();
+    let mut guard = TRI_GLOBALS.lock();
+    let phase: &mut Phase<Globals> = &mut guard;
     match phase {
         Phase::Ready => panic!("dhat: asserting when no profiler is running"),
         Phase::Running(g) => {
@@ -1710,19 +7484,40 @@ where
             if cond() {
                 return false;
             }
+            if g.soft_asserts {
+                // Failure, but profiling continues. Only the first failure
+                // saves the profile.
+                let out = if g.soft_assert_failed {
+                    None
+                } else {
+                    g.soft_assert_failed = true;
+                    Some(g.snapshot())
+                };
+                drop(guard);
+                if let Some(out) = out {
+                    out.print();
+                }
+                return true;
+            }
         }
-        Phase::PostAssert => panic!("dhat: asserting after the profiler has asserted"),
+        Phase::PostAssert(_) => panic!("dhat: asserting after the profiler has asserted"),
     }
 
-    // Failure.
-    match std::mem::replace(phase, Phase::PostAssert) {
+    // Failure. The data is saved without consuming the `Globals`, so that
+    // profiling can be resumed by `Profiler::recover_from_assert`.
+    HEAP_PROFILING.store(false, Ordering::Release);
+    let out = match std::mem::replace(phase, Phase::Ready) {
         Phase::Ready => unreachable!(),
-        Phase::Running(g) => {
-            g.finish(None);
-            true
+        Phase::Running(mut g) => {
+            let out = g.snapshot();
+            *phase = Phase::PostAssert(g);
+            out
         }
-        Phase::PostAssert => unreachable!(),
-    }
+        Phase::PostAssert(_) => unreachable!(),
+    };
+    drop(guard);
+    out.print();
+    true
 }
 
 /// Asserts that an expression is true.
@@ -1737,6 +7532,8 @@ where
 /// - If called when a [`Profiler`] is not running or is not in testing mode.
 /// - If called after a previous `dhat` assertion has failed with the current
 ///   [`Profiler`]. This is possible if [`std::panic::catch_unwind`] is used.
+///   (Unless [`ProfilerBuilder::soft_asserts`] is enabled, or
+///   [`Profiler::recover_from_assert`] has been called since.)
 #[macro_export]
 macro_rules! assert {
     ($cond:expr) => ({
@@ -1763,6 +7560,8 @@ macro_rules! assert {
 /// - If called when a [`Profiler`] is not running or is not in testing mode.
 /// - If called after a previous `dhat` assertion has failed with the current
 ///   [`Profiler`]. This is possible if [`std::panic::catch_unwind`] is used.
+///   (Unless [`ProfilerBuilder::soft_asserts`] is enabled, or
+///   [`Profiler::recover_from_assert`] has been called since.)
 #[macro_export]
 macro_rules! assert_eq {
     ($left:expr, $right:expr $(,)?) => ({
@@ -1772,58 +7571,426 @@ macro_rules! assert_eq {
                 $left, $right
             );
         }
-    });
-    ($left:expr, $right:expr, $($arg:tt)+) => ({
-        if dhat::check_assert_condition(|| $left == $right) {
-            panic!(
-                "dhat: assertion failed: `(left == right)`\n  left: `{:?}`,\n right: `{:?}`: {}",
-                $left, $right, format_args!($($arg)+)
-            );
+    });
+    ($left:expr, $right:expr, $($arg:tt)+) => ({
+        if dhat::check_assert_condition(|| $left == $right) {
+            panic!(
+                "dhat: assertion failed: `(left == right)`\n  left: `{:?}`,\n right: `{:?}`: {}",
+                $left, $right, format_args!($($arg)+)
+            );
+        }
+    });
+}
+
+/// Asserts that two expressions are not equal.
+///
+/// Like [`std::assert_ne!`], additional format arguments are supported. On
+/// failure, this macro will save the profile data and panic.
+///
+/// # Panics
+///
+/// Panics immediately (without saving the profile data) in the following
+/// circumstances.
+/// - If called when a [`Profiler`] is not running or is not in testing mode.
+/// - If called after a previous `dhat` assertion has failed with the current
+///   [`Profiler`]. This is possible if [`std::panic::catch_unwind`] is used.
+///   (Unless [`ProfilerBuilder::soft_asserts`] is enabled, or
+///   [`Profiler::recover_from_assert`] has been called since.)
+#[macro_export]
+macro_rules! assert_ne {
+    ($left:expr, $right:expr) => ({
+        if dhat::check_assert_condition(|| $left != $right) {
+            panic!(
+                "dhat: assertion failed: `(left != right)`\n  left: `{:?}`,\n right: `{:?}`",
+                $left, $right
+            );
+        }
+    });
+    ($left:expr, $right:expr, $($arg:tt)+) => ({
+        if dhat::check_assert_condition(|| $left != $right) {
+            panic!(
+                "dhat: assertion failed: `(left != right)`\n  left: `{:?}`,\n right: `{:?}`: {}",
+                $left, $right, format_args!($($arg)+)
+            );
+        }
+    });
+}
+
+/// Asserts that a field of a [`HeapStatsDelta`] is less than or equal to a
+/// limit, e.g. `dhat::assert_delta_le!(delta, total_blocks, 2)`.
+///
+/// Like [`std::assert!`], additional format arguments are supported. On
+/// failure, this macro will save the profile data and panic. The panic message
+/// includes the entire delta.
+///
+/// # Panics
+///
+/// Panics immediately (without saving the profile data) in the following
+/// circumstances.
+/// - If called when a [`Profiler`] is not running or is not in testing mode.
+/// - If called after a previous `dhat` assertion has failed with the current
+///   [`Profiler`]. This is possible if [`std::panic::catch_unwind`] is used.
+///   (Unless [`ProfilerBuilder::soft_asserts`] is enabled, or
+///   [`Profiler::recover_from_assert`] has been called since.)
+#[macro_export]
+macro_rules! assert_delta_le {
+    ($delta:expr, $field:ident, $max:expr $(,)?) => ({
+        if dhat::check_assert_condition(|| $delta.$field <= $max) {
+            panic!(
+                "dhat: assertion failed: `(delta.{} <= max)`\n delta: `{:?}`,\n   max: `{:?}`",
+                stringify!($field), $delta, $max
+            );
+        }
+    });
+    ($delta:expr, $field:ident, $max:expr, $($arg:tt)+) => ({
+        if dhat::check_assert_condition(|| $delta.$field <= $max) {
+            panic!(
+                "dhat: assertion failed: `(delta.{} <= max)`\n delta: `{:?}`,\n   max: `{:?}`: {}",
+                stringify!($field), $delta, $max, format_args!($($arg)+)
+            );
+        }
+    });
+}
+
+/// Asserts that a field of a [`HeapStatsDelta`] equals an expected value,
+/// e.g. `dhat::assert_delta_eq!(delta, curr_blocks, 0)`.
+///
+/// Like [`std::assert_eq!`], additional format arguments are supported. On
+/// failure, this macro will save the profile data and panic. The panic message
+/// includes the entire delta.
+///
+/// # Panics
+///
+/// Panics immediately (without saving the profile data) in the following
+/// circumstances.
+/// - If called when a [`Profiler`] is not running or is not in testing mode.
+/// - If called after a previous `dhat` assertion has failed with the current
+///   [`Profiler`]. This is possible if [`std::panic::catch_unwind`] is used.
+///   (Unless [`ProfilerBuilder::soft_asserts`] is enabled, or
+///   [`Profiler::recover_from_assert`] has been called since.)
+#[macro_export]
+macro_rules! assert_delta_eq {
+    ($delta:expr, $field:ident, $expected:expr $(,)?) => ({
+        if dhat::check_assert_condition(|| $delta.$field == $expected) {
+            panic!(
+                "dhat: assertion failed: `(delta.{} == expected)`\n    delta: `{:?}`,\n expected: `{:?}`",
+                stringify!($field), $delta, $expected
+            );
+        }
+    });
+    ($delta:expr, $field:ident, $expected:expr, $($arg:tt)+) => ({
+        if dhat::check_assert_condition(|| $delta.$field == $expected) {
+            panic!(
+                "dhat: assertion failed: `(delta.{} == expected)`\n    delta: `{:?}`,\n expected: `{:?}`: {}",
+                stringify!($field), $delta, $expected, format_args!($($arg)+)
+            );
+        }
+    });
+}
+
+// Builds the `PpInfoJson` for each backtrace in turn, giving each unique frame
+// an index into `ftbl` as it goes.
+struct PpJsonBuilder<'a> {
+    g: &'a Globals,
+
+    // The lifetimes of all remaining live blocks, per PP.
+    live_lifetimes: Vec<Duration>,
+
+    // The resolved frames of all the backtraces.
+    resolved: ResolvedFrames,
+
+    // The index into `ftbl` of each unique frame seen so far.
+    ftbl_indices: FxHashMap<String, usize>,
+
+    // The structured form of each unique frame seen so far, in index order,
+    // for `ProfilerBuilder::structured_frames`.
+    structured_ftbl: Option<Vec<FrameJson>>,
+}
+
+impl<'a> PpJsonBuilder<'a> {
+    fn new(g: &'a Globals, backtraces: &[(Backtrace, usize)], now: Instant) -> Self {
+        // Account for the lifetimes of all remaining live blocks. This is
+        // done here, rather than in `pp_infos`, so that snapshots don't
+        // disturb the ongoing accounting.
+        let mut live_lifetimes = vec![Duration::ZERO; g.pp_infos.len()];
+        if let Some(h) = &g.heap {
+            for &LiveBlock {
+                pp_info_idx,
+                allocation_instant,
+                ..
+            } in h.live_blocks.values()
+            {
+                live_lifetimes[pp_info_idx] += now.duration_since(allocation_instant);
+            }
+        }
+
+        // Do the potentially expensive debug info lookups to get symbol
+        // names, line numbers, etc. This is done for all backtraces up front,
+        // so that each unique frame is only looked up once and so that it
+        // can be done in parallel; the interning in `pp_json` must stay
+        // serial so that frame indices are deterministic. The resolved frames
+        // are freed when the builder is dropped.
+        let resolved = if g.resolve_symbols {
+            Backtrace::resolve_all(&g.frames, backtraces.iter().map(|(bt, _)| bt))
+        } else {
+            ResolvedFrames::default()
+        };
+
+        // We give each unique frame an index into `ftbl`, starting with 0
+        // for the special frame "[root]".
+        let mut ftbl_indices: FxHashMap<String, usize> = FxHashMap::default();
+        ftbl_indices.insert("[root]".to_string(), 0);
+        let structured_ftbl = g
+            .structured_frames
+            .then(|| vec![FrameJson::synthetic("[root]")]);
+
+        PpJsonBuilder {
+            g,
+            live_lifetimes,
+            resolved,
+            ftbl_indices,
+            structured_ftbl,
+        }
+    }
+
+    // True if symbols were resolved but none of them had file and line info.
+    fn missing_line_info(&self) -> bool {
+        !self.resolved.is_empty()
+            && !self
+                .resolved
+                .values()
+                .flat_map(|frame| frame.symbols())
+                .any(|symbol| symbol.filename().is_some())
+    }
+
+    fn pp_json(&mut self, bt: &Backtrace, pp_info_idx: usize) -> PpInfoJson {
+        let g = self.g;
+        let resolved = &self.resolved;
+
+        // Trim boring frames at the top and bottom of the backtrace. This
+        // requires symbol names.
+        let first_symbol_to_show = if g.trim_backtraces.is_some() && g.resolve_symbols {
+            if g.heap.is_some() {
+                bt.first_heap_symbol_to_show(resolved, &g.trim_top_matching)
+            } else {
+                bt.first_ad_hoc_symbol_to_show()
+            }
+        } else {
+            0
+        };
+
+        // Determine the frame indices for this backtrace. This involves
+        // getting the string for each frame and adding a new entry to
+        // `ftbl_indices` if it hasn't been seen before. `structured` gets the
+        // structured form of a new frame from its string, if needed.
+        let ftbl_indices = &mut self.ftbl_indices;
+        let structured_ftbl = &mut self.structured_ftbl;
+        let mut fs = vec![];
+        let mut push_frame = |s: String, structured: &dyn Fn(&str) -> FrameJson| {
+            let next_ftbl_idx = ftbl_indices.len();
+            let &mut ftbl_idx = ftbl_indices.entry(s).or_insert_with_key(|s| {
+                if let Some(structured_ftbl) = structured_ftbl {
+                    structured_ftbl.push(structured(s));
+                }
+                next_ftbl_idx
+            });
+            fs.push(ftbl_idx);
+        };
+        // A tag and a region label become synthetic innermost frames, so the
+        // viewer groups everything within them together.
+        if let Some(s) = bt.tag_string() {
+            push_frame(s, &FrameJson::synthetic);
+        }
+        if let Some(label) = bt.region {
+            push_frame(format!("[region: {}]", label), &FrameJson::synthetic);
+        }
+        if let Some(s) = bt.location_string(g.path_components) {
+            push_frame(s, &|_| {
+                FrameJson::from_location(bt.location.unwrap(), g.path_components)
+            });
+        }
+        if g.resolve_symbols {
+            for (frame, symbol) in bt.symbols(resolved).skip(first_symbol_to_show) {
+                let s = if g.merge_by_line {
+                    Backtrace::frame_to_line_string(symbol, g.path_components)
+                } else {
+                    Backtrace::frame_to_string(frame, symbol, g.path_components)
+                };
+                push_frame(s, &|_| {
+                    FrameJson::from_symbol(frame, symbol, g.path_components, g.merge_by_line)
+                });
+            }
+        } else {
+            // Unresolved frames have no symbols, just addresses.
+            for ip in bt.ips.iter() {
+                push_frame(format!("{:#x}", ip), &|s| FrameJson {
+                    ip: Some(s.to_string()),
+                    ..FrameJson::default()
+                });
+            }
+        }
+        if let Some(s) = bt.truncated_string() {
+            push_frame(s, &FrameJson::synthetic);
+        }
+
+        PpInfoJson::new(
+            &g.pp_infos[pp_info_idx],
+            self.live_lifetimes[pp_info_idx],
+            g.start_instant,
+            g.time_unit,
+            fs,
+        )
+    }
+
+    // The frames seen so far, in index order.
+    fn ftbl(&self) -> Vec<&str> {
+        // We pre-allocate `ftbl` with empty strings, and then fill it in.
+        let mut ftbl = vec![""; self.ftbl_indices.len()];
+        for (frame, &ftbl_idx) in self.ftbl_indices.iter() {
+            ftbl[ftbl_idx] = frame;
+        }
+        ftbl
+    }
+
+    // Like `ftbl`, but consumes the builder to avoid copying the frames.
+    fn into_ftbl(self) -> Ftbl {
+        let mut ftbl = vec![String::new(); self.ftbl_indices.len()];
+        for (frame, ftbl_idx) in self.ftbl_indices.into_iter() {
+            ftbl[ftbl_idx] = frame;
+        }
+        Ftbl {
+            strings: ftbl,
+            structured: self.structured_ftbl,
+        }
+    }
+}
+
+// Serializes the items of an iterator as a JSON array, without collecting
+// them first. Because the iterator is consumed, this can only be serialized
+// once.
+struct StreamedSeq<I>(RefCell<Option<I>>);
+
+impl<I> StreamedSeq<I> {
+    fn new(iter: I) -> Self {
+        StreamedSeq(RefCell::new(Some(iter)))
+    }
+}
+
+impl<I> Serialize for StreamedSeq<I>
+where
+    I: Iterator,
+    I::Item: Serialize,
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let iter = self
+            .0
+            .borrow_mut()
+            .take()
+            .expect("dhat: stream serialized twice");
+        serializer.collect_seq(iter)
+    }
+}
+
+// Serializes the frame table of a `PpJsonBuilder`. This must be done after
+// all the PPs have been built.
+struct StreamedFtbl<'a, 'g>(&'a RefCell<PpJsonBuilder<'g>>);
+
+impl Serialize for StreamedFtbl<'_, '_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let builder = self.0.borrow();
+        match &builder.structured_ftbl {
+            Some(structured) => structured.serialize(serializer),
+            None => serializer.collect_seq(builder.ftbl()),
+        }
+    }
+}
+
+// The frame table of a `DhatJson`. The strings are always present, because
+// other outputs such as folded stacks use them, but with
+// `ProfilerBuilder::structured_frames` the structured frames are what is
+// serialized.
+struct Ftbl {
+    strings: Vec<String>,
+    structured: Option<Vec<FrameJson>>,
+}
+
+impl Serialize for Ftbl {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.structured {
+            Some(structured) => structured.serialize(serializer),
+            None => self.strings.serialize(serializer),
         }
-    });
+    }
 }
 
-/// Asserts that two expressions are not equal.
-///
-/// Like [`std::assert_ne!`], additional format arguments are supported. On
-/// failure, this macro will save the profile data and panic.
-///
-/// # Panics
-///
-/// Panics immediately (without saving the profile data) in the following
-/// circumstances.
-/// - If called when a [`Profiler`] is not running or is not in testing mode.
-/// - If called after a previous `dhat` assertion has failed with the current
-///   [`Profiler`]. This is possible if [`std::panic::catch_unwind`] is used.
-#[macro_export]
-macro_rules! assert_ne {
-    ($left:expr, $right:expr) => ({
-        if dhat::check_assert_condition(|| $left != $right) {
-            panic!(
-                "dhat: assertion failed: `(left != right)`\n  left: `{:?}`,\n right: `{:?}`",
-                $left, $right
-            );
+// A frame in the frame table, for `ProfilerBuilder::structured_frames`. Not
+// part of DHAT's format.
+#[derive(Default, Serialize)]
+struct FrameJson {
+    #[serde(rename = "fn", skip_serializing_if = "Option::is_none")]
+    func: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    col: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+}
+
+impl FrameJson {
+    // A frame that is just a string, e.g. `[root]`.
+    fn synthetic(s: &str) -> Self {
+        FrameJson {
+            func: Some(s.to_string()),
+            ..FrameJson::default()
         }
-    });
-    ($left:expr, $right:expr, $($arg:tt)+) => ({
-        if dhat::check_assert_condition(|| $left != $right) {
-            panic!(
-                "dhat: assertion failed: `(left != right)`\n  left: `{:?}`,\n right: `{:?}`: {}",
-                $left, $right, format_args!($($arg)+)
-            );
+    }
+
+    // The structured form of `Backtrace::location_string`.
+    fn from_location(location: &Location<'_>, path_components: usize) -> Self {
+        FrameJson {
+            file: Some(
+                trim_path(Path::new(location.file()), path_components)
+                    .display()
+                    .to_string(),
+            ),
+            line: Some(location.line()),
+            col: Some(location.column()),
+            ..FrameJson::default()
         }
-    });
+    }
+
+    // The structured form of `Backtrace::frame_to_string`, or of
+    // `Backtrace::frame_to_line_string` if `line_only` is true.
+    fn from_symbol(
+        frame: &backtrace::BacktraceFrame,
+        symbol: &backtrace::BacktraceSymbol,
+        path_components: usize,
+        line_only: bool,
+    ) -> Self {
+        FrameJson {
+            func: symbol.name().map(|name| format!("{:#}", name)),
+            file: symbol
+                .filename()
+                .map(|path| trim_path(path, path_components).display().to_string()),
+            line: symbol.lineno(),
+            col: symbol.colno().filter(|_| !line_only),
+            ip: (!line_only).then(|| format!("{:?}", frame.ip())),
+        }
+    }
 }
 
 // A Rust representation of DHAT's JSON file format, which is described in
 // comments in dhat/dh_main.c in Valgrind's source code.
 //
-// Building this structure in order to serialize does take up some memory. We
-// could instead stream the JSON output directly to file ourselves. This would
-// be more efficient but make the code uglier.
+// Building this structure in order to serialize does take up some memory.
+// With `ProfilerBuilder::stream_output`, `pps` and `ftbl` are streams instead
+// of collections, so that each PP is written as soon as it is built.
 #[derive(Serialize)]
 #[allow(non_snake_case)]
-struct DhatJson {
+struct DhatJson<P = Vec<PpInfoJson>, F = Ftbl> {
     dhatFileVersion: u32,
     mode: &'static str,
     verb: &'static str,
@@ -1844,15 +8011,208 @@ struct DhatJson {
     #[serde(skip_serializing_if = "Option::is_none")]
     tg: Option<u128>,
     te: u128,
-    pps: Vec<PpInfoJson>,
-    ftbl: Vec<String>,
+    pps: P,
+    ftbl: F,
+
+    // The following fields are not part of DHAT's format. DHAT's viewer
+    // ignores them.
+
+    // Present, and `true`, if `ftbl` contains unresolved code addresses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unresolved: Option<bool>,
+
+    // The environment variables requested with `ProfilerBuilder::record_env`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    env: Option<BTreeMap<String, String>>,
+
+    // The markers recorded with `marker`, if there are any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    markers: Option<Vec<MarkerJson>>,
+
+    // The samples taken with `ProfilerBuilder::sample_curve`, if there are
+    // any. Each one is a time (like `markers`), current bytes, and current
+    // blocks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    curve: Option<Vec<(u128, usize, usize)>>,
+
+    // The settings that produced this profile.
+    config: ConfigJson,
+
+    // Not serialized. True if symbols were resolved but none of them had file
+    // and line info, which means the program lacks debug info.
+    #[serde(skip)]
+    missing_line_info: bool,
+}
+
+// A `serde_json` formatter that lays out the JSON like DHAT itself does: one
+// line per top-level field, one line per element of the top-level arrays
+// (`pps` and `ftbl`), leading commas, and no other whitespace. E.g.:
+//
+//   {"dhatFileVersion":2
+//   ,"mode":"rust-heap"
+//   ...
+//   ,"pps":
+//    [{"tb":1000,"tbk":1,...,"fs":[1,2,3]}
+//    ,{"tb":200,"tbk":2,...,"fs":[4,2,3]}
+//    ]
+//   ,"ftbl":
+//    ["[root]"
+//    ,"0x1000: foo (src/foo.rs:1:1)"
+//    ...
+//    ]
+//   }
+#[derive(Default)]
+struct DhatFormatter {
+    // The nesting depth of objects and arrays.
+    depth: usize,
+
+    // Whether the current top-level array has any elements yet.
+    has_value: bool,
+}
+
+impl serde_json::ser::Formatter for DhatFormatter {
+    fn begin_object<W: ?Sized + Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        self.depth += 1;
+        writer.write_all(b"{")
+    }
+
+    fn end_object<W: ?Sized + Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        self.depth -= 1;
+        if self.depth == 0 {
+            writer.write_all(b"\n}\n")
+        } else {
+            writer.write_all(b"}")
+        }
+    }
+
+    fn begin_object_key<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> std::io::Result<()> {
+        match (first, self.depth) {
+            (true, _) => Ok(()),
+            (false, 1) => writer.write_all(b"\n,"),
+            (false, _) => writer.write_all(b","),
+        }
+    }
+
+    fn begin_array<W: ?Sized + Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        self.depth += 1;
+        if self.depth == 2 {
+            self.has_value = false;
+            writer.write_all(b"\n [")
+        } else {
+            writer.write_all(b"[")
+        }
+    }
+
+    fn end_array<W: ?Sized + Write>(&mut self, writer: &mut W) -> std::io::Result<()> {
+        self.depth -= 1;
+        if self.depth == 1 && self.has_value {
+            writer.write_all(b"\n ]")
+        } else {
+            writer.write_all(b"]")
+        }
+    }
+
+    fn begin_array_value<W: ?Sized + Write>(
+        &mut self,
+        writer: &mut W,
+        first: bool,
+    ) -> std::io::Result<()> {
+        if self.depth == 2 {
+            self.has_value = true;
+        }
+        match (first, self.depth) {
+            (true, _) => Ok(()),
+            (false, 2) => writer.write_all(b"\n ,"),
+            (false, _) => writer.write_all(b","),
+        }
+    }
+}
+
+// A Rust representation of the Chrome trace event format, as used by
+// `ProfilerBuilder::chrome_trace`.
+#[derive(Serialize)]
+#[allow(non_snake_case)]
+struct ChromeTraceJson {
+    traceEvents: Vec<ChromeTraceEvent>,
+}
+
+#[derive(Serialize)]
+struct ChromeTraceEvent {
+    name: &'static str,
+    // The event type: "C" for counter events, "i" for instant events.
+    ph: &'static str,
+    // In microseconds, regardless of `Globals::time_unit`.
+    ts: u128,
+    pid: u32,
+    tid: u32,
+    // The scope of an instant event: "g" for global.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    s: Option<&'static str>,
+    args: ChromeTraceArgs,
+}
+
+#[derive(Serialize)]
+struct ChromeTraceArgs {
+    bytes: usize,
+}
+
+// The summary stats saved with `ProfilerBuilder::stats_file`, for tools such
+// as CI scripts. Not part of DHAT's format. For ad hoc profiling, the totals
+// are events and (integer) units, and the other counts are omitted.
+#[derive(Serialize)]
+struct StatsJson {
+    // "heap" or "ad-hoc".
+    mode: &'static str,
+    total_blocks: u64,
+    total_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    curr_blocks: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    curr_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_blocks: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_bytes: Option<usize>,
+    elapsed_secs: f64,
+}
+
+// A marker within the JSON file. Like the other times in the file, `t` is
+// relative to the start of profiling, in the units given by `tu`.
+#[derive(Serialize)]
+struct MarkerJson {
+    t: u128,
+    name: String,
+}
+
+// The effective `ProfilerBuilder` settings, recorded in the saved profile so
+// that it documents how it was produced. Not part of DHAT's format.
+#[derive(Serialize)]
+struct ConfigJson {
+    // "heap" or "ad-hoc".
+    mode: &'static str,
+    // `null` means unlimited.
+    trim_backtraces: Option<usize>,
+    no_backtraces: bool,
+    min_size: usize,
+    // The curve sampling interval, in the units given by `tu`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sample_curve: Option<u128>,
+    // "bytes" or "blocks".
+    peak_metric: &'static str,
+    track_alignment: bool,
+    net_only: bool,
+    split_by_size_class: bool,
 }
 
 // A Rust representation of a PpInfo within DHAT's JSON file format.
 #[derive(Serialize)]
 struct PpInfoJson {
     // `PpInfo::total_bytes and `PpInfo::total_blocks.
-    tb: u64,
+    tb: Units,
     tbk: u64,
 
     // Derived from `PpInfo::total_lifetimes_duration`.
@@ -1878,28 +8238,57 @@ struct PpInfoJson {
     #[serde(skip_serializing_if = "Option::is_none")]
     ebk: Option<usize>,
 
+    // Derived from `PpInfo::max_instant`. Not part of DHAT's format, which
+    // only records the time of the global max (`tg`); DHAT's viewer ignores
+    // it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mt: Option<u128>,
+
+    // `PpInfo::lifetime_histogram`. Not part of DHAT's format; DHAT's viewer
+    // ignores it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lh: Option<Vec<u64>>,
+
     // Frames. Each element is an index into `ftbl`.
     fs: Vec<usize>,
 }
 
 impl PpInfoJson {
-    fn new(pp_info: &PpInfo, fs: Vec<usize>) -> Self {
+    // `live_lifetimes` is the total lifetime so far of this PP's blocks that
+    // are still live.
+    fn new(
+        pp_info: &PpInfo,
+        live_lifetimes: Duration,
+        start_instant: Instant,
+        time_unit: TimeUnit,
+        fs: Vec<usize>,
+    ) -> Self {
         if let Some(h) = &pp_info.heap {
             Self {
-                tb: pp_info.total_bytes,
+                tb: Units::Int(pp_info.total_bytes),
                 tbk: pp_info.total_blocks,
-                tl: Some(h.total_lifetimes_duration.as_micros()),
+                tl: Some(time_unit.convert(h.total_lifetimes_duration + live_lifetimes)),
                 mb: Some(h.max_bytes),
                 mbk: Some(h.max_blocks),
                 gb: Some(h.at_tgmax_bytes),
                 gbk: Some(h.at_tgmax_blocks),
                 eb: Some(h.curr_bytes),
                 ebk: Some(h.curr_blocks),
+                mt: h
+                    .max_instant
+                    .map(|i| time_unit.convert(i.saturating_duration_since(start_instant))),
+                lh: (!h.lifetime_histogram.is_empty()).then(|| h.lifetime_histogram.clone()),
                 fs,
             }
         } else {
+            // `PpInfo::total_units_f64` is only non-zero for ad hoc profiling.
+            let tb = if pp_info.total_units_f64 == 0.0 {
+                Units::Int(pp_info.total_bytes)
+            } else {
+                Units::Float(pp_info.total_bytes as f64 + pp_info.total_units_f64)
+            };
             Self {
-                tb: pp_info.total_bytes,
+                tb,
                 tbk: pp_info.total_blocks,
                 tl: None,
                 mb: None,
@@ -1908,12 +8297,78 @@ impl PpInfoJson {
                 gbk: None,
                 eb: None,
                 ebk: None,
+                mt: None,
+                lh: None,
                 fs,
             }
         }
     }
 }
 
+impl PpInfoJson {
+    // Merge the PPs with identical frames, for `ProfilerBuilder::merge_by_line`.
+    // Counts are summed, like in `merge_profiles`.
+    fn merge_identical<I>(pps: I) -> Vec<PpInfoJson>
+    where
+        I: IntoIterator<Item = PpInfoJson>,
+    {
+        fn sum(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+            a.zip(b).map(|(a, b)| a + b)
+        }
+
+        let mut merged: Vec<PpInfoJson> = vec![];
+        let mut indices: FxHashMap<Vec<usize>, usize> = FxHashMap::default();
+        for pp in pps {
+            let Some(&idx) = indices.get(&pp.fs) else {
+                indices.insert(pp.fs.clone(), merged.len());
+                merged.push(pp);
+                continue;
+            };
+            let m = &mut merged[idx];
+            let as_f64 = |units: &Units| match *units {
+                Units::Int(n) => n as f64,
+                Units::Float(n) => n,
+            };
+            m.tb = match (&m.tb, &pp.tb) {
+                (Units::Int(a), Units::Int(b)) => Units::Int(a + b),
+                (a, b) => Units::Float(as_f64(a) + as_f64(b)),
+            };
+            m.tbk += pp.tbk;
+            m.tl = m.tl.zip(pp.tl).map(|(a, b)| a + b);
+            // The time of the max is that of the larger of the two.
+            if pp.mb > m.mb {
+                m.mt = pp.mt;
+            }
+            m.mb = sum(m.mb, pp.mb);
+            m.mbk = sum(m.mbk, pp.mbk);
+            m.gb = sum(m.gb, pp.gb);
+            m.gbk = sum(m.gbk, pp.gbk);
+            m.eb = sum(m.eb, pp.eb);
+            m.ebk = sum(m.ebk, pp.ebk);
+            // Lifetime histograms are summed bucket by bucket.
+            if let Some(n) = pp.lh {
+                let lh = m.lh.get_or_insert_with(Vec::new);
+                if lh.len() < n.len() {
+                    lh.resize(n.len(), 0);
+                }
+                for (m, n) in lh.iter_mut().zip(n) {
+                    *m += n;
+                }
+            }
+        }
+        merged
+    }
+}
+
+// A byte or unit count in the JSON. Integral unless `ad_hoc_event_f64` has
+// been used.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Units {
+    Int(u64),
+    Float(f64),
+}
+
 // A change in size. Used for `realloc`.
 #[derive(Clone, Copy)]
 struct Delta {
@@ -1974,34 +8429,441 @@ pub fn assert_is_panic<R, F: FnOnce() -> R + std::panic::UnwindSafe>(f: F, expec
     }
 }
 
+// A subset of the pprof protobuf format, as defined in
+// https://github.com/google/pprof/blob/main/proto/profile.proto, with the same
+// field numbers. Fields that `dhat` doesn't use are omitted.
+#[cfg(feature = "pprof")]
+mod pprof {
+    use super::{DhatJson, TimeUnit, Units};
+    use rustc_hash::FxHashMap;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub(crate) struct Profile {
+        #[prost(message, repeated, tag = "1")]
+        sample_type: Vec<ValueType>,
+        #[prost(message, repeated, tag = "2")]
+        sample: Vec<Sample>,
+        #[prost(message, repeated, tag = "4")]
+        location: Vec<Location>,
+        #[prost(message, repeated, tag = "5")]
+        function: Vec<Function>,
+        #[prost(string, repeated, tag = "6")]
+        string_table: Vec<String>,
+        #[prost(int64, tag = "9")]
+        time_nanos: i64,
+        #[prost(int64, tag = "10")]
+        duration_nanos: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct ValueType {
+        #[prost(int64, tag = "1")]
+        r#type: i64,
+        #[prost(int64, tag = "2")]
+        unit: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Sample {
+        #[prost(uint64, repeated, tag = "1")]
+        location_id: Vec<u64>,
+        #[prost(int64, repeated, tag = "2")]
+        value: Vec<i64>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Location {
+        #[prost(uint64, tag = "1")]
+        id: u64,
+        #[prost(uint64, tag = "3")]
+        address: u64,
+        #[prost(message, repeated, tag = "4")]
+        line: Vec<Line>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Line {
+        #[prost(uint64, tag = "1")]
+        function_id: u64,
+        #[prost(int64, tag = "2")]
+        line: i64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Function {
+        #[prost(uint64, tag = "1")]
+        id: u64,
+        #[prost(int64, tag = "2")]
+        name: i64,
+        #[prost(int64, tag = "3")]
+        system_name: i64,
+        #[prost(int64, tag = "4")]
+        filename: i64,
+    }
+
+    // Interns strings into a pprof string table, whose first element must be
+    // the empty string.
+    struct StringTable {
+        strings: Vec<String>,
+        indices: FxHashMap<String, i64>,
+    }
+
+    impl StringTable {
+        fn new() -> Self {
+            let mut table = StringTable {
+                strings: vec![],
+                indices: FxHashMap::default(),
+            };
+            table.intern("");
+            table
+        }
+
+        fn intern(&mut self, s: &str) -> i64 {
+            if let Some(&idx) = self.indices.get(s) {
+                return idx;
+            }
+            let idx = self.strings.len() as i64;
+            self.strings.push(s.to_string());
+            self.indices.insert(s.to_string(), idx);
+            idx
+        }
+    }
+
+    // Split a frame string produced by `Backtrace::frame_to_string` or
+    // `Backtrace::frame_to_line_string` into its address, symbol name, file
+    // name, and line number. Frames that lack some of these (e.g. unresolved
+    // ones) get defaults.
+    fn parse_frame<'a>(frame: &'a str) -> (u64, &'a str, &'a str, i64) {
+        let (address, rest) = match frame.split_once(": ") {
+            Some((ip, rest)) if ip.starts_with("0x") => {
+                (u64::from_str_radix(&ip[2..], 16).unwrap_or(0), rest)
+            }
+            _ => (0, frame),
+        };
+        if let Some((name, loc)) = rest.strip_suffix(')').and_then(|s| s.split_once(" (")) {
+            // `loc` is `file:line:col`, or `file:line` with
+            // `ProfilerBuilder::merge_by_line`.
+            let split_num = |s: &'a str| {
+                let (s, n) = s.rsplit_once(':')?;
+                Some((s, n.parse::<i64>().ok()?))
+            };
+            if let Some((s, n)) = split_num(loc) {
+                let (file, line) = split_num(s).unwrap_or((s, n));
+                return (address, name, file, line);
+            }
+        }
+        let address = if address == 0 {
+            frame
+                .strip_prefix("0x")
+                .and_then(|ip| u64::from_str_radix(ip, 16).ok())
+                .unwrap_or(0)
+        } else {
+            address
+        };
+        (address, rest, "", 0)
+    }
+
+    impl Profile {
+        pub(crate) fn new(json: &DhatJson, is_heap: bool, time_unit: TimeUnit) -> Self {
+            let mut strings = StringTable::new();
+            let sample_type = if is_heap {
+                vec![("alloc_objects", "count"), ("alloc_space", "bytes")]
+            } else {
+                vec![("events", "count"), ("units", "count")]
+            };
+            let sample_type = sample_type
+                .into_iter()
+                .map(|(ty, unit)| ValueType {
+                    r#type: strings.intern(ty),
+                    unit: strings.intern(unit),
+                })
+                .collect();
+
+            // Each `ftbl` entry becomes one location and one function, with
+            // id `ftbl_idx + 1` because pprof ids must be non-zero.
+            let mut location = vec![];
+            let mut function = vec![];
+            for (ftbl_idx, frame) in json.ftbl.strings.iter().enumerate().skip(1) {
+                let id = ftbl_idx as u64 + 1;
+                let (address, name, file, line) = parse_frame(frame);
+                let name = strings.intern(name);
+                function.push(Function {
+                    id,
+                    name,
+                    system_name: name,
+                    filename: strings.intern(file),
+                });
+                location.push(Location {
+                    id,
+                    address,
+                    line: vec![Line {
+                        function_id: id,
+                        line,
+                    }],
+                });
+            }
+
+            let sample = json
+                .pps
+                .iter()
+                .map(|pp| {
+                    let total = match pp.tb {
+                        Units::Int(n) => n as i64,
+                        Units::Float(n) => n.round() as i64,
+                    };
+                    Sample {
+                        location_id: pp.fs.iter().map(|&idx| idx as u64 + 1).collect(),
+                        value: vec![pp.tbk as i64, total],
+                    }
+                })
+                .collect();
+
+            let duration_nanos = (json.te * time_unit.nanos()) as i64;
+            let time_nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as i64 - duration_nanos);
+
+            Profile {
+                sample_type,
+                sample,
+                location,
+                function,
+                string_table: strings.strings,
+                time_nanos,
+                duration_nanos,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::parse_frame;
+
+        #[test]
+        fn test_parse_frame() {
+            std::assert_eq!(
+                parse_frame("0x10a: foo::bar (src/foo.rs:12:5)"),
+                (0x10a, "foo::bar", "src/foo.rs", 12)
+            );
+            std::assert_eq!(
+                parse_frame("0x10a: <A as B>::f (a:b.rs:3:1)"),
+                (0x10a, "<A as B>::f", "a:b.rs", 3)
+            );
+            std::assert_eq!(
+                parse_frame("0x10a: foo::bar (src/foo.rs:12)"),
+                (0x10a, "foo::bar", "src/foo.rs", 12)
+            );
+            std::assert_eq!(
+                parse_frame("foo::bar (src/foo.rs:12)"),
+                (0, "foo::bar", "src/foo.rs", 12)
+            );
+            std::assert_eq!(parse_frame("0x10a"), (0x10a, "0x10a", "", 0));
+            std::assert_eq!(
+                parse_frame("[region: parsing]"),
+                (0, "[region: parsing]", "", 0)
+            );
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::trim_path;
-    use std::path::Path;
+    #[cfg(unix)]
+    use super::ForkFileName;
+    use super::{
+        align_padding, expand_file_name, lifetime_bucket, numbered_file_name, symbol_module,
+        trim_path, Backtrace, HeapStats, PpInfo,
+    };
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    #[test]
+    fn test_numbered_file_name() {
+        std::assert_eq!(
+            numbered_file_name(Path::new("dhat-heap.json"), 12),
+            PathBuf::from("dhat-heap.12.json")
+        );
+        std::assert_eq!(
+            numbered_file_name(Path::new("/tmp/a.b.json"), 12),
+            PathBuf::from("/tmp/a.b.12.json")
+        );
+        std::assert_eq!(
+            numbered_file_name(Path::new("out"), 12),
+            PathBuf::from("out.12")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fork_file_name() {
+        for path in ["dhat-heap.json", "/tmp/a.b.json", "out"] {
+            let old = PathBuf::from(path);
+            let mut name = ForkFileName::new(&old);
+            let mut path = old.clone();
+            name.swap(&mut path, 4294967295);
+            std::assert_eq!(path, numbered_file_name(&old, 4294967295u32));
+            std::assert_eq!(name.path, old.into_os_string());
+        }
+    }
+
+    #[test]
+    fn test_expand_file_name() {
+        let pid = std::process::id();
+        std::assert_eq!(
+            expand_file_name(Path::new("a.json")),
+            PathBuf::from("a.json")
+        );
+        std::assert_eq!(
+            expand_file_name(Path::new("a-{pid}.json")),
+            PathBuf::from(format!("a-{}.json", pid))
+        );
+        let ts = expand_file_name(Path::new("{ts}"));
+        std::assert!(ts.to_str().unwrap().parse::<u64>().unwrap() > 0);
+
+        // `{n}` is incremented past existing files.
+        let dir = std::env::temp_dir().join(format!("dhat-test-{}", pid));
+        std::fs::create_dir_all(&dir).unwrap();
+        let template = dir.join("x-{n}.json");
+        std::assert_eq!(expand_file_name(&template), dir.join("x-0.json"));
+        std::fs::write(dir.join("x-0.json"), "").unwrap();
+        std::fs::write(dir.join("x-1.json"), "").unwrap();
+        std::assert_eq!(expand_file_name(&template), dir.join("x-2.json"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_dealloc_underflow() {
+        let mut pp_info = PpInfo::new_heap();
+        pp_info.update_counts_for_alloc(10, None, std::time::Instant::now());
+        std::assert!(!pp_info.update_counts_for_dealloc(10, Duration::ZERO));
+        std::assert!(pp_info.update_counts_for_dealloc(10, Duration::ZERO));
+        let h = pp_info.heap.as_ref().unwrap();
+        std::assert_eq!(h.curr_blocks, 0);
+        std::assert_eq!(h.curr_bytes, 0);
+    }
 
     #[test]
     fn test_trim_path() {
-        std::assert_eq!(trim_path(Path::new("")), Path::new(""));
-        std::assert_eq!(trim_path(Path::new("/")), Path::new("/"));
-        std::assert_eq!(trim_path(Path::new("aa.rs")), Path::new("aa.rs"));
-        std::assert_eq!(trim_path(Path::new("/aa.rs")), Path::new("/aa.rs"));
-        std::assert_eq!(trim_path(Path::new("bb/aa.rs")), Path::new("bb/aa.rs"));
-        std::assert_eq!(trim_path(Path::new("/bb/aa.rs")), Path::new("/bb/aa.rs"));
+        std::assert_eq!(trim_path(Path::new(""), 3), Path::new(""));
+        std::assert_eq!(trim_path(Path::new("/"), 3), Path::new("/"));
+        std::assert_eq!(trim_path(Path::new("aa.rs"), 3), Path::new("aa.rs"));
+        std::assert_eq!(trim_path(Path::new("/aa.rs"), 3), Path::new("/aa.rs"));
+        std::assert_eq!(trim_path(Path::new("bb/aa.rs"), 3), Path::new("bb/aa.rs"));
+        std::assert_eq!(trim_path(Path::new("/bb/aa.rs"), 3), Path::new("/bb/aa.rs"));
         std::assert_eq!(
-            trim_path(Path::new("cc/bb/aa.rs")),
+            trim_path(Path::new("cc/bb/aa.rs"), 3),
             Path::new("cc/bb/aa.rs")
         );
         std::assert_eq!(
-            trim_path(Path::new("/cc/bb/aa.rs")),
+            trim_path(Path::new("/cc/bb/aa.rs"), 3),
             Path::new("cc/bb/aa.rs")
         );
         std::assert_eq!(
-            trim_path(Path::new("dd/cc/bb/aa.rs")),
+            trim_path(Path::new("dd/cc/bb/aa.rs"), 3),
             Path::new("cc/bb/aa.rs")
         );
         std::assert_eq!(
-            trim_path(Path::new("/dd/cc/bb/aa.rs")),
+            trim_path(Path::new("/dd/cc/bb/aa.rs"), 3),
             Path::new("cc/bb/aa.rs")
         );
+        std::assert_eq!(
+            trim_path(Path::new("/dd/cc/bb/aa.rs"), 1),
+            Path::new("aa.rs")
+        );
+        std::assert_eq!(
+            trim_path(Path::new("/dd/cc/bb/aa.rs"), 4),
+            Path::new("dd/cc/bb/aa.rs")
+        );
+        std::assert_eq!(
+            trim_path(Path::new("/dd/cc/bb/aa.rs"), usize::MAX),
+            Path::new("/dd/cc/bb/aa.rs")
+        );
+    }
+
+    #[test]
+    fn test_heap_stats_diff() {
+        let a = HeapStats {
+            total_blocks: 10,
+            total_bytes: 1000,
+            curr_blocks: 2,
+            curr_bytes: 200,
+            freed_blocks: 8,
+            freed_bytes: 800,
+            max_blocks: 5,
+            max_bytes: 500,
+            pp_count: 3,
+            failed_blocks: 0,
+            realloc_blocks: 1,
+            realloc_bytes_moved: 100,
+            realloc_in_place: 0,
+            realloc_moved: 1,
+            zst_allocations: 0,
+            largest_block: 100,
+        };
+        let b = HeapStats {
+            total_blocks: 12,
+            curr_blocks: 0,
+            curr_bytes: 0,
+            ..a.clone()
+        };
+        let diff = a.diff(&b);
+        let lines: Vec<_> = diff.lines().collect();
+        std::assert_eq!(lines.len(), 17);
+        std::assert!(lines[1].starts_with("total_blocks "));
+        std::assert!(lines[1].ends_with(" +2"));
+        std::assert!(lines[4].starts_with("curr_bytes "));
+        std::assert!(lines[4].ends_with(" -200"));
+        std::assert!(lines[8].ends_with(" +0"));
+
+        let delta = b.delta_from(&a);
+        std::assert_eq!(delta.total_blocks, 2);
+        std::assert_eq!(delta.curr_bytes, -200);
+        std::assert_eq!(delta.max_bytes, 0);
+    }
+
+    #[test]
+    fn test_align_padding() {
+        let w = std::mem::size_of::<usize>();
+        std::assert_eq!(align_padding(8, 1), 0);
+        std::assert_eq!(align_padding(12, 8), 4);
+        std::assert_eq!(align_padding(64, 64), 64 - 2 * w);
+        std::assert_eq!(align_padding(10, 4096), 4086 + 4096 - 2 * w);
+    }
+
+    #[test]
+    fn test_symbol_module() {
+        std::assert_eq!(symbol_module("main"), None);
+        std::assert_eq!(symbol_module("heap::main"), Some("heap"));
+        std::assert_eq!(symbol_module("a::b::f::<c::D>"), Some("a::b"));
+        std::assert_eq!(
+            symbol_module("<dhat::Alloc as core::alloc::global::GlobalAlloc>::alloc"),
+            Some("<dhat::Alloc as core::alloc::global::GlobalAlloc>")
+        );
+        std::assert_eq!(
+            symbol_module("a::call::<fn() -> b::C>::{closure#0}"),
+            Some("a::call::<fn() -> b::C>")
+        );
+    }
+
+    #[test]
+    fn test_lifetime_bucket() {
+        std::assert_eq!(lifetime_bucket(Duration::ZERO), 0);
+        std::assert_eq!(lifetime_bucket(Duration::from_nanos(1)), 0);
+        std::assert_eq!(lifetime_bucket(Duration::from_nanos(2)), 1);
+        std::assert_eq!(lifetime_bucket(Duration::from_nanos(1023)), 9);
+        std::assert_eq!(lifetime_bucket(Duration::from_nanos(1024)), 10);
+        std::assert_eq!(lifetime_bucket(Duration::from_secs(1)), 29);
+        std::assert_eq!(lifetime_bucket(Duration::MAX), 63);
+    }
+
+    #[test]
+    fn test_get_frames_to_trim_empty() {
+        // Backtraces can be empty when unwinding doesn't work. Trimming must
+        // not underflow on them.
+        let empty = Backtrace::new(Box::default(), None);
+        let non_empty = Backtrace::new(vec![1, 2, 3].into(), None);
+        std::assert!(empty.get_frames_to_trim(&empty).is_empty());
+        std::assert!(empty.get_frames_to_trim(&non_empty).is_empty());
+        std::assert!(non_empty.get_frames_to_trim(&empty).is_empty());
     }
 }
@@ -0,0 +1,31 @@
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler =
+            std::mem::ManuallyDrop::new(dhat::Profiler::builder().ad_hoc().eprint_json().build());
+
+        dhat::ad_hoc_event(2);
+        for _ in 0..3 {
+            dhat::ad_hoc_event_f64(0.5);
+        }
+
+        let stats = dhat::AdHocStats::get();
+        assert_eq!(stats.total_events, 4);
+        assert_eq!(stats.total_units, 2);
+        assert_eq!(stats.total_units_f64, 1.5);
+        assert_eq!(stats.pp_count, 2);
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let pps = v["pps"].as_array().unwrap();
+    let tbs: Vec<_> = pps.iter().map(|pp| pp["tb"].as_f64().unwrap()).collect();
+    assert!(tbs.contains(&2.0));
+    assert!(tbs.contains(&1.5));
+
+    // Integer weights are still written as integers.
+    assert!(pps.iter().any(|pp| pp["tb"].as_u64() == Some(2)));
+}
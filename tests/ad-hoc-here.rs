@@ -0,0 +1,39 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler =
+            std::mem::ManuallyDrop::new(dhat::Profiler::builder().ad_hoc().eprint_json().build());
+
+        for _ in 0..3 {
+            dhat::ad_hoc_event_here(10);
+        }
+        dhat::ad_hoc_event_here(5);
+
+        let stats = dhat::AdHocStats::get();
+        assert_eq!(stats.total_events, 4);
+        assert_eq!(stats.total_units, 35);
+        assert_eq!(stats.pp_count, 2);
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let ftbl = v["ftbl"].as_array().unwrap();
+    let pps = v["pps"].as_array().unwrap();
+    assert_eq!(pps.len(), 2);
+
+    // Each call site is a single-frame PP.
+    let frame = |tb: u64| {
+        let pp = pps.iter().find(|pp| pp["tb"] == tb).unwrap();
+        let fs = pp["fs"].as_array().unwrap();
+        assert_eq!(fs.len(), 1);
+        ftbl[fs[0].as_u64().unwrap() as usize].as_str().unwrap()
+    };
+    assert_eq!(frame(30), "tests/ad-hoc-here.rs:13:13");
+    assert_eq!(frame(5), "tests/ad-hoc-here.rs:15:9");
+}
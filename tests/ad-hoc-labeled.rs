@@ -0,0 +1,45 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+fn record(kind: &'static str, weight: usize) {
+    dhat::ad_hoc_event_labeled(kind, weight);
+}
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler =
+            std::mem::ManuallyDrop::new(dhat::Profiler::builder().ad_hoc().eprint_json().build());
+
+        // The same backtrace with different labels gives different PPs, and
+        // the same label gives the same PP.
+        for _ in 0..3 {
+            record("hit", 10);
+        }
+        record("miss", 5);
+
+        let stats = dhat::AdHocStats::get();
+        assert_eq!(stats.total_events, 4);
+        assert_eq!(stats.total_units, 35);
+        assert_eq!(stats.pp_count, 2);
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let ftbl = v["ftbl"].as_array().unwrap();
+    let pps = v["pps"].as_array().unwrap();
+    assert_eq!(pps.len(), 2);
+
+    // The label is the first frame.
+    let first_frame = |tb: u64| {
+        let pp = pps.iter().find(|pp| pp["tb"] == tb).unwrap();
+        ftbl[pp["fs"][0].as_u64().unwrap() as usize]
+            .as_str()
+            .unwrap()
+    };
+    assert_eq!(first_frame(30), "[label: hit]");
+    assert_eq!(first_frame(5), "[label: miss]");
+}
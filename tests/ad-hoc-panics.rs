@@ -1,3 +1,5 @@
+#![allow(clippy::redundant_closure)]
+
 // Test most of the panics that can occur during ad hoc profiling. Because we
 // can't have multiple `#[test]` instances in a single test, we use
 // `assert_is_panic` to test multiple panics within a single `#[test]`.
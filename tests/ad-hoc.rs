@@ -30,6 +30,7 @@ fn main() {
         let stats = dhat::AdHocStats::get();
         assert_eq!(stats.total_events, 0);
         assert_eq!(stats.total_units, 0);
+        assert_eq!(stats.pp_count, 0);
 
         dhat::ad_hoc_event(100);
         f1();
@@ -40,6 +41,7 @@ fn main() {
         let stats = dhat::AdHocStats::get();
         assert_eq!(stats.total_events, 4);
         assert_eq!(stats.total_units, 106);
+        assert_eq!(stats.pp_count, 4);
 
         profiler.drop_and_get_memory_output()
     };
@@ -142,15 +144,15 @@ fn main() {
         assert!(y("ad_hoc::f2 (dhat-rs\\tests\\ad-hoc.rs:6:0)"));
         //assert!(y("ad_hoc::f1 (dhat-rs\\tests\\ad-hoc.rs:10:0)"));
         assert!(y("ad_hoc::f1 (dhat-rs\\tests\\ad-hoc.rs:11:0)"));
-        assert!(y("ad_hoc::main (dhat-rs\\tests\\ad-hoc.rs:34:0)"));
-        //assert!(y("ad_hoc::main (dhat-rs\\tests\\ad-hoc.rs:35:0)"));
+        assert!(y("ad_hoc::main (dhat-rs\\tests\\ad-hoc.rs:35:0)"));
+        //assert!(y("ad_hoc::main (dhat-rs\\tests\\ad-hoc.rs:36:0)"));
     } else {
         assert!(y("ad_hoc::f2 (dhat-rs/tests/ad-hoc.rs:5:5)"));
         assert!(y("ad_hoc::f2 (dhat-rs/tests/ad-hoc.rs:6:5)"));
         assert!(y("ad_hoc::f1 (dhat-rs/tests/ad-hoc.rs:10:5)"));
         assert!(y("ad_hoc::f1 (dhat-rs/tests/ad-hoc.rs:11:5)"));
-        assert!(y("ad_hoc::main (dhat-rs/tests/ad-hoc.rs:34:9)"));
         assert!(y("ad_hoc::main (dhat-rs/tests/ad-hoc.rs:35:9)"));
+        assert!(y("ad_hoc::main (dhat-rs/tests/ad-hoc.rs:36:9)"));
     }
 
     // This stuff should be removed by backtrace trimming.
@@ -1,3 +1,5 @@
+#![allow(clippy::useless_vec)]
+
 #[global_allocator]
 static ALLOC: dhat::Alloc = dhat::Alloc;
 
@@ -22,6 +24,11 @@ fn main() {
         "dhat: asserting after the profiler has asserted",
     );
 
+    assert_eq!(
+        dhat::Profiler::builder().testing().try_build().err(),
+        Some(dhat::ProfilerError::PostAssert)
+    );
+
     drop(profiler);
 
     let _profiler = dhat::Profiler::builder().testing().eprint_json().build();
@@ -0,0 +1,46 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let file_name = std::env::temp_dir().join(format!("dhat-recover-{}.json", std::process::id()));
+
+    let profiler = dhat::Profiler::builder()
+        .testing()
+        .file_name(&file_name)
+        .build();
+
+    let _v1 = vec![0u8; 100];
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_is_panic(
+        || dhat::assert!(stats.curr_bytes == 0),
+        "dhat: assertion failed: stats.curr_bytes == 0",
+    );
+    dhat::assert_is_panic(
+        || dhat::assert!(true),
+        "dhat: asserting after the profiler has asserted",
+    );
+
+    // Recovering allows assertions again, and the counts accumulate.
+    profiler.recover_from_assert();
+    let _v2 = vec![0u8; 200];
+    let stats = dhat::HeapStats::get();
+    dhat::assert!(stats.curr_bytes >= 300);
+    dhat::assert_is_panic(
+        || dhat::assert!(stats.curr_bytes == 0),
+        "dhat: assertion failed: stats.curr_bytes == 0",
+    );
+
+    // Recovering again works, and recovering when no assertion has failed
+    // has no effect.
+    profiler.recover_from_assert();
+    profiler.recover_from_assert();
+    let stats = dhat::HeapStats::get();
+    dhat::assert!(stats.curr_bytes >= 300);
+
+    drop(profiler);
+    std::fs::remove_file(&file_name).unwrap();
+}
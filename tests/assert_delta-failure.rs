@@ -0,0 +1,21 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+#[should_panic(expected = "dhat: assertion failed: `(delta.total_blocks <= max)`")]
+fn main() {
+    let _profiler = dhat::Profiler::builder().testing().eprint_json().build();
+
+    let _v1 = vec![1, 2, 3, 4];
+    let baseline = dhat::HeapStats::get();
+    let _v2 = vec![5, 6, 7, 8];
+    let _v3 = vec![9, 10];
+
+    // Test with and without extra arguments.
+    let delta = dhat::HeapStats::get().delta_from(&baseline);
+    dhat::assert_delta_eq!(delta, curr_bytes, 24);
+    dhat::assert_delta_le!(delta, total_blocks, 2, "oh dear {}", 99);
+    dhat::assert_delta_le!(delta, total_blocks, 1); // failure
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::useless_vec)]
+
 #[global_allocator]
 static ALLOC: dhat::Alloc = dhat::Alloc;
 
@@ -0,0 +1,36 @@
+// Note: no global allocator, so only the `TrackingAllocator` allocations are
+// recorded.
+#![cfg(feature = "allocator-api")]
+#![feature(allocator_api)]
+
+#[test]
+fn main() {
+    let _profiler = dhat::Profiler::builder().testing().build();
+
+    // Not recorded.
+    let _v1 = vec![0u8; 1000];
+
+    // Recorded.
+    let mut v2 = Vec::with_capacity_in(10, dhat::TrackingAllocator);
+    v2.extend_from_slice(&[0u8; 10]);
+    v2.extend_from_slice(&[0u8; 10]); // grows to 20
+    v2.truncate(5);
+    v2.shrink_to_fit(); // shrinks to 5
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.total_blocks, 3);
+    dhat::assert_eq!(stats.total_bytes, 10 + 20 + 5);
+    dhat::assert_eq!(stats.curr_blocks, 1);
+    dhat::assert_eq!(stats.curr_bytes, 5);
+
+    drop(v2);
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.curr_blocks, 0);
+    dhat::assert_eq!(stats.curr_bytes, 0);
+
+    // Zero-sized allocations don't reach the profiler.
+    let v3: Vec<u8, _> = Vec::new_in(dhat::TrackingAllocator);
+    drop(v3);
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.total_blocks, 3);
+}
@@ -0,0 +1,25 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let profiler = dhat::Profiler::builder().testing().build();
+
+    assert_eq!(dhat::HeapStats::get().avg_alloc_size(), 0.0);
+
+    let _v1 = vec![0u8; 100];
+    let _v2 = vec![0u8; 300];
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.avg_alloc_size(), 200.0);
+
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    let rate = profiler.alloc_rate();
+    dhat::assert!(rate > 0.0);
+    // The blocks allocated so far in at least 10ms. (The test harness may
+    // allocate on another thread meanwhile, so the count is re-read.)
+    let blocks = dhat::HeapStats::get().total_blocks as f64;
+    dhat::assert!(rate <= blocks * 100.0);
+}
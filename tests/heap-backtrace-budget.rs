@@ -0,0 +1,33 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+    use std::time::Duration;
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .backtrace_budget(Duration::ZERO)
+                .eprint_json()
+                .build(),
+        );
+
+        let _v1 = vec![0u8; 1000];
+        let _v2 = vec![0u8; 2000];
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    // With no budget at all, every backtrace is cut short and marked as such.
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let ftbl = v["ftbl"].as_array().unwrap();
+    let pps = v["pps"].as_array().unwrap();
+    assert!(!pps.is_empty());
+    for pp in pps {
+        let fs = pp["fs"].as_array().unwrap();
+        let last = &ftbl[fs.last().unwrap().as_u64().unwrap() as usize];
+        assert_eq!(last, "[truncated: backtrace budget exceeded]");
+    }
+}
@@ -0,0 +1,22 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let profiler = dhat::Profiler::builder().testing().build();
+
+    let _v1 = vec![0u8; 1000];
+    let _v2 = vec![0u8; 2000];
+
+    let bytes_by_file = profiler.bytes_by_file();
+
+    // Both allocation points are in this file, and are combined.
+    let (_, bytes) = bytes_by_file
+        .iter()
+        .find(|(file, _)| file.ends_with("heap-bytes-by-file.rs"))
+        .unwrap();
+    assert!(*bytes >= 3000);
+
+    // Sorted by decreasing byte count.
+    assert!(bytes_by_file.windows(2).all(|w| w[0].1 >= w[1].1));
+}
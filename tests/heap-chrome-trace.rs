@@ -0,0 +1,39 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let path = std::env::temp_dir().join(format!("dhat-trace-{}.json", std::process::id()));
+    {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .chrome_trace(&path, 3)
+                .summary_output(dhat::SummaryOutput::None)
+                .build(),
+        );
+
+        let v1 = vec![0u8; 1000];
+        let v2 = vec![0u8; 2000];
+        drop(v1);
+        drop(v2);
+
+        profiler.drop_and_get_memory_output();
+    }
+
+    let trace: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let events = trace["traceEvents"].as_array().unwrap();
+
+    // Older events have been dropped, leaving the last three.
+    let counters: Vec<_> = events.iter().filter(|e| e["ph"] == "C").collect();
+    assert_eq!(counters.len(), 3);
+    assert!(counters
+        .windows(2)
+        .all(|w| w[0]["ts"].as_u64() <= w[1]["ts"].as_u64()));
+
+    let peaks: Vec<_> = events.iter().filter(|e| e["ph"] == "i").collect();
+    assert_eq!(peaks.len(), 1);
+    assert!(peaks[0]["args"]["bytes"].as_u64().unwrap() >= 3000);
+}
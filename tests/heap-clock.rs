@@ -0,0 +1,48 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// The number of seconds since profiling started, according to the clock.
+static SECS: AtomicU64 = AtomicU64::new(0);
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let start = Instant::now();
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .clock(Box::new(move || {
+                    start + Duration::from_secs(SECS.load(Ordering::Relaxed))
+                }))
+                .eprint_json()
+                .build(),
+        );
+
+        SECS.store(10, Ordering::Relaxed);
+        let v1 = vec![0u8; 1000];
+        SECS.store(30, Ordering::Relaxed);
+        drop(v1);
+        SECS.store(50, Ordering::Relaxed);
+        let v2 = vec![0u8; 2000];
+        SECS.store(80, Ordering::Relaxed);
+        let mem = profiler.drop_and_get_memory_output();
+        drop(v2);
+        mem
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    assert_eq!(v["tu"], "µs");
+    assert_eq!(v["te"], 80_000_000);
+    assert_eq!(v["tg"], 50_000_000);
+
+    let pps = v["pps"].as_array().unwrap();
+    let pp = pps.iter().find(|pp| pp["tb"] == 1000).unwrap();
+    assert_eq!(pp["tl"], 20_000_000);
+    // Still live at the end.
+    let pp = pps.iter().find(|pp| pp["tb"] == 2000).unwrap();
+    assert_eq!(pp["tl"], 30_000_000);
+}
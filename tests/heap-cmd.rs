@@ -0,0 +1,29 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+fn cmd(builder: dhat::ProfilerBuilder) -> String {
+    use serde_json::Value;
+
+    let mem = std::mem::ManuallyDrop::new(builder.build()).drop_and_get_memory_output();
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    v["cmd"].as_str().unwrap().to_string()
+}
+
+#[test]
+fn main() {
+    // The default is the full command line.
+    let full = cmd(dhat::Profiler::builder());
+    assert_eq!(full, std::env::args().collect::<Vec<_>>().join(" "));
+
+    // Redacted: just the program's file name.
+    let redacted = cmd(dhat::Profiler::builder().redact_args(true));
+    assert!(!redacted.contains('/'));
+    assert!(!redacted.contains(' '));
+    assert!(full.contains(&redacted));
+
+    // An explicit command overrides everything.
+    let explicit = cmd(dhat::Profiler::builder()
+        .redact_args(true)
+        .cmd("my-program".to_string()));
+    assert_eq!(explicit, "my-program");
+}
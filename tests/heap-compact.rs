@@ -0,0 +1,38 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let file_name = std::env::temp_dir().join(format!("dhat-compact-{}.json", std::process::id()));
+    {
+        let _profiler = dhat::Profiler::builder()
+            .file_name(&file_name)
+            .compact_output(true)
+            .summary_output(dhat::SummaryOutput::None)
+            .build();
+        let _v1 = vec![0u8; 100];
+        let _v2 = vec![0u8; 200];
+    }
+    let text = std::fs::read_to_string(&file_name).unwrap();
+    std::fs::remove_file(&file_name).unwrap();
+
+    // It's still valid JSON.
+    let json: Value = serde_json::from_str(&text).unwrap();
+    let n_pps = json["pps"].as_array().unwrap().len();
+    let n_frames = json["ftbl"].as_array().unwrap().len();
+    assert_eq!(n_pps, 2);
+
+    // One line per PP and per frame.
+    let lines: Vec<_> = text.lines().collect();
+    assert_eq!(lines[0], r#"{"dhatFileVersion":2"#);
+    assert_eq!(*lines.last().unwrap(), "}");
+    let pp_lines = lines.iter().filter(|l| l.contains(r#""fs":["#)).count();
+    assert_eq!(pp_lines, n_pps);
+    assert!(lines.contains(&r#" ["[root]""#));
+    let ftbl_idx = lines.iter().position(|l| *l == r#" ["[root]""#).unwrap();
+    assert_eq!(lines[ftbl_idx + n_frames], " ]");
+}
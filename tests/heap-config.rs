@@ -0,0 +1,36 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .trim_backtraces(Some(4))
+                .min_size(8)
+                .peak_metric(dhat::PeakMetric::Blocks)
+                .time_unit(dhat::TimeUnit::Millis)
+                .sample_curve(std::time::Duration::from_secs(2))
+                .eprint_json()
+                .build(),
+        );
+
+        let _v = vec![0u8; 100];
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let config = &v["config"];
+    assert_eq!(config["mode"], "heap");
+    assert_eq!(config["trim_backtraces"], 4);
+    assert_eq!(config["no_backtraces"], false);
+    assert_eq!(config["min_size"], 8);
+    assert_eq!(config["sample_curve"], 2000);
+    assert_eq!(config["peak_metric"], "blocks");
+    assert_eq!(config["net_only"], false);
+}
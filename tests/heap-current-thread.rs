@@ -0,0 +1,33 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let _profiler = dhat::Profiler::builder()
+        .testing()
+        .current_thread_only(true)
+        .build();
+
+    // Allocations on other threads aren't recorded. (Spawning a thread
+    // allocates a little on this thread, though.)
+    std::thread::spawn(|| {
+        let _v = vec![0u8; 100_000];
+    })
+    .join()
+    .unwrap();
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert!(stats.total_bytes < 100_000);
+
+    // A block allocated on this thread and freed on another is counted as
+    // freed.
+    let v = vec![0u8; 200_000];
+    let stats = dhat::HeapStats::get();
+    dhat::assert!(stats.curr_bytes >= 200_000);
+
+    std::thread::spawn(move || drop(v)).join().unwrap();
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert!(stats.curr_bytes < 200_000);
+    dhat::assert!(stats.max_bytes >= 200_000);
+}
@@ -0,0 +1,53 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+    use std::time::Duration;
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .sample_curve(Duration::from_millis(1))
+                .summary_output(dhat::SummaryOutput::None)
+                .build(),
+        );
+
+        let v = vec![0u8; 10_000];
+        std::thread::sleep(Duration::from_millis(50));
+        drop(v);
+        std::thread::sleep(Duration::from_millis(50));
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let curve = v["curve"].as_array().unwrap();
+    let samples: Vec<(u64, u64, u64)> = curve
+        .iter()
+        .map(|s| {
+            let s = s.as_array().unwrap();
+            (
+                s[0].as_u64().unwrap(),
+                s[1].as_u64().unwrap(),
+                s[2].as_u64().unwrap(),
+            )
+        })
+        .collect();
+
+    // The curve rises while the vector is live, and falls afterwards.
+    assert!(samples.windows(2).all(|w| w[0].0 <= w[1].0));
+    assert!(samples
+        .iter()
+        .any(|&(_, bytes, blocks)| bytes >= 10_000 && blocks >= 1));
+    assert!(samples.last().unwrap().1 < 10_000);
+
+    // The sampler's own allocations aren't counted. (The test harness's main
+    // thread may allocate while it waits, so other PPs can appear.)
+    assert!(v["ftbl"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|f| !f.as_str().unwrap().contains("start_curve_sampler")));
+}
@@ -0,0 +1,31 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let profiler = dhat::Profiler::builder()
+        .record_dealloc_backtraces(true)
+        .testing()
+        .build();
+
+    // No deallocations yet.
+    assert!(profiler.alloc_dealloc_matrix().is_empty());
+
+    for _ in 0..3 {
+        let v = vec![0u8; 100];
+        drop(v);
+    }
+
+    // One allocation site, one deallocation site, three deallocations. (We
+    // don't check the frame names, because they depend on how well the
+    // allocator frames are trimmed.)
+    let matrix = profiler.alloc_dealloc_matrix();
+    assert_eq!(matrix.len(), 1);
+    assert_eq!(matrix[0].2, 3);
+
+    let _v = vec![0u8; 200];
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.curr_blocks, 1);
+}
@@ -0,0 +1,34 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let file_name = std::env::temp_dir().join(format!("dhat-disabled-{}.json", std::process::id()));
+
+    std::env::set_var("DHAT_DISABLE", "1");
+    {
+        let _profiler = dhat::Profiler::builder().file_name(&file_name).build();
+        let _v = vec![0u8; 100];
+
+        // Nothing is recorded.
+        let stats = dhat::HeapStats::get();
+        assert_eq!(stats.total_blocks, 0);
+        assert_eq!(stats.curr_bytes, 0);
+    }
+    // Nothing is saved.
+    assert!(!file_name.exists());
+
+    // `0` doesn't disable profiling.
+    std::env::set_var("DHAT_DISABLE", "0");
+    {
+        let _profiler = dhat::Profiler::builder().file_name(&file_name).build();
+        let _v = vec![0u8; 100];
+
+        let stats = dhat::HeapStats::get();
+        assert_eq!(stats.total_blocks, 1);
+    }
+    assert!(file_name.exists());
+    std::fs::remove_file(&file_name).unwrap();
+}
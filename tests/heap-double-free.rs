@@ -0,0 +1,30 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    let _profiler = dhat::Profiler::builder()
+        .testing()
+        .on_double_free(dhat::DoubleFreePolicy::Warn)
+        .build();
+
+    // Free a block behind `dhat`'s back, so it still thinks the block is live.
+    // The system allocator will very likely reuse the address for the next
+    // allocation of the same size.
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    let p1 = unsafe { ALLOC.alloc(layout) };
+    unsafe { System.dealloc(p1, layout) };
+    let p2 = unsafe { ALLOC.alloc(layout) };
+
+    let stats = dhat::HeapStats::get();
+    if p1 == p2 {
+        // The old block was treated as freed.
+        dhat::assert_eq!(stats.total_blocks, 2);
+        dhat::assert_eq!(stats.curr_blocks, 1);
+        dhat::assert_eq!(stats.curr_bytes, 64);
+    }
+
+    unsafe { ALLOC.dealloc(p2, layout) };
+}
@@ -0,0 +1,46 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+// All four `GlobalAlloc` methods record consistently, whether called directly
+// or via the standard library.
+#[test]
+fn main() {
+    use std::alloc::{GlobalAlloc, Layout};
+
+    let _profiler = dhat::Profiler::builder().testing().build();
+
+    let layout = Layout::from_size_align(100, 8).unwrap();
+    unsafe {
+        let p1 = ALLOC.alloc(layout);
+        let p2 = ALLOC.alloc_zeroed(layout);
+        std::assert!(std::slice::from_raw_parts(p2, 100).iter().all(|&b| b == 0));
+
+        let stats = dhat::HeapStats::get();
+        dhat::assert_eq!(stats.total_blocks, 2);
+        dhat::assert_eq!(stats.curr_bytes, 200);
+
+        let p1 = ALLOC.realloc(p1, layout, 300);
+        let p2 = ALLOC.realloc(p2, layout, 50);
+
+        let stats = dhat::HeapStats::get();
+        dhat::assert_eq!(stats.total_blocks, 4);
+        dhat::assert_eq!(stats.curr_blocks, 2);
+        dhat::assert_eq!(stats.curr_bytes, 350);
+        dhat::assert_eq!(stats.max_bytes, 400);
+        dhat::assert_eq!(stats.realloc_blocks, 2);
+
+        ALLOC.dealloc(p1, Layout::from_size_align(300, 8).unwrap());
+        ALLOC.dealloc(p2, Layout::from_size_align(50, 8).unwrap());
+    }
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.curr_blocks, 0);
+    dhat::assert_eq!(stats.curr_bytes, 0);
+
+    // `vec![0; n]` uses `alloc_zeroed`.
+    let v = vec![0u8; 1000];
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.total_blocks, 5);
+    dhat::assert_eq!(stats.curr_bytes, 1000);
+    drop(v);
+}
@@ -0,0 +1,31 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    std::env::set_var("DHAT_TEST_ENV", "hello");
+    std::env::remove_var("DHAT_TEST_UNSET");
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .record_env(vec![
+                    "DHAT_TEST_ENV".to_string(),
+                    "DHAT_TEST_UNSET".to_string(),
+                ])
+                .build(),
+        );
+
+        // Changes after profiling starts aren't recorded.
+        std::env::set_var("DHAT_TEST_ENV", "goodbye");
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let env = v["env"].as_object().unwrap();
+    assert_eq!(env.len(), 1);
+    assert_eq!(env["DHAT_TEST_ENV"], "hello");
+}
@@ -0,0 +1,18 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+#[should_panic(expected = "dhat: leak detected: 1000 bytes in 1 blocks still allocated")]
+fn main() {
+    let file_name = std::env::temp_dir().join(format!("dhat-leak-{}.json", std::process::id()));
+    let _profiler = dhat::Profiler::builder()
+        .testing()
+        .fail_on_leak(true)
+        .file_name(&file_name)
+        .build();
+
+    let _v = vec![0u8; 100];
+    std::mem::forget(vec![0u8; 1000]);
+}
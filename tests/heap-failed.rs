@@ -0,0 +1,24 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let _profiler = dhat::Profiler::builder().testing().build();
+
+    // Far more than any system can provide, but below `isize::MAX` so the
+    // request actually reaches the allocator.
+    let huge = 1 << 62;
+
+    // A failed `alloc`.
+    let mut v1: Vec<u8> = Vec::new();
+    assert!(v1.try_reserve_exact(huge).is_err());
+
+    // A failed `realloc`.
+    let mut v2 = vec![0u8; 100];
+    assert!(v2.try_reserve_exact(huge).is_err());
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.failed_blocks, 2);
+    dhat::assert_eq!(stats.curr_blocks, 1);
+    dhat::assert_eq!(stats.curr_bytes, 100);
+}
@@ -0,0 +1,34 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+// The profile is written to the given file, not to the named one.
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let dir = std::env::temp_dir();
+    let file_name = dir.join(format!("dhat-file-{}.json", std::process::id()));
+    let unused_file_name = dir.join(format!("dhat-file-unused-{}.json", std::process::id()));
+
+    // Old contents longer than the profile, which must be truncated.
+    std::fs::write(&file_name, " ".repeat(1_000_000)).unwrap();
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&file_name)
+        .unwrap();
+    {
+        let _profiler = dhat::Profiler::builder()
+            .file(file)
+            .file_name(&unused_file_name)
+            .summary_output(dhat::SummaryOutput::None)
+            .build();
+        let _v = vec![0u8; 100];
+    }
+
+    let json: Value = serde_json::from_str(&std::fs::read_to_string(&file_name).unwrap()).unwrap();
+    assert_eq!(json["mode"], "rust-heap");
+    assert!(!unused_file_name.exists());
+    std::fs::remove_file(&file_name).unwrap();
+}
@@ -0,0 +1,34 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let dir = std::env::temp_dir();
+    let folded_name = dir.join(format!("dhat-folded-{}.folded", std::process::id()));
+
+    {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .folded_output(&folded_name, dhat::FoldedWeight::TotalBytes)
+                .build(),
+        );
+
+        let _v1 = vec![0u8; 1000];
+        let _v2 = vec![0u8; 2000];
+
+        profiler.drop_and_get_memory_output();
+    }
+
+    let folded = std::fs::read_to_string(&folded_name).unwrap();
+    std::fs::remove_file(&folded_name).unwrap();
+
+    // Each line is `frame;frame;... <weight>`.
+    let mut weights = vec![];
+    for line in folded.lines() {
+        let (stack, weight) = line.rsplit_once(' ').unwrap();
+        assert!(stack.contains("heap_folded::main"));
+        weights.push(weight.parse::<u64>().unwrap());
+    }
+    assert!(weights.contains(&1000));
+    assert!(weights.contains(&2000));
+}
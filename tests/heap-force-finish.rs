@@ -0,0 +1,48 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+// `force_finish` writes the profile immediately, and later drops of the same
+// `Profiler` must not affect a new one.
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let file_name = std::env::temp_dir().join(format!("dhat-force-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&file_name);
+
+    let profiler = dhat::Profiler::builder()
+        .file_name(&file_name)
+        .summary_output(dhat::SummaryOutput::None)
+        .build();
+    let _v = vec![0u8; 100];
+    profiler.force_finish();
+
+    let json: Value = serde_json::from_str(&std::fs::read_to_string(&file_name).unwrap()).unwrap();
+    assert_eq!(json["mode"], "rust-heap");
+    std::fs::remove_file(&file_name).unwrap();
+
+    // A second call does nothing.
+    profiler.force_finish();
+    assert!(!file_name.exists());
+
+    // Queries on a finished `Profiler` panic.
+    dhat::assert_is_panic(
+        || profiler.per_thread_totals(),
+        "dhat: getting per-thread totals after the profiler has finished",
+    );
+
+    // They still panic once a second `Profiler` is running, rather than
+    // getting its data. Dropping the first `Profiler` doesn't stop the second
+    // one.
+    let _profiler2 = dhat::Profiler::builder().testing().build();
+    dhat::assert_is_panic(
+        || profiler.live_blocks(),
+        "dhat: getting live blocks after the profiler has finished",
+    );
+    drop(profiler);
+    let _w = vec![0u8; 200];
+    let stats = dhat::HeapStats::get();
+    dhat::assert!(stats.total_bytes >= 200);
+}
@@ -0,0 +1,59 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[cfg(unix)]
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let dir = std::env::temp_dir().join(format!("dhat-fork-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file_name = dir.join("dhat-heap.json");
+
+    let profiler = dhat::Profiler::builder()
+        .file_name(&file_name)
+        .reset_on_fork(true)
+        .build();
+
+    let _v1 = vec![0u8; 10_000];
+
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0);
+    if pid == 0 {
+        // The child starts afresh. Don't panic here, because that would
+        // unwind into the test harness.
+        let stats = dhat::HeapStats::get();
+        let ok = stats.total_bytes < 10_000;
+        let _v2 = vec![0u8; 2000];
+        drop(profiler);
+        unsafe { libc::_exit(if ok { 0 } else { 1 }) };
+    }
+
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status));
+    assert_eq!(libc::WEXITSTATUS(status), 0);
+
+    // The parent's data is unaffected.
+    let stats = dhat::HeapStats::get();
+    assert!(stats.total_bytes >= 10_000);
+    drop(profiler);
+
+    let read = |path: &std::path::Path| -> Value {
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    };
+    let tbs = |v: &Value| -> Vec<u64> {
+        let pps = v["pps"].as_array().unwrap();
+        pps.iter().map(|pp| pp["tb"].as_u64().unwrap()).collect()
+    };
+
+    let parent = read(&file_name);
+    assert!(tbs(&parent).contains(&10_000));
+    assert!(!tbs(&parent).contains(&2000));
+
+    let child = read(&dir.join(format!("dhat-heap.{}.json", pid)));
+    assert!(tbs(&child).contains(&2000));
+    assert!(!tbs(&child).contains(&10_000));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,27 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let _profiler = dhat::Profiler::builder().testing().build();
+
+    let v1 = vec![0u8; 100];
+    let mut v2: Vec<u8> = Vec::with_capacity(200);
+    let _v3 = vec![0u8; 300];
+
+    drop(v1);
+    v2.reserve_exact(1000); // the old 200-byte block counts as freed
+    drop(v2);
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.freed_blocks, 3);
+    dhat::assert_eq!(stats.freed_bytes, 1300);
+    dhat::assert_eq!(
+        stats.total_bytes - stats.freed_bytes,
+        stats.curr_bytes as u64
+    );
+    dhat::assert_eq!(
+        stats.total_blocks - stats.freed_blocks,
+        stats.curr_blocks as u64
+    );
+}
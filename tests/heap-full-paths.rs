@@ -0,0 +1,25 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .full_paths(true)
+                .summary_output(dhat::SummaryOutput::None)
+                .build(),
+        );
+        let _v = vec![0u8; 100];
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let ftbl = v["ftbl"].as_array().unwrap();
+    let full = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/heap-full-paths.rs:");
+    assert!(ftbl.iter().any(|f| f.as_str().unwrap().contains(full)));
+}
@@ -0,0 +1,36 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let profiler = dhat::Profiler::builder().testing().build();
+
+    let v1 = vec![0u8; 100];
+    let _v2 = vec![0u8; 2000];
+    drop(v1);
+    let _v3 = {
+        let _region = dhat::Region::new("phase");
+        vec![0u8; 30]
+    };
+
+    let pps: Vec<_> = profiler.iter_pps().collect();
+    assert_eq!(pps.len(), 3);
+
+    assert_eq!(pps[0].total_bytes, 100);
+    assert_eq!(pps[0].total_blocks, 1);
+    assert_eq!(pps[0].max_bytes, 100);
+    assert_eq!(pps[0].at_tgmax_bytes, 100); // the peak is before `drop(v1)`
+    assert_eq!(pps[0].curr_bytes, 0);
+    assert!(!pps[0].frames.is_empty());
+
+    assert_eq!(pps[1].total_bytes, 2000);
+    assert_eq!(pps[1].at_tgmax_bytes, 2000);
+    assert_eq!(pps[1].curr_bytes, 2000);
+
+    assert_eq!(pps[2].total_bytes, 30);
+    assert_eq!(pps[2].frames[0], "[region: phase]");
+    drop(pps);
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.curr_bytes, 2030);
+}
@@ -0,0 +1,28 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let profiler = dhat::Profiler::builder().testing().build();
+
+    assert_eq!(profiler.largest_block_site(), None);
+    assert_eq!(dhat::HeapStats::get().largest_block, 0);
+
+    let v1 = vec![0u8; 1000];
+    drop(v1);
+    let _v2 = vec![0u8; 200];
+
+    // Growing a block by reallocation counts too.
+    let mut v3: Vec<u8> = Vec::with_capacity(500);
+    v3.reserve_exact(1500);
+
+    // We don't check the frame name, because it depends on how well the
+    // allocator frames are trimmed.
+    let (_frame, bytes) = profiler.largest_block_site().unwrap();
+    assert_eq!(bytes, 1500);
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.largest_block, 1500);
+}
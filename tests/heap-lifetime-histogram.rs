@@ -0,0 +1,58 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .lifetime_histogram(true)
+                .eprint_json()
+                .build(),
+        );
+
+        // Many short-lived blocks, then one long-lived block, all from the
+        // same allocation point.
+        let mut long = None;
+        for i in 0..11 {
+            let v = vec![0u8; 1000];
+            if i == 10 {
+                long = Some(v);
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        drop(long);
+
+        // A block that is never freed.
+        let _leak = vec![0u8; 2000];
+
+        let reports: Vec<_> = profiler.iter_pps().collect();
+        let report = reports.iter().find(|r| r.total_bytes == 11_000).unwrap();
+        assert_eq!(report.lifetime_histogram.iter().sum::<u64>(), 11);
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let pps = v["pps"].as_array().unwrap();
+
+    let pp = pps.iter().find(|pp| pp["tb"] == 11_000).unwrap();
+    let lh: Vec<u64> = pp["lh"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|n| n.as_u64().unwrap())
+        .collect();
+    assert_eq!(lh.iter().sum::<u64>(), 11);
+
+    // The long-lived block is in the last bucket, which covers at least
+    // 20ms, i.e. 2^24 nanoseconds or more.
+    assert_eq!(*lh.last().unwrap(), 1);
+    assert!(lh.len() > 24);
+
+    // Blocks that are never freed aren't counted.
+    let pp = pps.iter().find(|pp| pp["tb"] == 2000).unwrap();
+    assert_eq!(pp.get("lh"), None);
+}
@@ -0,0 +1,25 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+fn leak() -> Vec<u8> {
+    vec![0u8; 4000]
+}
+
+#[test]
+fn main() {
+    let profiler = dhat::Profiler::builder().testing().build();
+
+    let _v1 = leak();
+    let mut v2 = vec![0u8; 100];
+    v2.push(1); // realloc
+    drop(vec![0u8; 300]); // not live
+
+    let blocks = profiler.live_blocks();
+    assert!(blocks.len() >= 2);
+    assert!(blocks.windows(2).all(|w| w[0].size >= w[1].size));
+
+    assert_eq!(blocks[0].size, 4000);
+    assert!(blocks[0].top_frame.starts_with("0x"));
+    assert!(blocks.iter().any(|b| b.size == v2.capacity()));
+    assert!(blocks.iter().all(|b| b.size != 300));
+}
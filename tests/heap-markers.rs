@@ -0,0 +1,38 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .summary_output(dhat::SummaryOutput::None)
+                .build(),
+        );
+
+        dhat::marker("start");
+        let _v = vec![0u8; 100];
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        dhat::marker("end");
+
+        // Recording a marker isn't counted as an allocation.
+        let stats = dhat::HeapStats::get();
+        assert_eq!(stats.total_blocks, 1);
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let markers = v["markers"].as_array().unwrap();
+    assert_eq!(markers.len(), 2);
+    assert_eq!(markers[0]["name"], "start");
+    assert_eq!(markers[1]["name"], "end");
+    let t0 = markers[0]["t"].as_u64().unwrap();
+    let t1 = markers[1]["t"].as_u64().unwrap();
+    assert!(t1 >= t0 + 2000);
+    assert!(t1 <= v["te"].as_u64().unwrap());
+}
@@ -0,0 +1,39 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .merge_by_line(true)
+                .eprint_json()
+                .build(),
+        );
+
+        // Two allocation points on one line, differing only by column.
+        #[rustfmt::skip]
+        let (_v1, _v2) = (vec![0u8; 1000], vec![0u8; 2000]);
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let pps = v["pps"].as_array().unwrap();
+    let pp = pps.iter().find(|pp| pp["tb"] == 3000).unwrap();
+    assert_eq!(pp["tbk"], 2);
+    assert_eq!(pp["gb"], 3000);
+    assert_eq!(pp["eb"], 3000);
+    assert!(pps.iter().all(|pp| pp["tb"] != 1000 && pp["tb"] != 2000));
+
+    // Frames have no columns.
+    let ftbl = v["ftbl"].as_array().unwrap();
+    let frame = ftbl
+        .iter()
+        .map(|f| f.as_str().unwrap())
+        .find(|f| f.contains("heap_merge_by_line::main"))
+        .unwrap();
+    assert!(frame.ends_with("heap-merge-by-line.rs:18)"));
+}
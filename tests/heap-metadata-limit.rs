@@ -0,0 +1,38 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let file_name =
+        std::env::temp_dir().join(format!("dhat-metadata-limit-{}.json", std::process::id()));
+
+    {
+        // A tiny limit, so the first allocation stops profiling.
+        let _profiler = dhat::Profiler::builder()
+            .file_name(&file_name)
+            .metadata_limit(1)
+            .build();
+
+        let _v1 = vec![0u8; 100];
+        let _v2 = vec![0u8; 200]; // not recorded
+
+        let stats = dhat::HeapStats::get();
+        assert_eq!(stats.total_blocks, 1);
+        assert_eq!(stats.total_bytes, 100);
+    }
+
+    // The data was saved when profiling stopped.
+    let json: Value = serde_json::from_str(&std::fs::read_to_string(&file_name).unwrap()).unwrap();
+    assert_eq!(json["pps"].as_array().unwrap().len(), 1);
+    std::fs::remove_file(&file_name).unwrap();
+
+    // Profiling works again with a new profiler.
+    let _profiler = dhat::Profiler::builder().testing().build();
+    let _v3 = vec![0u8; 300];
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.total_bytes, 300);
+}
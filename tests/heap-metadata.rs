@@ -0,0 +1,19 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let profiler = dhat::Profiler::builder().testing().build();
+
+    let before = profiler.metadata_bytes();
+
+    // Many live blocks, which must be recorded.
+    let vs: Vec<_> = (0..10_000).map(|_| Box::new(0u64)).collect();
+
+    let after = profiler.metadata_bytes();
+    assert!(after >= before + vs.len() * std::mem::size_of::<usize>());
+
+    // The metadata isn't counted in the heap stats.
+    let stats = dhat::HeapStats::get();
+    dhat::assert!(stats.curr_bytes < 10_000 * 8 + vs.capacity() * 8 + 1024);
+}
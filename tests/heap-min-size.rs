@@ -0,0 +1,32 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let _profiler = dhat::Profiler::builder().min_size(16).testing().build();
+
+    let v1 = vec![0u8; 8]; // unrecorded
+    let mut v2 = vec![0u8; 100];
+    let mut v3 = vec![0u8; 4]; // unrecorded
+
+    v3.reserve_exact(1000); // still unrecorded
+    drop(v1); // still unrecorded
+    v2.truncate(8);
+    v2.shrink_to_fit(); // now unrecorded, treated as freed
+
+    let _v4 = vec![0u8; 16];
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.total_blocks, 2);
+    dhat::assert_eq!(stats.total_bytes, 116);
+    dhat::assert_eq!(stats.curr_blocks, 1);
+    dhat::assert_eq!(stats.curr_bytes, 16);
+    dhat::assert_eq!(stats.max_bytes, 100);
+
+    drop(v2);
+    drop(v3);
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.curr_blocks, 1);
+}
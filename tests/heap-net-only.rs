@@ -0,0 +1,56 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .net_only(true)
+                .eprint_json()
+                .build(),
+        );
+
+        // Many short-lived blocks, all freed.
+        for _ in 0..10 {
+            let v = vec![0u8; 1000];
+            drop(v);
+        }
+
+        // Many short-lived blocks from one allocation point, of which one
+        // survives.
+        let mut kept = None;
+        for i in 0..10 {
+            let v = vec![0u8; 500];
+            if i == 9 {
+                kept = Some(v);
+            }
+        }
+
+        // A block that is never freed.
+        let _leak = vec![0u8; 2000];
+
+        // The live stats still count everything.
+        let stats = dhat::HeapStats::get();
+        assert!(stats.total_bytes >= 17_000);
+
+        let mem = profiler.drop_and_get_memory_output();
+        drop(kept);
+        mem
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let pps = v["pps"].as_array().unwrap();
+
+    // Only the surviving blocks are counted.
+    let pp = pps.iter().find(|pp| pp["tb"] == 2000).unwrap();
+    assert_eq!(pp["tbk"], 1);
+    let pp = pps.iter().find(|pp| pp["tb"] == 500).unwrap();
+    assert_eq!(pp["tbk"], 1);
+
+    // Allocation points with no live blocks are omitted.
+    assert!(pps.iter().all(|pp| pp["tb"] != 10_000 && pp["tb"] != 5000));
+    assert!(pps.iter().all(|pp| pp["tb"].as_u64().unwrap() > 0));
+}
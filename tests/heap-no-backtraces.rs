@@ -0,0 +1,41 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .no_backtraces()
+                .eprint_json()
+                .build(),
+        );
+
+        let v1 = vec![0u8; 1000];
+        let v2 = vec![0u8; 2000];
+        drop(v1);
+        let _v3 = vec![0u8; 3000];
+        drop(v2);
+
+        // Accurate totals and peaks, all from a single PP.
+        let stats = dhat::HeapStats::get();
+        assert_eq!(stats.total_blocks, 3);
+        assert_eq!(stats.total_bytes, 6000);
+        assert_eq!(stats.curr_blocks, 1);
+        assert_eq!(stats.curr_bytes, 3000);
+        assert_eq!(stats.max_blocks, 2);
+        assert_eq!(stats.max_bytes, 5000);
+        assert_eq!(stats.pp_count, 1);
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let pps = v["pps"].as_array().unwrap();
+    assert_eq!(pps.len(), 1);
+    assert_eq!(pps[0]["tb"], 6000);
+    assert_eq!(pps[0]["mb"], 5000);
+    assert_eq!(v["ftbl"].as_array().unwrap().len(), 1);
+}
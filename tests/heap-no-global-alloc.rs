@@ -0,0 +1,19 @@
+// Note: no `#[global_allocator]`, so nothing is recorded. `finish` warns
+// about this, which can be seen with `cargo test -- --nocapture`.
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler =
+            std::mem::ManuallyDrop::new(dhat::Profiler::builder().eprint_json().build());
+
+        let _v = vec![0u8; 1000];
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    assert!(v["pps"].as_array().unwrap().is_empty());
+}
@@ -0,0 +1,41 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static REALLOCS: AtomicUsize = AtomicUsize::new(0);
+static BYTES_1234: AtomicUsize = AtomicUsize::new(0);
+
+#[test]
+fn main() {
+    let profiler = dhat::Profiler::builder()
+        .on_alloc(Box::new(|event| {
+            if event.is_realloc {
+                REALLOCS.fetch_add(1, Ordering::Relaxed);
+            } else {
+                ALLOCS.fetch_add(1, Ordering::Relaxed);
+            }
+            if event.size == 1234 {
+                BYTES_1234.fetch_add(event.size, Ordering::Relaxed);
+            }
+        }))
+        .testing()
+        .build();
+
+    let mut v: Vec<u8> = Vec::with_capacity(1234);
+    let _w = vec![0u8; 1234];
+    v.reserve_exact(2000);
+
+    let stats = dhat::HeapStats::get();
+    drop(profiler);
+
+    // Every recorded allocation and reallocation, and nothing else, was
+    // passed to the callback.
+    let allocs = ALLOCS.load(Ordering::Relaxed);
+    let reallocs = REALLOCS.load(Ordering::Relaxed);
+    assert!(allocs >= 2);
+    assert!(reallocs >= 1);
+    assert_eq!(allocs + reallocs, stats.total_blocks as usize);
+    assert_eq!(BYTES_1234.load(Ordering::Relaxed), 2468);
+}
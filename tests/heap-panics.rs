@@ -1,3 +1,5 @@
+#![allow(clippy::redundant_closure)]
+
 #[global_allocator]
 static ALLOC: dhat::Alloc = dhat::Alloc;
 
@@ -23,6 +25,10 @@ fn main() {
             || dhat::Profiler::new_heap(),
             "dhat: creating a profiler while a profiler is already running",
         );
+        assert_eq!(
+            dhat::Profiler::builder().try_build().err(),
+            Some(dhat::ProfilerError::AlreadyRunning)
+        );
 
         dhat::assert_is_panic(
             || dhat::AdHocStats::get(),
@@ -0,0 +1,36 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let _profiler = dhat::Profiler::builder().testing().build();
+
+    let v1 = vec![0u8; 100];
+
+    dhat::pause();
+    let mut v2 = vec![0u8; 1000]; // unrecorded
+    drop(v1); // recorded
+    dhat::resume();
+
+    v2.reserve_exact(1000); // still unrecorded
+    drop(v2); // still unrecorded
+    let _v3 = vec![0u8; 10];
+
+    let mut v4 = vec![0u8; 20];
+    dhat::pause();
+    v4.reserve_exact(1000); // recorded as freed, and the new block unrecorded
+    dhat::resume();
+    drop(v4); // unrecorded
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.total_blocks, 3);
+    dhat::assert_eq!(stats.total_bytes, 130);
+    dhat::assert_eq!(stats.curr_blocks, 1);
+    dhat::assert_eq!(stats.curr_bytes, 10);
+    dhat::assert_eq!(stats.freed_blocks, 2);
+    dhat::assert_eq!(stats.freed_bytes, 120);
+    dhat::assert_eq!(stats.max_bytes, 100);
+    dhat::assert_eq!(stats.realloc_blocks, 0);
+}
@@ -0,0 +1,46 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let run = |metric| {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .peak_metric(metric)
+                .eprint_json()
+                .build(),
+        );
+
+        // Many small blocks, then one big block.
+        let small = [(); 10].map(|_| vec![0u8; 10]);
+        drop(small);
+        let big = vec![0u8; 1000];
+
+        let stats = dhat::HeapStats::get();
+        drop(big);
+        let json: Value = serde_json::from_str(&profiler.drop_and_get_memory_output()).unwrap();
+        (stats, json)
+    };
+
+    let (stats, json) = run(dhat::PeakMetric::Bytes);
+    assert_eq!((stats.max_blocks, stats.max_bytes), (1, 1000));
+    let gb: u64 = json["pps"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|pp| pp["gb"].as_u64().unwrap())
+        .sum();
+    assert_eq!(gb, 1000);
+
+    let (stats, json) = run(dhat::PeakMetric::Blocks);
+    assert_eq!((stats.max_blocks, stats.max_bytes), (10, 100));
+    let gbk: u64 = json["pps"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|pp| pp["gbk"].as_u64().unwrap())
+        .sum();
+    assert_eq!(gbk, 10);
+}
@@ -0,0 +1,23 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let profiler = dhat::Profiler::builder().testing().build();
+
+    assert_eq!(profiler.peak_site(), None);
+
+    let v1 = vec![0u8; 1000];
+    let v2 = vec![0u8; 200];
+    drop(v1);
+    drop(v2);
+    let _v3 = vec![0u8; 500]; // not at the peak
+
+    // We don't check the frame name, because it depends on how well the
+    // allocator frames are trimmed.
+    let (_frame, bytes) = profiler.peak_site().unwrap();
+    assert_eq!(bytes, 1000);
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.max_bytes, 1200);
+}
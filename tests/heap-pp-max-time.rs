@@ -0,0 +1,33 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .summary_output(dhat::SummaryOutput::None)
+                .build(),
+        );
+
+        let v1 = vec![0u8; 1000];
+        drop(v1);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let _v2 = vec![0u8; 2000];
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let pps = v["pps"].as_array().unwrap();
+    let mt = |mb: u64| {
+        let pp = pps.iter().find(|pp| pp["mb"] == mb).unwrap();
+        pp["mt"].as_u64().unwrap()
+    };
+
+    // Times are in microseconds, and the two PPs peaked at least 20ms apart.
+    assert!(mt(2000) >= mt(1000) + 20_000);
+    assert!(mt(2000) <= v["te"].as_u64().unwrap());
+}
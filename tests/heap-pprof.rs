@@ -0,0 +1,37 @@
+#![cfg(feature = "pprof")]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use std::io::Read;
+
+    let dir = std::env::temp_dir();
+    let pprof_name = dir.join(format!("dhat-pprof-{}.pb.gz", std::process::id()));
+
+    {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder().pprof_output(&pprof_name).build(),
+        );
+
+        let _v1 = vec![0u8; 1000];
+
+        profiler.drop_and_get_memory_output();
+    }
+
+    let file = std::fs::File::open(&pprof_name).unwrap();
+    let mut data = vec![];
+    flate2::read::GzDecoder::new(file)
+        .read_to_end(&mut data)
+        .unwrap();
+    std::fs::remove_file(&pprof_name).unwrap();
+
+    // Check the string table has the expected contents. Protobuf strings are
+    // stored as raw UTF-8, so a substring search suffices.
+    let contains = |s: &str| data.windows(s.len()).any(|w| w == s.as_bytes());
+    assert!(contains("alloc_objects"));
+    assert!(contains("alloc_space"));
+    assert!(contains("heap_pprof::main"));
+    assert!(contains("heap-pprof.rs"));
+}
@@ -0,0 +1,20 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    // Allocated before profiling starts.
+    let mut v: Vec<u8> = Vec::with_capacity(100);
+
+    let _profiler = dhat::Profiler::builder().testing().build();
+
+    // Reallocated within, so counted as a new allocation, and mentioned in
+    // the summary.
+    v.reserve_exact(1000);
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.total_blocks, 1);
+    dhat::assert_eq!(stats.total_bytes, 1000);
+    dhat::assert_eq!(stats.curr_bytes, 1000);
+    dhat::assert_eq!(stats.realloc_blocks, 0);
+}
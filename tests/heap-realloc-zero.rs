@@ -0,0 +1,35 @@
+use std::alloc::{GlobalAlloc, Layout};
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let _profiler = dhat::Profiler::builder().testing().build();
+
+    let layout = Layout::from_size_align(100, 1).unwrap();
+    unsafe {
+        let ptr = ALLOC.alloc(layout);
+        assert!(!ptr.is_null());
+
+        let stats = dhat::HeapStats::get();
+        dhat::assert_eq!(stats.curr_blocks, 1);
+        dhat::assert_eq!(stats.curr_bytes, 100);
+
+        // Reallocating to size zero frees the block. Depending on the system
+        // allocator, the result may be null or a block that must be freed.
+        let new_ptr = ALLOC.realloc(ptr, layout, 0);
+
+        let stats = dhat::HeapStats::get();
+        dhat::assert_eq!(stats.curr_blocks, 0);
+        dhat::assert_eq!(stats.curr_bytes, 0);
+        dhat::assert_eq!(stats.total_blocks, 1);
+        dhat::assert_eq!(stats.failed_blocks, 0);
+
+        if !new_ptr.is_null() {
+            ALLOC.dealloc(new_ptr, Layout::from_size_align(1, 1).unwrap());
+        }
+        let stats = dhat::HeapStats::get();
+        dhat::assert_eq!(stats.curr_blocks, 0);
+    }
+}
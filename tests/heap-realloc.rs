@@ -0,0 +1,19 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let _profiler = dhat::Profiler::builder().testing().build();
+
+    let mut v = Vec::with_capacity(10);
+    v.extend_from_slice(&[0u8; 10]);
+    v.reserve_exact(10); // realloc to 20
+    v.truncate(5);
+    v.shrink_to_fit(); // realloc to 5
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.total_blocks, 3);
+    dhat::assert_eq!(stats.realloc_blocks, 2);
+    dhat::assert_eq!(stats.realloc_bytes_moved, 20 + 5);
+    dhat::assert_eq!(stats.realloc_in_place + stats.realloc_moved, 2);
+}
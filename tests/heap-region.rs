@@ -0,0 +1,55 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+fn alloc() -> Vec<u8> {
+    vec![0u8; 10]
+}
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler =
+            std::mem::ManuallyDrop::new(dhat::Profiler::builder().eprint_json().build());
+
+        // The same allocation site in three different regions gives three
+        // different PPs.
+        let _v1 = alloc();
+        let _v2 = {
+            let _region = dhat::Region::new("parsing");
+            alloc()
+        };
+        let _v3 = {
+            let _outer = dhat::Region::new("parsing");
+            let _inner = dhat::Region::new("codegen");
+            alloc()
+        };
+
+        let stats = dhat::HeapStats::get();
+        assert_eq!(stats.pp_count, 3);
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let ftbl = v["ftbl"].as_array().unwrap();
+    let pps = v["pps"].as_array().unwrap();
+    let first_frames: Vec<_> = pps
+        .iter()
+        .map(|pp| {
+            ftbl[pp["fs"][0].as_u64().unwrap() as usize]
+                .as_str()
+                .unwrap()
+        })
+        .collect();
+    assert!(first_frames.contains(&"[region: parsing]"));
+    assert!(first_frames.contains(&"[region: codegen]"));
+    assert_eq!(
+        first_frames
+            .iter()
+            .filter(|f| f.starts_with("[region:"))
+            .count(),
+        2
+    );
+}
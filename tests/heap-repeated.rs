@@ -0,0 +1,50 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[inline(never)]
+fn allocate() -> Vec<u8> {
+    vec![0u8; 100]
+}
+
+// Successive profilers in one process trim backtraces identically.
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let frames = || {
+        let mem = {
+            let mut profiler = std::mem::ManuallyDrop::new(
+                dhat::Profiler::builder()
+                    .summary_output(dhat::SummaryOutput::None)
+                    .build(),
+            );
+            let _v = allocate();
+            profiler.drop_and_get_memory_output()
+        };
+
+        let v: Value = serde_json::from_str(&mem).unwrap();
+        let ftbl = v["ftbl"].as_array().unwrap();
+        let pps = v["pps"].as_array().unwrap();
+        assert_eq!(pps.len(), 1);
+        pps[0]["fs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| {
+                ftbl[f.as_u64().unwrap() as usize]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // The profilers must be built from the same place, because the frames
+    // below that are trimmed.
+    let runs: Vec<_> = (0..3).map(|_| frames()).collect();
+    assert!(runs[0]
+        .iter()
+        .any(|f| f.contains("heap_repeated::allocate")));
+    assert_eq!(runs[0], runs[1]);
+    assert_eq!(runs[0], runs[2]);
+}
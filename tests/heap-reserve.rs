@@ -0,0 +1,26 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let profiler = dhat::Profiler::builder().testing().build();
+    let unreserved = profiler.metadata_bytes();
+    drop(profiler);
+
+    let profiler = dhat::Profiler::builder()
+        .reserve_blocks(100_000)
+        .reserve_backtraces(1000)
+        .testing()
+        .build();
+
+    // The reserved space is counted, at several bytes per block.
+    let reserved = profiler.metadata_bytes();
+    assert!(reserved > unreserved + 100_000 * 8);
+
+    // Profiling works as normal.
+    let _v = vec![0u8; 100];
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.curr_bytes, 100);
+}
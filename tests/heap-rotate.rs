@@ -0,0 +1,47 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use std::time::Duration;
+
+    let dir = std::env::temp_dir().join(format!("dhat-rotate-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    {
+        let _profiler = dhat::Profiler::builder()
+            .file_name(dir.join("dhat-heap.json"))
+            .rotate(Duration::from_millis(20), 2)
+            .summary_output(dhat::SummaryOutput::None)
+            .build();
+
+        // Wait for several snapshots.
+        let _v = vec![0u8; 1000];
+        while !dir.join("dhat-heap.3.json").exists() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // The main profile, and only the two most recent snapshots.
+    let mut names: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(names.len(), 3, "{:?}", names);
+    assert!(names.contains(&"dhat-heap.json".to_string()));
+    let seqs: Vec<u64> = names
+        .iter()
+        .filter_map(|name| {
+            name.strip_prefix("dhat-heap.")?
+                .strip_suffix(".json")?
+                .parse()
+                .ok()
+        })
+        .collect();
+    assert_eq!(seqs.len(), 2);
+    assert_eq!(seqs.iter().max().unwrap() - seqs.iter().min().unwrap(), 1);
+    assert!(*seqs.iter().min().unwrap() >= 2);
+}
@@ -0,0 +1,60 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let peak_file_name =
+        std::env::temp_dir().join(format!("dhat-peak-{}.json", std::process::id()));
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .save_at_peak(&peak_file_name)
+                .eprint_json()
+                .build(),
+        );
+
+        // Freed before the peak.
+        drop(vec![0u8; 100]);
+
+        // Live at the peak, and one of them freed afterwards.
+        let v1 = vec![0u8; 1000];
+        let v2 = vec![0u8; 2000];
+        drop(v1);
+
+        // Allocated after the peak.
+        let _v3 = vec![0u8; 10];
+
+        let mem = profiler.drop_and_get_memory_output();
+        drop(v2);
+        mem
+    };
+
+    // The main profile is unaffected.
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    assert!(v["pps"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|pp| pp["tb"] == 100));
+
+    let peak: Value =
+        serde_json::from_str(&std::fs::read_to_string(&peak_file_name).unwrap()).unwrap();
+    std::fs::remove_file(&peak_file_name).unwrap();
+    let pps = peak["pps"].as_array().unwrap();
+    assert_eq!(peak["mode"], "rust-heap");
+    assert_eq!(peak["te"], peak["tg"]);
+
+    // Only the blocks live at the peak are present.
+    for tb in [1000, 2000] {
+        let pp = pps.iter().find(|pp| pp["tb"] == tb).unwrap();
+        assert_eq!(pp["tbk"], 1);
+        assert_eq!(pp["gb"], tb);
+        assert_eq!(pp["eb"], tb);
+    }
+    assert!(pps.iter().all(|pp| pp["tb"] != 100 && pp["tb"] != 10));
+}
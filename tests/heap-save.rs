@@ -0,0 +1,44 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+// The profile is written via a temporary file, which must be gone afterwards,
+// and an existing profile must be replaced.
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let file_name = std::env::temp_dir().join(format!("dhat-save-{}.json", std::process::id()));
+    let mut tmp_file_name = file_name.clone().into_os_string();
+    tmp_file_name.push(".tmp");
+
+    std::fs::write(&file_name, "old contents").unwrap();
+    {
+        let _profiler = dhat::Profiler::builder()
+            .file_name(&file_name)
+            .summary_output(dhat::SummaryOutput::None)
+            .build();
+        let _v = vec![0u8; 100];
+    }
+
+    let json: Value = serde_json::from_str(&std::fs::read_to_string(&file_name).unwrap()).unwrap();
+    assert_eq!(json["mode"], "rust-heap");
+    assert!(!std::path::Path::new(&tmp_file_name).exists());
+
+    // Likewise with syncing.
+    {
+        let _profiler = dhat::Profiler::builder()
+            .file_name(&file_name)
+            .summary_output(dhat::SummaryOutput::None)
+            .sync_on_write(true)
+            .build();
+        let _v = vec![0u8; 200];
+    }
+
+    let json: Value = serde_json::from_str(&std::fs::read_to_string(&file_name).unwrap()).unwrap();
+    assert_eq!(json["pps"][0]["tb"], 200);
+    assert!(!std::path::Path::new(&tmp_file_name).exists());
+
+    std::fs::remove_file(&file_name).unwrap();
+}
@@ -0,0 +1,37 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+    use std::time::Duration;
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .end_settle(Duration::from_millis(500))
+                .build(),
+        );
+
+        // A block that is still live when the profiler is dropped, but freed
+        // by a background thread shortly afterwards.
+        let v = vec![0u8; 1000];
+        let freer = std::thread::spawn(move || {
+            rx.recv().unwrap();
+            std::thread::sleep(Duration::from_millis(10));
+            drop(v);
+        });
+        tx.send(()).unwrap();
+
+        let mem = profiler.drop_and_get_memory_output();
+        freer.join().unwrap();
+        mem
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let pps = v["pps"].as_array().unwrap();
+    let pp = pps.iter().find(|pp| pp["tb"] == 1000).unwrap();
+    assert_eq!(pp["eb"], 0);
+    assert_eq!(pp["ebk"], 0);
+}
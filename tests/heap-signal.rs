@@ -0,0 +1,45 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[cfg(unix)]
+#[test]
+fn main() {
+    use serde_json::Value;
+    use std::time::{Duration, Instant};
+
+    let file_name = std::env::temp_dir().join(format!("dhat-signal-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&file_name);
+
+    let _profiler = dhat::Profiler::builder()
+        .testing()
+        .file_name(&file_name)
+        .dump_on_signal(libc::SIGUSR1)
+        .build();
+
+    let _v1 = vec![0u8; 1000];
+
+    unsafe {
+        libc::raise(libc::SIGUSR1);
+    }
+
+    // Wait for the helper thread to save the data.
+    let start = Instant::now();
+    while !file_name.exists() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    {
+        let v: Value = serde_json::from_str(&std::fs::read_to_string(&file_name).unwrap()).unwrap();
+        assert_eq!(v["mode"], "rust-heap");
+        let pps = v["pps"].as_array().unwrap();
+        assert!(pps.iter().any(|pp| pp["tb"].as_i64().unwrap() == 1000));
+    }
+
+    // Profiling continues after the dump. Only `_v1` is live, now that the
+    // parsed data has been dropped.
+    let stats = dhat::HeapStats::get();
+    assert_eq!(stats.curr_blocks, 1);
+    assert_eq!(stats.curr_bytes, 1000);
+
+    std::fs::remove_file(&file_name).unwrap();
+}
@@ -0,0 +1,53 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+fn alloc(n: usize) -> Vec<u8> {
+    vec![0u8; n]
+}
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .split_by_size_class(true)
+                .eprint_json()
+                .build(),
+        );
+
+        // The same allocation site at two different size classes gives two
+        // different PPs. Sizes within a size class share a PP.
+        let _v1 = alloc(16);
+        let _v2 = alloc(31);
+        let _v3 = alloc(4096);
+
+        let stats = profiler.stats_matching("[size class: 16-31 bytes]");
+        assert_eq!(stats.total_blocks, 2);
+        assert_eq!(stats.total_bytes, 47);
+        let stats = profiler.stats_matching("[size class: 4096-8191 bytes]");
+        assert_eq!(stats.total_blocks, 1);
+
+        let reports: Vec<_> = profiler.iter_pps().collect();
+        let report = reports.iter().find(|r| r.total_bytes == 4096).unwrap();
+        assert_eq!(report.frames[0], "[size class: 4096-8191 bytes]");
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let ftbl = v["ftbl"].as_array().unwrap();
+    let pps = v["pps"].as_array().unwrap();
+    let first_frames: Vec<_> = pps
+        .iter()
+        .map(|pp| {
+            ftbl[pp["fs"][0].as_u64().unwrap() as usize]
+                .as_str()
+                .unwrap()
+        })
+        .collect();
+    assert!(first_frames.contains(&"[size class: 16-31 bytes]"));
+    assert!(first_frames.contains(&"[size class: 4096-8191 bytes]"));
+    assert!(first_frames.iter().all(|f| f.starts_with("[size class:")));
+}
@@ -0,0 +1,40 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let stats_file_name =
+        std::env::temp_dir().join(format!("dhat-stats-{}.json", std::process::id()));
+
+    let stats = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .stats_file(&stats_file_name)
+                .build(),
+        );
+
+        let v1 = vec![0u8; 1000];
+        let _v2 = vec![0u8; 2000];
+        drop(v1);
+
+        let stats = dhat::HeapStats::get();
+        profiler.drop_and_get_memory_output();
+        stats
+    };
+
+    let json: Value =
+        serde_json::from_str(&std::fs::read_to_string(&stats_file_name).unwrap()).unwrap();
+    std::fs::remove_file(&stats_file_name).unwrap();
+
+    assert_eq!(json["mode"], "heap");
+    assert_eq!(json["total_blocks"], stats.total_blocks);
+    assert_eq!(json["total_bytes"], stats.total_bytes);
+    assert_eq!(json["curr_blocks"], stats.curr_blocks);
+    assert_eq!(json["curr_bytes"], stats.curr_bytes);
+    assert_eq!(json["max_blocks"], stats.max_blocks);
+    assert_eq!(json["max_bytes"], stats.max_bytes);
+    assert!(json["max_bytes"].as_u64().unwrap() >= 3000);
+    assert!(json["elapsed_secs"].as_f64().unwrap() >= 0.0);
+}
@@ -0,0 +1,40 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[inline(never)]
+fn parse_input() -> Vec<u8> {
+    vec![0u8; 100]
+}
+
+#[inline(never)]
+fn build_output() -> Vec<u8> {
+    vec![0u8; 2000]
+}
+
+#[test]
+fn main() {
+    let profiler = dhat::Profiler::builder().testing().build();
+
+    let v1 = parse_input();
+    let _v2 = parse_input();
+    let _v3 = build_output();
+    drop(v1);
+
+    let stats = profiler.stats_matching("parse_input");
+    assert_eq!(stats.total_blocks, 2);
+    assert_eq!(stats.total_bytes, 200);
+    assert_eq!(stats.curr_blocks, 1);
+    assert_eq!(stats.curr_bytes, 100);
+    assert_eq!(stats.max_bytes, 200);
+    assert_eq!(stats.pp_count, 2); // two call sites in `main`
+
+    let stats = profiler.stats_matching("build_output");
+    assert_eq!(stats.total_bytes, 2000);
+
+    let stats = profiler.stats_matching("no_such_function");
+    assert_eq!(stats.total_blocks, 0);
+    assert_eq!(stats.pp_count, 0);
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.curr_blocks, 2);
+}
@@ -0,0 +1,43 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+// Dropping the profiler while another thread is writing to `stderr` (and
+// allocating while holding the `stderr` lock) must not deadlock.
+#[test]
+fn main() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let file_name = std::env::temp_dir().join(format!("dhat-spam-{}.json", std::process::id()));
+
+    for _ in 0..10 {
+        let profiler = dhat::Profiler::builder().file_name(&file_name).build();
+
+        let started = Arc::new(AtomicBool::new(false));
+        let started2 = Arc::clone(&started);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop2 = Arc::clone(&stop);
+        let spammer = std::thread::spawn(move || {
+            started2.store(true, Ordering::Relaxed);
+            while !stop2.load(Ordering::Relaxed) {
+                // Allocate while holding the `stderr` lock.
+                let mut stderr = std::io::stderr().lock();
+                let v = vec![0u8; 10];
+                let _ = std::io::Write::write_all(&mut stderr, &v[..0]);
+            }
+        });
+
+        let _v = vec![0u8; 1000];
+        while !started.load(Ordering::Relaxed) {
+            std::thread::yield_now();
+        }
+        drop(profiler);
+
+        stop.store(true, Ordering::Relaxed);
+        spammer.join().unwrap();
+    }
+
+    std::fs::remove_file(&file_name).unwrap();
+}
@@ -0,0 +1,45 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+// A streamed profile must be complete and valid JSON, in both the default and
+// compact layouts.
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let file_name = std::env::temp_dir().join(format!("dhat-stream-{}.json", std::process::id()));
+
+    for compact in [false, true] {
+        {
+            let _profiler = dhat::Profiler::builder()
+                .file_name(&file_name)
+                .summary_output(dhat::SummaryOutput::None)
+                .compact_output(compact)
+                .stream_output(true)
+                .build();
+            let _v1 = vec![0u8; 1000];
+            let _v2 = vec![0u8; 2000];
+        }
+
+        let json: Value =
+            serde_json::from_str(&std::fs::read_to_string(&file_name).unwrap()).unwrap();
+        assert_eq!(json["mode"], "rust-heap");
+
+        let pps = json["pps"].as_array().unwrap();
+        assert_eq!(pps.len(), 2);
+        let tb: u64 = pps.iter().map(|pp| pp["tb"].as_u64().unwrap()).sum();
+        assert_eq!(tb, 3000);
+
+        // The frame table is written after the PPs, and must still contain
+        // every frame they refer to.
+        let ftbl = json["ftbl"].as_array().unwrap();
+        assert_eq!(ftbl[0], "[root]");
+        for pp in pps {
+            for f in pp["fs"].as_array().unwrap() {
+                assert!((f.as_u64().unwrap() as usize) < ftbl.len());
+            }
+        }
+    }
+
+    std::fs::remove_file(&file_name).unwrap();
+}
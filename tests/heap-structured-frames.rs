@@ -0,0 +1,49 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .structured_frames(true)
+                .eprint_json()
+                .build(),
+        );
+
+        let _v = vec![0u8; 1000];
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    let ftbl = v["ftbl"].as_array().unwrap();
+    assert_eq!(ftbl[0]["fn"], "[root]");
+    assert!(ftbl.iter().all(Value::is_object));
+
+    let frame = ftbl
+        .iter()
+        .find(|f| f["fn"] == "heap_structured_frames::main")
+        .unwrap();
+    assert!(frame["file"]
+        .as_str()
+        .unwrap()
+        .ends_with("heap-structured-frames.rs"));
+    assert_eq!(frame["line"], 16);
+    assert!(frame["col"].as_u64().unwrap() > 0);
+    assert!(frame["ip"].as_str().unwrap().starts_with("0x"));
+
+    // The PPs still refer to the frames by index.
+    let pp = v["pps"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|pp| pp["tb"] == 1000)
+        .unwrap();
+    let fs = pp["fs"].as_array().unwrap();
+    assert!(fs
+        .iter()
+        .any(|i| ftbl[i.as_u64().unwrap() as usize] == *frame));
+}
@@ -0,0 +1,28 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let profiler = dhat::Profiler::builder()
+        .detect_thrashing(10)
+        .testing()
+        .build();
+
+    // A buffer allocated and freed on every iteration.
+    for i in 0..100 {
+        let v = vec![0u8; 1024 + i];
+        drop(v);
+    }
+
+    // Blocks that are all live at once.
+    let kept: Vec<_> = (0..100).map(|_| vec![0u8; 2000]).collect();
+    drop(kept);
+
+    let pps: Vec<_> = profiler.iter_pps().collect();
+    let pp = pps.iter().find(|pp| pp.total_bytes == 107_350).unwrap();
+    assert_eq!(pp.thrash_cycles, 99);
+
+    // Only the last free leaves no live blocks.
+    let pp = pps.iter().find(|pp| pp.total_bytes == 200_000).unwrap();
+    assert_eq!(pp.thrash_cycles, 1);
+}
@@ -0,0 +1,59 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let profiler = dhat::Profiler::builder().testing().build();
+
+    let _v1 = vec![0u8; 1000];
+
+    std::thread::Builder::new()
+        .name("worker".to_string())
+        .spawn(|| {
+            let _v2 = vec![0u8; 2000];
+            let _v3 = vec![0u8; 3000];
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+
+    std::thread::Builder::new()
+        .name("churner".to_string())
+        .spawn(|| {
+            for _ in 0..10 {
+                let _v4 = vec![0u8; 4000];
+            }
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+
+    let totals = profiler.per_thread_totals();
+    // Each thread allocated at least the vectors; thread startup also
+    // allocates a little.
+    let worker = totals.iter().find(|t| t.0 == "worker").unwrap();
+    assert!(worker.1 >= 5000);
+    assert!(worker.2 >= 2);
+
+    let main = totals.iter().find(|t| t.0 == "main").unwrap();
+    assert!(main.1 >= 1000);
+
+    let churner = totals.iter().find(|t| t.0 == "churner").unwrap();
+    assert!(churner.1 >= 40_000);
+
+    // Sorted by decreasing byte count.
+    assert!(totals.windows(2).all(|w| w[0].1 >= w[1].1));
+
+    // The churner allocated the most in total, but the worker held the most
+    // at once.
+    let peaks = profiler.per_thread_peaks();
+    let worker = peaks.iter().find(|t| t.0 == "worker").unwrap();
+    assert!(worker.1 >= 5000);
+    let churner = peaks.iter().find(|t| t.0 == "churner").unwrap();
+    assert!(churner.1 >= 4000 && churner.1 < 5000);
+    let main = peaks.iter().find(|t| t.0 == "main").unwrap();
+    assert!(main.1 >= 1000);
+
+    // Sorted by decreasing byte count.
+    assert!(peaks.windows(2).all(|w| w[0].1 >= w[1].1));
+}
@@ -0,0 +1,33 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .time_unit(dhat::TimeUnit::Nanos)
+                .build(),
+        );
+
+        let v = vec![0u8; 100];
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        drop(v);
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    assert_eq!(v["tu"], "ns");
+    assert_eq!(v["Mtu"], "ms");
+    assert_eq!(v["tuth"], 10_000);
+    assert!(v["te"].as_u64().unwrap() >= 2_000_000);
+
+    // The block lives for at least 2ms.
+    let pps = v["pps"].as_array().unwrap();
+    assert!(pps
+        .iter()
+        .any(|pp| pp["tb"] == 100 && pp["tl"].as_u64().unwrap() >= 2_000_000));
+}
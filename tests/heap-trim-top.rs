@@ -0,0 +1,38 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+// Stands in for a wrapper allocator's allocation function.
+#[inline(never)]
+fn my_wrapper_alloc(n: usize) -> Vec<u8> {
+    std::hint::black_box(vec![0u8; n])
+}
+
+#[test]
+fn main() {
+    let frames = |trim_top_matching: Vec<String>| {
+        let profiler = dhat::Profiler::builder()
+            .testing()
+            .trim_top_matching(trim_top_matching)
+            .build();
+        let v = my_wrapper_alloc(100);
+        let pp = profiler
+            .iter_pps()
+            .find(|pp| pp.total_bytes == 100)
+            .unwrap();
+        drop(v);
+        pp.frames
+    };
+
+    // By default, the wrapper's frame is shown.
+    let default = frames(vec![]);
+    assert!(default
+        .iter()
+        .any(|f| f.contains("heap_trim_top::my_wrapper_alloc")));
+
+    // With a matching pattern, it and any frames above it are trimmed.
+    let trimmed = frames(vec!["heap_trim_top::my_wrapper_alloc".to_string()]);
+    assert!(!trimmed
+        .iter()
+        .any(|f| f.contains("heap_trim_top::my_wrapper_alloc")));
+    assert!(trimmed[0].contains("heap_trim_top::main"));
+}
@@ -0,0 +1,33 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let mem = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .resolve_symbols(false)
+                .eprint_json()
+                .build(),
+        );
+
+        let _v = vec![0u8; 100];
+
+        profiler.drop_and_get_memory_output()
+    };
+
+    let v: Value = serde_json::from_str(&mem).unwrap();
+    assert_eq!(v["unresolved"], Value::Bool(true));
+
+    // Every frame other than the root is a raw address.
+    let ftbl = v["ftbl"].as_array().unwrap();
+    assert_eq!(ftbl[0], "[root]");
+    assert!(ftbl.len() > 1);
+    assert!(ftbl[1..]
+        .iter()
+        .all(|f| f.as_str().unwrap().starts_with("0x")));
+}
@@ -0,0 +1,40 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let total_bytes = |json: &[u8]| -> u64 {
+        let v: Value = serde_json::from_slice(json).unwrap();
+        v["pps"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|pp| pp["tb"].as_u64().unwrap())
+            .sum()
+    };
+
+    let (json1, json2, mem) = {
+        let mut profiler = std::mem::ManuallyDrop::new(
+            dhat::Profiler::builder()
+                .summary_output(dhat::SummaryOutput::None)
+                .build(),
+        );
+
+        let _v1 = vec![0u8; 1000];
+        let json1 = profiler.write_to_vec();
+
+        // Profiling continues afterwards. The first buffer isn't counted.
+        let _v2 = vec![0u8; 2000];
+        let json2 = profiler.write_to_vec();
+
+        // The buffers are parsed only once profiling has stopped, so that
+        // the parsing's allocations aren't counted either.
+        (json1, json2, profiler.drop_and_get_memory_output())
+    };
+
+    assert_eq!(total_bytes(&json1), 1000);
+    assert_eq!(total_bytes(&json2), 3000);
+    assert_eq!(total_bytes(mem.as_bytes()), 3000);
+}
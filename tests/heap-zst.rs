@@ -0,0 +1,33 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use std::alloc::{GlobalAlloc, Layout};
+
+    let _profiler = dhat::Profiler::builder().testing().build();
+
+    // Boxes of zero-sized types don't allocate at all.
+    for _ in 0..10 {
+        let b = Box::new(());
+        std::hint::black_box(b);
+    }
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.total_blocks, 0);
+    dhat::assert_eq!(stats.zst_allocations, 0);
+
+    // Zero-sized allocations made directly are counted, but not recorded as
+    // live blocks, even if the same address is returned for several of them.
+    let layout = Layout::from_size_align(0, 1).unwrap();
+    let ptrs: Vec<_> = (0..10).map(|_| unsafe { ALLOC.alloc(layout) }).collect();
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.zst_allocations, 10);
+    dhat::assert_eq!(stats.total_blocks, 1); // `ptrs`
+    dhat::assert_eq!(stats.curr_blocks, 1);
+
+    for ptr in ptrs {
+        unsafe { ALLOC.dealloc(ptr, layout) };
+    }
+    let stats = dhat::HeapStats::get();
+    dhat::assert_eq!(stats.curr_blocks, 0);
+}
@@ -27,6 +27,7 @@ fn main() {
         assert_eq!(stats.curr_bytes, 0);
         assert_eq!(stats.max_blocks, 0);
         assert_eq!(stats.max_bytes, 0);
+        assert_eq!(stats.pp_count, 0);
 
         // Allocated before, freed during.
         drop(v1);
@@ -47,7 +48,7 @@ fn main() {
         let addr = &x as *const usize as usize; // always be bigger than 10
         for i in 0..std::cmp::min(10, addr) {
             let v7 = vec![i as u8];
-            x += v7.as_ptr() as usize; // can't unroll this
+            x += std::hint::black_box(&v7).as_ptr() as usize; // can't unroll this
         }
         assert_ne!(x, 0);
 
@@ -58,6 +59,7 @@ fn main() {
         assert_eq!(stats.curr_bytes, 432);
         assert_eq!(stats.max_blocks, 3);
         assert_eq!(stats.max_bytes, 1432);
+        assert_eq!(stats.pp_count, 4);
 
         drop(v6);
         profiler.drop_and_get_memory_output()
@@ -163,20 +165,20 @@ fn main() {
             assert!(y(
                 "alloc::vec::Vec<u32,alloc::alloc::Global>::push<u32,alloc::alloc::Global>"
             ));
-            assert!(y("heap::main (dhat-rs\\tests\\heap.rs:35:0)")); // v3
-            assert!(y("heap::main (dhat-rs\\tests\\heap.rs:38:0)")); // v5
-            assert!(y("heap::main (dhat-rs\\tests\\heap.rs:39:0)")); // v6
-            assert!(y("heap::main (dhat-rs\\tests\\heap.rs:49:0)")); // _v7
+            assert!(y("heap::main (dhat-rs\\tests\\heap.rs:36:0)")); // v3
+            assert!(y("heap::main (dhat-rs\\tests\\heap.rs:39:0)")); // v5
+            assert!(y("heap::main (dhat-rs\\tests\\heap.rs:40:0)")); // v6
+            assert!(y("heap::main (dhat-rs\\tests\\heap.rs:50:0)")); // _v7
         } else {
             // Stack traces are terrible in Windows release builds.
             assert!(y("RawVec"));
         }
     } else {
         assert!(y("alloc::vec::Vec<T,A>::push"));
-        assert!(y("heap::main (dhat-rs/tests/heap.rs:35:9)")); // v3
-        assert!(y("heap::main (dhat-rs/tests/heap.rs:38:18)")); // v5
-        assert!(y("heap::main (dhat-rs/tests/heap.rs:39:22)")); // v6
-        assert!(y("heap::main (dhat-rs/tests/heap.rs:49:22)")); // _v7
+        assert!(y("heap::main (dhat-rs/tests/heap.rs:36:9)")); // v3
+        assert!(y("heap::main (dhat-rs/tests/heap.rs:39:18)")); // v5
+        assert!(y("heap::main (dhat-rs/tests/heap.rs:40:22)")); // v6
+        assert!(y("heap::main (dhat-rs/tests/heap.rs:50:22)")); // _v7
     }
 
     // This stuff should be removed by backtrace trimming.
@@ -0,0 +1,91 @@
+use serde_json::{json, Value};
+use std::path::PathBuf;
+
+fn profile(cmd: &str, te: u64, tg: u64, ftbl: &[&str], pps: Value) -> Value {
+    json!({
+        "dhatFileVersion": 2,
+        "mode": "rust-heap",
+        "verb": "Allocated",
+        "bklt": true,
+        "bkacc": false,
+        "tu": "µs",
+        "Mtu": "s",
+        "tuth": 10,
+        "cmd": cmd,
+        "pid": 1,
+        "tg": tg,
+        "te": te,
+        "pps": pps,
+        "ftbl": ftbl,
+    })
+}
+
+fn pp(tb: u64, gb: u64, fs: &[usize]) -> Value {
+    json!({
+        "tb": tb, "tbk": 1, "tl": 5, "mb": tb, "mbk": 1, "gb": gb, "gbk": 1,
+        "eb": 0, "ebk": 0, "fs": fs
+    })
+}
+
+#[test]
+fn main() {
+    let dir = std::env::temp_dir().join(format!("dhat-merge-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, v: &Value| -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, serde_json::to_string(v).unwrap()).unwrap();
+        path
+    };
+
+    // `a` and `b` share the `main -> f` PP, but their frame tables are in
+    // different orders.
+    let a = write(
+        "a.json",
+        &profile(
+            "prog 0",
+            100,
+            50,
+            &["[root]", "main", "f"],
+            json!([pp(10, 10, &[2, 1]), pp(20, 0, &[1])]),
+        ),
+    );
+    let b = write(
+        "b.json",
+        &profile(
+            "prog 1",
+            200,
+            80,
+            &["[root]", "f", "g", "main"],
+            json!([pp(1, 100, &[1, 3]), pp(2, 0, &[2])]),
+        ),
+    );
+    let out = dir.join("out.json");
+    dhat::merge_profiles(&[&a, &b], &out).unwrap();
+
+    let v: Value = serde_json::from_str(&std::fs::read_to_string(&out).unwrap()).unwrap();
+    assert_eq!(v["cmd"], "prog 0; prog 1");
+    assert_eq!(v["te"], 200);
+    assert_eq!(v["tg"], 80); // `b` has the larger global peak
+    assert_eq!(v["ftbl"], json!(["[root]", "main", "f", "g"]));
+
+    let pps = v["pps"].as_array().unwrap();
+    assert_eq!(pps.len(), 3);
+    assert_eq!(pps[0]["fs"], json!([2, 1]));
+    assert_eq!(pps[0]["tb"], 11);
+    assert_eq!(pps[0]["tbk"], 2);
+    assert_eq!(pps[0]["tl"], 10);
+    assert_eq!(pps[0]["gb"], 110);
+    assert_eq!(pps[1]["fs"], json!([1]));
+    assert_eq!(pps[1]["tb"], 20);
+    assert_eq!(pps[2]["fs"], json!([3]));
+    assert_eq!(pps[2]["tb"], 2);
+
+    // Incompatible profiles are rejected.
+    let mut ad_hoc = profile("prog 2", 1, 1, &["[root]"], json!([]));
+    ad_hoc["mode"] = json!("rust-ad-hoc");
+    let c = write("c.json", &ad_hoc);
+    assert!(dhat::merge_profiles(&[&a, &c], &out).is_err());
+    assert!(dhat::merge_profiles(&[], &out).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
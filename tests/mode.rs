@@ -0,0 +1,24 @@
+#[test]
+fn main() {
+    assert!(!dhat::is_profiling());
+    assert_eq!(dhat::current_mode(), None);
+
+    {
+        let _profiler = dhat::Profiler::builder().testing().build();
+        assert!(dhat::is_profiling());
+        assert_eq!(dhat::current_mode(), Some(dhat::Mode::Heap));
+    }
+
+    assert_eq!(dhat::current_mode(), None);
+
+    {
+        let _profiler = dhat::Profiler::builder().ad_hoc().testing().build();
+        assert_eq!(dhat::current_mode(), Some(dhat::Mode::AdHoc));
+
+        // After a failed assertion, nothing is running.
+        let res = std::panic::catch_unwind(|| dhat::assert!(false));
+        assert!(res.is_err());
+        assert!(!dhat::is_profiling());
+        assert_eq!(dhat::current_mode(), None);
+    }
+}
@@ -0,0 +1,30 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let dir = std::env::temp_dir();
+    let heap_file = dir.join(format!("dhat-new-heap-to-{}.json", std::process::id()));
+    let ad_hoc_file = dir.join(format!("dhat-new-ad-hoc-to-{}.json", std::process::id()));
+
+    {
+        let _profiler = dhat::Profiler::new_heap_to(&heap_file);
+        let _v = vec![0u8; 100];
+    }
+    {
+        let _profiler = dhat::Profiler::new_ad_hoc_to(&ad_hoc_file);
+        dhat::ad_hoc_event(10);
+    }
+
+    let read = |path| -> Value {
+        let v = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+        v
+    };
+    assert_eq!(read(&heap_file)["mode"], "rust-heap");
+    assert_eq!(read(&ad_hoc_file)["mode"], "rust-ad-hoc");
+}
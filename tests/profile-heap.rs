@@ -0,0 +1,28 @@
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    let (len, stats) = dhat::profile_heap(|| {
+        let v1 = vec![0u8; 1000];
+        let _v2 = vec![0u8; 2000];
+        drop(v1);
+        3
+    });
+    assert_eq!(len, 3);
+    assert_eq!(stats.total_blocks, 2);
+    assert_eq!(stats.total_bytes, 3000);
+    assert_eq!(stats.max_bytes, 3000);
+    assert_eq!(stats.curr_bytes, 0);
+
+    // The profiler has stopped, so another can run.
+    assert!(!dhat::is_profiling());
+    let (v, stats) = dhat::profile_heap(|| vec![0u8; 100]);
+    assert_eq!(stats.curr_bytes, 100);
+    drop(v);
+
+    // Profiling within a running profiler panics.
+    let _profiler = dhat::Profiler::builder().testing().build();
+    let res = std::panic::catch_unwind(|| dhat::profile_heap(|| ()));
+    assert!(res.is_err());
+}
@@ -0,0 +1,46 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    use serde_json::Value;
+
+    let file_name = std::env::temp_dir().join(format!("dhat-soft-{}.json", std::process::id()));
+
+    let profiler = dhat::Profiler::builder()
+        .testing()
+        .soft_asserts(true)
+        .file_name(&file_name)
+        .summary_output(dhat::SummaryOutput::None)
+        .build();
+
+    let _v1 = vec![0u8; 100];
+
+    let stats = dhat::HeapStats::get();
+    dhat::assert_is_panic(
+        || dhat::assert_eq!(stats.curr_bytes, 99),
+        "dhat: assertion failed: `(left == right)`\n  left: `100`,\n right: `99`",
+    );
+
+    // The first failure saved the profile.
+    let json: Value = serde_json::from_str(&std::fs::read_to_string(&file_name).unwrap()).unwrap();
+    assert_eq!(json["pps"][0]["tb"], 100);
+    std::fs::remove_file(&file_name).unwrap();
+
+    // Profiling continues, and later assertions are still evaluated.
+    let _v2 = vec![0u8; 200];
+    let stats = dhat::HeapStats::get();
+    // The caught panic may have allocated too.
+    dhat::assert!(stats.curr_bytes >= 300);
+    dhat::assert_is_panic(
+        || dhat::assert!(stats.curr_blocks == 0),
+        "dhat: assertion failed: stats.curr_blocks == 0",
+    );
+
+    // Later failures don't save the profile again.
+    assert!(!file_name.exists());
+
+    drop(profiler);
+}
@@ -0,0 +1,53 @@
+#![allow(clippy::useless_vec)]
+
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
+#[test]
+fn main() {
+    assert_eq!(dhat::try_heap_stats(), None);
+    assert!(dhat::try_ad_hoc_stats().is_none());
+
+    {
+        let _profiler = dhat::Profiler::builder().testing().build();
+        let _v = vec![0u8; 100];
+
+        let stats = dhat::try_heap_stats().unwrap();
+        assert_eq!(stats, dhat::HeapStats::get());
+        assert_eq!(dhat::HeapStats::try_get(), Some(stats));
+        assert!(dhat::try_ad_hoc_stats().is_none());
+
+        // Within `dhat` itself, the stats can't be got safely.
+        dhat::assert!(dhat::HeapStats::try_get().is_none());
+    }
+
+    // The failed assertion below saves the profile, so keep it out of the
+    // current directory.
+    let file_name =
+        std::env::temp_dir().join(format!("dhat-try-stats-{}.json", std::process::id()));
+    {
+        let _profiler = dhat::Profiler::builder()
+            .ad_hoc()
+            .testing()
+            .file_name(&file_name)
+            .summary_output(dhat::SummaryOutput::None)
+            .build();
+        dhat::ad_hoc_event(5);
+
+        let stats = dhat::try_ad_hoc_stats().unwrap();
+        assert_eq!(stats.total_events, 1);
+        assert_eq!(stats.total_units, 5);
+        assert_eq!(dhat::try_heap_stats(), None);
+        assert_eq!(dhat::HeapStats::try_get(), None);
+
+        // After a failed assertion, neither kind of stats is available.
+        let res = std::panic::catch_unwind(|| dhat::assert!(false));
+        assert!(res.is_err());
+        assert!(dhat::try_ad_hoc_stats().is_none());
+        assert_eq!(dhat::try_heap_stats(), None);
+    }
+    std::fs::remove_file(&file_name).unwrap();
+
+    assert_eq!(dhat::try_heap_stats(), None);
+    assert_eq!(dhat::HeapStats::try_get(), None);
+}